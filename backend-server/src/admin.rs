@@ -0,0 +1,294 @@
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{json::Json, Serialize};
+use rocket::tokio::time::{timeout, Duration};
+use rocket::State;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+
+use crate::menu::{self, parse_locations};
+use crate::menu_source::SourceRegistry;
+use crate::pubq_client::{PubqClient, PubqStatus, SITE};
+use crate::timeslots::TimeSlotCache;
+use crate::vendor_order::VendorOrder;
+use crate::{VendorCache, VenderMenuCache, CACHE_TTL};
+
+/// How long admin requests wait for running PubQ queries.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The token for `/api/admin`, from `admin_token` in Rocket.toml. Without it the admin API is disabled.
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+/// A caller that sent the admin token as `Authorization: Bearer <token>`.
+#[derive(Debug)]
+pub struct Admin;
+
+/// Compares in constant time, so the token cannot be guessed from response times.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(expected) = request.rocket().state::<AdminConfig>().and_then(|config| config.token.as_deref()) else {
+            return Outcome::Error((Status::Forbidden, "The admin API is disabled"));
+        };
+        match request.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer ")) {
+            Some(token) if same_token(token.trim(), expected) => Outcome::Success(Admin),
+            Some(_) => {
+                warn!("Rejected admin request with a wrong token");
+                Outcome::Error((Status::Unauthorized, "Wrong admin token"))
+            },
+            None => Outcome::Error((Status::Unauthorized, "Missing admin token")),
+        }
+    }
+}
+
+/// A cache entry, with a key like `menus/<vendor>`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheEntry {
+    key: String,
+    age_seconds: u64,
+    fresh: bool,
+    /// Size of the cached JSON.
+    size_bytes: usize,
+}
+
+/// The cache keys a request removed and those it fetched again.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CacheChange {
+    removed: Vec<String>,
+    refreshed: Vec<String>,
+}
+
+fn entry(key: String, fetched: rocket::tokio::time::Instant, size_bytes: usize) -> CacheEntry {
+    let age = fetched.elapsed();
+    CacheEntry { key, age_seconds: age.as_secs(), fresh: age < CACHE_TTL, size_bytes }
+}
+
+async fn cache_entries(vendor_cache: &Mutex<Option<VendorCache>>, menu_cache: &Mutex<VenderMenuCache>, timeslot_cache: &Mutex<TimeSlotCache>) -> Vec<CacheEntry> {
+    let mut entries: Vec<CacheEntry> = vendor_cache.lock().await.iter()
+        .map(|cache| entry(format!("vendors/{}", SITE), cache.0, cache.1.len()))
+        .collect();
+    entries.extend(menu_cache.lock().await.0.iter()
+        .map(|(vendor, (fetched, menu, _, _))| entry(format!("menus/{}", vendor), *fetched, menu.to_string().len())));
+    entries.extend(timeslot_cache.lock().await.entries.iter()
+        .map(|(key, (fetched, timeslots))| entry(format!("timeslots/{}", key), *fetched, timeslots.len())));
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+/// Removes the entries with `key`, those of `vendor`, or those of all vendors of `site`.
+async fn invalidate(
+    key: Option<&str>,
+    vendor: Option<&str>,
+    site: Option<&str>,
+    vendor_cache: &Mutex<Option<VendorCache>>,
+    menu_cache: &Mutex<VenderMenuCache>,
+    timeslot_cache: &Mutex<TimeSlotCache>,
+) -> Result<Vec<String>, (Status, String)> {
+    let mut vendors: Vec<String> = vendor.into_iter().map(str::to_string).collect();
+    let mut removed = Vec::new();
+    if let Some(site) = site {
+        if site != SITE {
+            return Err((Status::NotFound, format!("Unknown site {}", site)));
+        }
+        if let Some(cache) = vendor_cache.lock().await.take() {
+            let locations = serde_json::from_str(&cache.1).ok().and_then(|value| parse_locations(&value).ok()).unwrap_or_default();
+            vendors.extend(menu::vendors(&locations).iter().map(|vendor| vendor.route_name.clone()));
+            removed.push(format!("vendors/{}", site));
+        }
+    }
+    if key.is_none() && vendors.is_empty() && site.is_none() {
+        return Err((Status::BadRequest, "Give a key, vendor or site to invalidate".to_string()));
+    }
+    // The vendor list is locked before the menus everywhere, see `shutdown`.
+    let key_split = key.and_then(|key| key.split_once('/'));
+    let vendor_list_removed = match key_split {
+        Some(("vendors", site)) if site == SITE => vendor_cache.lock().await.take().is_some(),
+        _ => false,
+    };
+
+    let menus = &mut menu_cache.lock().await.0;
+    let timeslots = &mut timeslot_cache.lock().await.entries;
+    for vendor in &vendors {
+        if menus.remove(vendor).is_some() {
+            removed.push(format!("menus/{}", vendor));
+        }
+        // Timeslot keys start with the route name, see `fetch_timeslots`.
+        let prefix = format!("{}-", vendor);
+        timeslots.retain(|key, _| {
+            let keep = !key.starts_with(&prefix);
+            if !keep {
+                removed.push(format!("timeslots/{}", key));
+            }
+            keep
+        });
+    }
+    if let Some(key) = key {
+        let found = match key_split {
+            Some(("vendors", _)) => vendor_list_removed,
+            Some(("menus", vendor)) => menus.remove(vendor).is_some(),
+            Some(("timeslots", timeslot_key)) => timeslots.remove(timeslot_key).is_some(),
+            _ => false,
+        };
+        if !found {
+            return Err((Status::NotFound, format!("No cache entry {}", key)));
+        }
+        removed.push(key.to_string());
+    }
+    Ok(removed)
+}
+
+#[get("/admin/cache")]
+#[instrument(skip_all)]
+pub async fn get_cache(
+    _admin: Admin,
+    vendor_cache: &State<Arc<Mutex<Option<VendorCache>>>>,
+    menu_cache: &State<Arc<Mutex<VenderMenuCache>>>,
+    timeslot_cache: &State<Arc<Mutex<TimeSlotCache>>>,
+) -> Json<Vec<CacheEntry>> {
+    Json(cache_entries(vendor_cache, menu_cache, timeslot_cache).await)
+}
+
+/// Removes cache entries, so they are fetched again on the next request.
+#[delete("/admin/cache?<key>&<vendor>&<site>")]
+#[instrument(skip(_admin, vendor_cache, menu_cache, timeslot_cache))]
+pub async fn delete_cache(
+    _admin: Admin,
+    key: Option<&str>,
+    vendor: Option<&str>,
+    site: Option<&str>,
+    vendor_cache: &State<Arc<Mutex<Option<VendorCache>>>>,
+    menu_cache: &State<Arc<Mutex<VenderMenuCache>>>,
+    timeslot_cache: &State<Arc<Mutex<TimeSlotCache>>>,
+) -> Result<Json<CacheChange>, (Status, String)> {
+    let removed = invalidate(key, vendor, site, vendor_cache, menu_cache, timeslot_cache).await?;
+    info!("Invalidated {:?}", removed);
+    Ok(Json(CacheChange { removed, refreshed: Vec::new() }))
+}
+
+/// Fetches the menu of `vendor`, or the vendor list of `site`, from its source right away.
+#[post("/admin/refresh?<vendor>&<site>")]
+#[instrument(skip(_admin, sources, order, vendor_cache, menu_cache, timeslot_cache))]
+#[allow(clippy::too_many_arguments)]
+pub async fn refresh(
+    _admin: Admin,
+    vendor: Option<&str>,
+    site: Option<&str>,
+    sources: &State<Arc<SourceRegistry>>,
+    order: &State<Mutex<VendorOrder>>,
+    vendor_cache: &State<Arc<Mutex<Option<VendorCache>>>>,
+    menu_cache: &State<Arc<Mutex<VenderMenuCache>>>,
+    timeslot_cache: &State<Arc<Mutex<TimeSlotCache>>>,
+) -> Result<Json<CacheChange>, (Status, String)> {
+    if vendor.is_none() && site.is_none() {
+        return Err((Status::BadRequest, "Give a vendor or site to refresh".to_string()));
+    }
+    // Only route names from the vendor list end up in PubQ paths.
+    if let Some(vendor) = vendor {
+        sources.find(vendor).await?;
+    }
+    let removed = invalidate(None, vendor, site, vendor_cache, menu_cache, timeslot_cache).await?;
+    let mut refreshed = Vec::new();
+    if let Some(site) = site {
+        sources.vendors(&*order.lock().await, 0).await?;
+        refreshed.push(format!("vendors/{}", site));
+    }
+    if let Some(vendor) = vendor {
+        sources.menu(vendor).await?;
+        refreshed.push(format!("menus/{}", vendor));
+    }
+    info!("Refreshed {:?}", refreshed);
+    Ok(Json(CacheChange { removed, refreshed }))
+}
+
+#[get("/admin/pubq")]
+#[instrument(skip_all)]
+pub async fn get_pubq(_admin: Admin, client: &State<Arc<Mutex<PubqClient>>>) -> Result<Json<PubqStatus>, (Status, String)> {
+    let client = timeout(CLIENT_TIMEOUT, client.lock()).await
+        .map_err(|_| (Status::ServiceUnavailable, format!("PubQ queries still running after {:?}", CLIENT_TIMEOUT)))?;
+    Ok(Json(client.status()))
+}
+
+/// Closes the PubQ connection and opens a new one.
+#[post("/admin/pubq/reconnect")]
+#[instrument(skip_all)]
+pub async fn reconnect(_admin: Admin, client: &State<Arc<Mutex<PubqClient>>>) -> Result<Json<PubqStatus>, (Status, String)> {
+    let mut client = timeout(CLIENT_TIMEOUT, client.lock()).await
+        .map_err(|_| (Status::ServiceUnavailable, format!("PubQ queries still running after {:?}", CLIENT_TIMEOUT)))?;
+    info!("Reconnecting to PubQ on request");
+    client.reconnect(CLIENT_TIMEOUT).await
+        .map_err(|er| (Status::BadGateway, format!("Reconnect failed {}", er)))?;
+    Ok(Json(client.status()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::local::blocking::Client;
+    use rocket::http::Header;
+    use rocket::tokio::time::Instant;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn caches() -> (Mutex<Option<VendorCache>>, Mutex<VenderMenuCache>, Mutex<TimeSlotCache>) {
+        let now = Instant::now();
+        let vendors = json!([{"name": "Food court", "children": [{"routeName": "compassdk_dbvendor1"}, {"routeName": "compassdk_dbvendor2"}]}]);
+        let mut timeslots = TimeSlotCache::default();
+        timeslots.entries.insert("compassdk_dbvendor1-soupx1".to_string(), (now, "[]".to_string()));
+        timeslots.entries.insert("compassdk_dbvendor10-soupx1".to_string(), (now, "[]".to_string()));
+        (
+            Mutex::new(Some(VendorCache(now, vendors.to_string(), String::new(), chrono::Utc::now()))),
+            Mutex::new(VenderMenuCache(HashMap::from([
+                ("compassdk_dbvendor1".to_string(), (now, json!([]), String::new(), chrono::Utc::now())),
+                ("compassdk_dbvendor3".to_string(), (now, json!([]), String::new(), chrono::Utc::now())),
+            ]))),
+            Mutex::new(timeslots),
+        )
+    }
+
+    #[rocket::async_test]
+    async fn invalidates_vendors_and_sites() {
+        let (vendor_cache, menu_cache, timeslot_cache) = caches();
+        assert_eq!(cache_entries(&vendor_cache, &menu_cache, &timeslot_cache).await.len(), 5);
+
+        let removed = invalidate(None, Some("compassdk_dbvendor1"), None, &vendor_cache, &menu_cache, &timeslot_cache).await.unwrap();
+        assert_eq!(removed, vec!["menus/compassdk_dbvendor1", "timeslots/compassdk_dbvendor1-soupx1"]);
+
+        let (vendor_cache, menu_cache, timeslot_cache) = caches();
+        let removed = invalidate(None, None, Some(SITE), &vendor_cache, &menu_cache, &timeslot_cache).await.unwrap();
+        assert_eq!(removed, vec![format!("vendors/{}", SITE), "menus/compassdk_dbvendor1".to_string(), "timeslots/compassdk_dbvendor1-soupx1".to_string()]);
+        assert!(menu_cache.lock().await.0.contains_key("compassdk_dbvendor3"));
+
+        let removed = invalidate(Some("menus/compassdk_dbvendor3"), None, None, &vendor_cache, &menu_cache, &timeslot_cache).await.unwrap();
+        assert_eq!(removed, vec!["menus/compassdk_dbvendor3"]);
+        let error = invalidate(Some("menus/compassdk_dbvendor3"), None, None, &vendor_cache, &menu_cache, &timeslot_cache).await.unwrap_err();
+        assert_eq!(error.0, Status::NotFound);
+    }
+
+    #[test]
+    fn requires_the_admin_token() {
+        let rocket = |token: Option<&str>| rocket::build()
+            .mount("/api", routes![get_cache])
+            .manage(AdminConfig { token: token.map(str::to_string) })
+            .manage(Arc::new(Mutex::new(None::<VendorCache>)))
+            .manage(Arc::new(Mutex::new(VenderMenuCache(HashMap::new()))))
+            .manage(Arc::new(Mutex::new(TimeSlotCache::default())));
+
+        let client = Client::tracked(rocket(Some("secret"))).unwrap();
+        assert_eq!(client.get("/api/admin/cache").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/api/admin/cache").header(Header::new("Authorization", "Bearer wrong")).dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/api/admin/cache").header(Header::new("Authorization", "Bearer secret")).dispatch().status(), Status::Ok);
+
+        let client = Client::tracked(rocket(None)).unwrap();
+        assert_eq!(client.get("/api/admin/cache").header(Header::new("Authorization", "Bearer secret")).dispatch().status(), Status::Forbidden);
+    }
+}
@@ -0,0 +1,199 @@
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use rocket::http::{Accept, ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::f32::consts::PI;
+use std::fmt;
+use std::io::Cursor;
+use tracing::{instrument, warn};
+
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Placeholders are scaled up by the client, so they only need a few pixels.
+const DEFAULT_SIZE: u32 = 32;
+const MAX_SIZE: u32 = 128;
+
+/// A placeholder only depends on its hash, so browsers may keep it for a year.
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlurHashError {
+    InvalidCharacter(char),
+    /// The length does not match the number of components in the first character.
+    InvalidLength { expected: usize, length: usize },
+}
+
+impl fmt::Display for BlurHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlurHashError::InvalidCharacter(character) => write!(f, "Invalid BlurHash character {:?}", character),
+            BlurHashError::InvalidLength { expected, length } => write!(f, "BlurHash has {} characters, expected {}", length, expected),
+        }
+    }
+}
+
+impl std::error::Error for BlurHashError {}
+
+fn decode83(text: &str) -> Result<u32, BlurHashError> {
+    text.chars().try_fold(0, |value, character| {
+        let digit = BASE83.iter().position(|&digit| digit as char == character).ok_or(BlurHashError::InvalidCharacter(character))?;
+        Ok(value * 83 + digit as u32)
+    })
+}
+
+fn srgb_to_linear(value: u32) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let srgb = if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 };
+    (srgb * 255.0 + 0.5) as u8
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+/// Decodes a BlurHash, see https://github.com/woltapp/blurhash, into `width` x `height` RGB pixels.
+pub fn decode(hash: &str, width: u32, height: u32) -> Result<Vec<u8>, BlurHashError> {
+    if !hash.is_ascii() {
+        return Err(BlurHashError::InvalidCharacter(hash.chars().find(|character| !character.is_ascii()).unwrap_or_default()));
+    }
+    if hash.len() < 6 {
+        return Err(BlurHashError::InvalidLength { expected: 6, length: hash.len() });
+    }
+    let size = decode83(&hash[0..1])?;
+    let (components_x, components_y) = ((size % 9 + 1) as usize, (size / 9 + 1) as usize);
+    let expected = 4 + 2 * components_x * components_y;
+    if hash.len() != expected {
+        return Err(BlurHashError::InvalidLength { expected, length: hash.len() });
+    }
+    let maximum = (decode83(&hash[1..2])? + 1) as f32 / 166.0;
+
+    let mut colors = Vec::with_capacity(components_x * components_y);
+    let dc = decode83(&hash[2..6])?;
+    colors.push([srgb_to_linear(dc >> 16), srgb_to_linear((dc >> 8) & 255), srgb_to_linear(dc & 255)]);
+    for index in 1..components_x * components_y {
+        let ac = decode83(&hash[4 + index * 2..6 + index * 2])?;
+        let component = |quantised: u32| sign_pow((quantised as f32 - 9.0) / 9.0, 2.0) * maximum;
+        colors.push([component(ac / (19 * 19)), component((ac / 19) % 19), component(ac % 19)]);
+    }
+
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let mut pixel = [0.0; 3];
+            for j in 0..components_y {
+                for i in 0..components_x {
+                    let basis = (PI * x as f32 * i as f32 / width as f32).cos() * (PI * y as f32 * j as f32 / height as f32).cos();
+                    let color = colors[i + j * components_x];
+                    for channel in 0..3 {
+                        pixel[channel] += color[channel] * basis;
+                    }
+                }
+            }
+            pixels.extend(pixel.map(linear_to_srgb));
+        }
+    }
+    Ok(pixels)
+}
+
+/// A placeholder image, as WebP when the client takes it, otherwise as PNG.
+pub struct Placeholder(Vec<u8>, ContentType);
+
+impl<'r> Responder<'r, 'static> for Placeholder {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.1)
+            .header(Header::new("Cache-Control", CACHE_CONTROL))
+            .header(Header::new("Vary", "Accept"))
+            .sized_body(self.0.len(), Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// Renders the `blurHash` of a vendor or menu item, to show while the image loads.
+#[get("/blurhash/<hash>?<w>&<h>")]
+#[instrument(skip(accept))]
+pub fn get_placeholder(hash: &str, w: Option<u32>, h: Option<u32>, accept: Option<&Accept>) -> Result<Placeholder, (Status, String)> {
+    let (width, height) = (w.unwrap_or(DEFAULT_SIZE), h.unwrap_or(DEFAULT_SIZE));
+    if width == 0 || height == 0 || width > MAX_SIZE || height > MAX_SIZE {
+        return Err((Status::BadRequest, format!("Width and height must be between 1 and {}", MAX_SIZE)));
+    }
+    let pixels = decode(hash, width, height).map_err(|er| {
+        warn!("Invalid BlurHash {}: {}", hash, er);
+        (Status::BadRequest, er.to_string())
+    })?;
+
+    let webp = accept.is_some_and(|accept| accept.media_types().any(|media_type| media_type.top() == "image" && media_type.sub() == "webp"));
+    let mut encoded = Vec::new();
+    let result = if webp {
+        WebPEncoder::new_lossless(&mut encoded).write_image(&pixels, width, height, ExtendedColorType::Rgb8)
+    } else {
+        PngEncoder::new(&mut encoded).write_image(&pixels, width, height, ExtendedColorType::Rgb8)
+    };
+    result.map_err(|er| (Status::InternalServerError, format!("Encoding placeholder failed {}", er)))?;
+    Ok(Placeholder(encoded, if webp { ContentType::WEBP } else { ContentType::PNG }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    fn encode83(mut value: u32, length: usize) -> String {
+        let mut digits = vec![0; length];
+        for digit in digits.iter_mut().rev() {
+            *digit = BASE83[(value % 83) as usize];
+            value /= 83;
+        }
+        String::from_utf8(digits).unwrap()
+    }
+
+    #[test]
+    fn decodes_the_average_color() {
+        // One component, so every pixel has the average color.
+        let hash = format!("00{}", encode83(0xFF8000, 4));
+        let pixels = decode(&hash, 4, 3).unwrap();
+        assert_eq!(pixels.len(), 4 * 3 * 3);
+        assert!(pixels.chunks(3).all(|pixel| pixel == [255, 128, 0]));
+    }
+
+    #[test]
+    fn decodes_components() {
+        // The example from the BlurHash README, with 4 x 3 components.
+        // Expected values are from the reference TypeScript decoder, with punch 1.
+        let pixels = decode("LEHV6nWB2yk8pyo0adR*.7kCMdnj", 32, 32).unwrap();
+        assert_eq!(pixels.len(), 32 * 32 * 3);
+        let pixel = |x: usize, y: usize| &pixels[(y * 32 + x) * 3..(y * 32 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), [135, 164, 177]);
+        assert_eq!(pixel(31, 0), [137, 166, 181]);
+        assert_eq!(pixel(16, 16), [158, 125, 108]);
+        assert_eq!(pixel(0, 31), [136, 144, 147]);
+        assert_eq!(pixel(31, 31), [133, 142, 147]);
+
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMdn", 32, 32), Err(BlurHashError::InvalidLength { expected: 28, length: 27 }));
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMdn/", 32, 32), Err(BlurHashError::InvalidCharacter('/')));
+    }
+
+    #[test]
+    fn serves_placeholders() {
+        let client = Client::tracked(rocket::build().mount("/api", routes![get_placeholder])).unwrap();
+        let response = client.get("/api/blurhash/LEHV6nWB2yk8pyo0adR%2A.7kCMdnj?w=16&h=8").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        let image = image::load_from_memory(&response.into_bytes().unwrap()).unwrap();
+        assert_eq!((image.width(), image.height()), (16, 8));
+
+        let response = client.get("/api/blurhash/LEHV6nWB2yk8pyo0adR%2A.7kCMdnj").header(Header::new("Accept", "image/avif,image/webp,*/*")).dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::WEBP));
+
+        assert_eq!(client.get("/api/blurhash/LEHV6n").dispatch().status(), Status::BadRequest);
+        assert_eq!(client.get("/api/blurhash/LEHV6nWB2yk8pyo0adR%2A.7kCMdnj?w=1000").dispatch().status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,273 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use rocket::http::{ContentType, Status};
+use rocket::request::FromParam;
+use rocket::State;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::menu::{find_item, parse_menu};
+use crate::menu_source::SourceRegistry;
+use crate::timeslots::{parse_slots, Slot, TimeslotProduct, TimeslotRequest};
+
+const TIMEZONE_ID: &str = "Europe/Copenhagen";
+
+/// Used when a day only has a single slot, so the slot length cannot be inferred.
+const DEFAULT_SLOT_MINUTES: i64 = 15;
+
+const VTIMEZONE_COPENHAGEN: &[&str] = &[
+    "BEGIN:VTIMEZONE",
+    "TZID:Europe/Copenhagen",
+    "X-LIC-LOCATION:Europe/Copenhagen",
+    "BEGIN:DAYLIGHT",
+    "TZOFFSETFROM:+0100",
+    "TZOFFSETTO:+0200",
+    "TZNAME:CEST",
+    "DTSTART:19700329T020000",
+    "RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+    "END:DAYLIGHT",
+    "BEGIN:STANDARD",
+    "TZOFFSETFROM:+0200",
+    "TZOFFSETTO:+0100",
+    "TZNAME:CET",
+    "DTSTART:19701025T030000",
+    "RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU",
+    "END:STANDARD",
+    "END:VTIMEZONE",
+];
+
+/// A path segment of the form `<product>.ics`.
+#[derive(Debug)]
+pub struct IcsFile<'r>(&'r str);
+
+impl<'r> FromParam<'r> for IcsFile<'r> {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        match param.strip_suffix(".ics") {
+            Some(product) if !product.is_empty() => Ok(IcsFile(product)),
+            _ => Err(param),
+        }
+    }
+}
+
+#[get("/calendar/<vendor>/<file>?<merge>")]
+#[instrument]
+pub async fn get_calendar(
+    vendor: &str,
+    file: IcsFile<'_>,
+    merge: Option<bool>,
+    sources: &State<Arc<SourceRegistry>>,
+) -> Result<(ContentType, String), (Status, String)> {
+    let product_id = file.0;
+    let menu = sources.menu(vendor).await?;
+    let menu = parse_menu(&menu.value)
+        .map_err(|er| (Status::BadGateway, format!("Unexpected menu format {:?}", er)))?;
+    let product_name = find_item(&menu, product_id)
+        .map(|item| item.name.clone())
+        .ok_or((Status::NotFound, format!("Product {} not found on the menu of {}", product_id, vendor)))?;
+
+    let request = TimeslotRequest {
+        route_name: vendor.to_string(),
+        products: vec![TimeslotProduct {
+            bong_category_id: 0,
+            product_id: product_id.to_string(),
+            product_name: product_name.clone(),
+            quantity: 1,
+        }],
+    };
+    let timeslots_json = sources.timeslots(&request).await?;
+    let slots = parse_slots(&timeslots_json)?;
+    let events = if merge.unwrap_or(false) { merged_windows(&slots) } else { single_slots(&slots) };
+    let calendar = render_calendar(vendor, product_id, &product_name, &events, Utc::now());
+    Ok((ContentType::Calendar, calendar))
+}
+
+fn slots_by_day(slots: &[Slot]) -> BTreeMap<NaiveDate, Vec<Slot>> {
+    let mut days: BTreeMap<NaiveDate, Vec<Slot>> = BTreeMap::new();
+    for slot in slots {
+        days.entry(slot.start.date_naive()).or_default().push(*slot);
+    }
+    days
+}
+
+/// The slot length of a day is the smallest gap between two consecutive slots, disabled ones included.
+fn slot_length(day_slots: &[Slot]) -> chrono::Duration {
+    day_slots.windows(2)
+        .map(|pair| pair[1].start - pair[0].start)
+        .filter(|gap| *gap > chrono::Duration::zero())
+        .min()
+        .unwrap_or(chrono::Duration::minutes(DEFAULT_SLOT_MINUTES))
+}
+
+fn enabled(day_slots: &[Slot]) -> impl Iterator<Item = DateTime<Tz>> + '_ {
+    day_slots.iter().filter(|slot| slot.enabled).map(|slot| slot.start)
+}
+
+fn single_slots(slots: &[Slot]) -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+    slots_by_day(slots).values()
+        .flat_map(|day_slots| {
+            let length = slot_length(day_slots);
+            enabled(day_slots).map(move |start| (start, start + length)).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Joins consecutive enabled slots of a day into windows, which end where a slot is missing or disabled.
+fn merged_windows(slots: &[Slot]) -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+    let mut windows: Vec<(DateTime<Tz>, DateTime<Tz>)> = Vec::new();
+    for day_slots in slots_by_day(slots).values() {
+        let length = slot_length(day_slots);
+        let mut current: Option<(DateTime<Tz>, DateTime<Tz>)> = None;
+        for start in enabled(day_slots) {
+            current = match current {
+                Some((window_start, window_end)) if start <= window_end => Some((window_start, start + length)),
+                Some(window) => {
+                    windows.push(window);
+                    Some((start, start + length))
+                },
+                None => Some((start, start + length)),
+            };
+        }
+        windows.extend(current);
+    }
+    windows
+}
+
+fn render_calendar(vendor: &str, product_id: &str, product_name: &str, events: &[(DateTime<Tz>, DateTime<Tz>)], now: DateTime<Utc>) -> String {
+    let mut lines: Vec<String> = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//MarketDash//Pickup timeslots//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{}", escape_text(product_name)),
+        format!("X-WR-TIMEZONE:{}", TIMEZONE_ID),
+    ];
+    lines.extend(VTIMEZONE_COPENHAGEN.iter().map(|line| line.to_string()));
+
+    let stamp = now.format("%Y%m%dT%H%M%SZ");
+    for (start, end) in events {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}-{}-{}@marketdash", vendor, product_id, start.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ")));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART;TZID={}:{}", TIMEZONE_ID, start.format("%Y%m%dT%H%M%S")));
+        lines.push(format!("DTEND;TZID={}:{}", TIMEZONE_ID, end.format("%Y%m%dT%H%M%S")));
+        lines.push(format!("SUMMARY:{}", escape_text(&format!("Pickup: {}", product_name))));
+        lines.push(format!("DESCRIPTION:{}", escape_text(&format!("{} can be picked up from {}", product_name, vendor))));
+        lines.push("TRANSP:TRANSPARENT".to_string());
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect::<Vec<String>>().join("")
+}
+
+/// Escapes a TEXT value as described in RFC 5545 section 3.3.11.
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+/// Folds a content line to at most 75 octets per line and terminates it with CRLF.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            line_length = 1;
+        }
+        folded.push(c);
+        line_length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn slots(raw: &str) -> Vec<Slot> {
+        parse_slots(raw).unwrap()
+    }
+
+    #[test]
+    fn can_parse_ics_file_param() {
+        assert_eq!(IcsFile::from_param("-O-0j9HBKcu4SX69EZvA.ics").unwrap().0, "-O-0j9HBKcu4SX69EZvA");
+        assert!(IcsFile::from_param("-O-0j9HBKcu4SX69EZvA").is_err());
+        assert!(IcsFile::from_param(".ics").is_err());
+    }
+
+    #[test]
+    fn converts_slots_to_copenhagen_time() {
+        let slots = slots(r#"[{"label":"Today","timeslots":[
+            {"dateISO":"2025-12-11T10:30:00.000Z","enabled":true},
+            {"dateISO":"2025-12-11T10:45:00.000Z","enabled":false},
+            {"dateISO":"2025-12-11T11:00:00.000Z","enabled":true}]}]"#);
+        let events = single_slots(&slots);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0.format("%H:%M").to_string(), "11:30");
+        assert_eq!(events[0].1.format("%H:%M").to_string(), "11:45");
+    }
+
+    #[test]
+    fn merges_slots_per_day() {
+        let slots = slots(r#"[{"label":"Today","timeslots":[
+            {"dateISO":"2025-06-11T09:00:00.000Z","enabled":true},
+            {"dateISO":"2025-06-11T09:15:00.000Z","enabled":true},
+            {"dateISO":"2025-06-11T09:30:00.000Z","enabled":true}]},
+            {"label":"Tomorrow","timeslots":[
+            {"dateISO":"2025-06-12T10:00:00.000Z","enabled":true}]}]"#);
+        let events = merged_windows(&slots);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0.format("%H:%M").to_string(), "11:00");
+        assert_eq!(events[0].1.format("%H:%M").to_string(), "11:45");
+        assert_eq!(events[1].0.format("%H:%M").to_string(), "12:00");
+        assert_eq!(events[1].1.format("%H:%M").to_string(), "12:15");
+    }
+
+    #[test]
+    fn splits_windows_at_missing_and_disabled_slots() {
+        let slots = slots(r#"[{"label":"Today","timeslots":[
+            {"dateISO":"2025-06-11T09:00:00.000Z","enabled":true},
+            {"dateISO":"2025-06-11T09:15:00.000Z","enabled":false},
+            {"dateISO":"2025-06-11T09:30:00.000Z","enabled":true},
+            {"dateISO":"2025-06-11T09:45:00.000Z","enabled":true},
+            {"dateISO":"2025-06-11T10:30:00.000Z","enabled":true}]}]"#);
+        let events: Vec<(String, String)> = merged_windows(&slots).iter()
+            .map(|(start, end)| (start.format("%H:%M").to_string(), end.format("%H:%M").to_string()))
+            .collect();
+        assert_eq!(events, vec![
+            ("11:00".to_string(), "11:15".to_string()),
+            ("11:30".to_string(), "12:00".to_string()),
+            ("12:30".to_string(), "12:45".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn renders_calendar_with_timezone() {
+        let slots = slots(r#"[{"label":"Today","timeslots":[{"dateISO":"2025-12-11T10:30:00.000Z","enabled":true}]}]"#);
+        let events = single_slots(&slots);
+        let now = DateTime::parse_from_rfc3339("2025-12-11T08:00:00Z").unwrap().with_timezone(&Utc);
+        let calendar = render_calendar("compassdk_dbvendor1", "abc", "Dal, rice; naan", &events, now);
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(calendar.contains("BEGIN:VTIMEZONE\r\nTZID:Europe/Copenhagen\r\n"));
+        assert!(calendar.contains("DTSTART;TZID=Europe/Copenhagen:20251211T113000\r\n"));
+        assert!(calendar.contains("DTEND;TZID=Europe/Copenhagen:20251211T114500\r\n"));
+        assert!(calendar.contains("SUMMARY:Pickup: Dal\\, rice\\; naan\r\n"));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let folded = fold_line(&"x".repeat(100));
+        assert_eq!(folded, format!("{}\r\n {}\r\n", "x".repeat(75), "x".repeat(25)));
+    }
+}
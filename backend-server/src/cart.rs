@@ -0,0 +1,400 @@
+use chrono::{DateTime, Utc};
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::response::content::RawJson;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+
+use crate::menu::{find_item, parse_menu, vendors};
+use crate::menu_source::SourceRegistry;
+use crate::timeslots::{TimeslotProduct, TimeslotRequest, MAX_PRODUCTS, MAX_QUANTITY};
+use crate::token::UserToken;
+use crate::vendor_order::VendorOrder;
+
+/// Carts are removed after this long without changes.
+const CART_IDLE_TIME: chrono::Duration = chrono::Duration::days(1);
+
+/// Carts kept at once, new carts are refused beyond this.
+const MAX_CARTS: usize = 1000;
+
+/// Lines per cart, one per member and item, so a whole order fits in one timeslot request.
+const MAX_CART_LINES: usize = MAX_PRODUCTS;
+
+#[derive(Deserialize, Debug)]
+pub struct NewCart {
+    vendor: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CartItemUpdate {
+    item: String,
+    /// Zero removes the item.
+    quantity: u32,
+    /// Display name of the member, defaults to their earlier name or "Anonymous".
+    #[serde(default)]
+    name: Option<String>,
+    /// The price in DKK the member saw. The update is rejected if the menu price has changed since.
+    #[serde(default)]
+    price: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CartLine {
+    #[serde(skip)]
+    token: UserToken,
+    member: String,
+    item: String,
+    name: String,
+    /// Price in øre.
+    #[serde(skip)]
+    unit_cost: i64,
+    unit_price: f64,
+    quantity: u32,
+    total: f64,
+}
+
+/// What one member owes. Members are told apart by their token, as names are free text.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MemberTotal {
+    #[serde(skip)]
+    token: UserToken,
+    member: String,
+    /// Total in DKK.
+    total: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Cart {
+    id: u64,
+    vendor: String,
+    vendor_name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    lines: Vec<CartLine>,
+    /// Per member, by name.
+    member_totals: Vec<MemberTotal>,
+    total: f64,
+}
+
+/// The carts in memory, they are not kept across restarts.
+#[derive(Default)]
+pub struct CartStore {
+    carts: HashMap<u64, Cart>,
+    next_id: u64,
+}
+
+impl CartStore {
+    /// Adds an empty cart with the next id, after removing carts that were not changed for `CART_IDLE_TIME`.
+    fn add(&mut self, vendor: String, vendor_name: String, now: DateTime<Utc>) -> Result<Cart, (Status, String)> {
+        self.carts.retain(|_, cart| cart.updated_at + CART_IDLE_TIME > now);
+        if self.carts.len() >= MAX_CARTS {
+            warn!("Refusing a new cart, {} carts are in use", self.carts.len());
+            return Err((Status::ServiceUnavailable, "Too many carts, try again later".to_string()));
+        }
+
+        self.next_id += 1;
+        let cart = Cart {
+            id: self.next_id,
+            vendor,
+            vendor_name,
+            created_at: now,
+            updated_at: now,
+            lines: Vec::new(),
+            member_totals: Vec::new(),
+            total: 0.0,
+        };
+        self.carts.insert(cart.id, cart.clone());
+        Ok(cart)
+    }
+}
+
+fn dkk(ore: i64) -> f64 {
+    ore as f64 / 100.0
+}
+
+/// The price in øre of `quantity` items, which cannot overflow for a menu price.
+fn line_cost(unit_cost: i64, quantity: u32) -> i64 {
+    unit_cost.saturating_mul(quantity as i64)
+}
+
+impl Cart {
+    /// Sets the quantity of an item for a member, replacing what they had of it. A new name applies to all their lines.
+    fn set_line(&mut self, token: UserToken, member: Option<String>, item: String, name: String, unit_cost: i64, quantity: u32) {
+        let member = member
+            .or_else(|| self.lines.iter().find(|line| line.token == token).map(|line| line.member.clone()))
+            .unwrap_or_else(|| "Anonymous".to_string());
+        self.lines.retain(|line| !(line.token == token && line.item == item));
+        for line in self.lines.iter_mut().filter(|line| line.token == token) {
+            line.member = member.clone();
+        }
+        if quantity > 0 {
+            self.lines.push(CartLine {
+                token,
+                member,
+                item,
+                name,
+                unit_cost,
+                unit_price: dkk(unit_cost),
+                quantity,
+                total: dkk(line_cost(unit_cost, quantity)),
+            });
+        }
+        self.update_totals();
+    }
+
+    fn update_totals(&mut self) {
+        let mut totals: Vec<(UserToken, String, i64)> = Vec::new();
+        for line in &self.lines {
+            let cost = line_cost(line.unit_cost, line.quantity);
+            match totals.iter_mut().find(|(token, _, _)| *token == line.token) {
+                Some((_, _, total)) => *total = total.saturating_add(cost),
+                None => totals.push((line.token.clone(), line.member.clone(), cost)),
+            }
+        }
+        self.total = dkk(totals.iter().fold(0i64, |sum, (_, _, total)| sum.saturating_add(*total)));
+        self.member_totals = totals.into_iter()
+            .map(|(token, member, total)| MemberTotal { token, member, total: dkk(total) })
+            .collect();
+        self.member_totals.sort_by(|a, b| a.member.cmp(&b.member));
+    }
+
+    /// The whole order as a single timeslot request, with quantities summed per item.
+    fn timeslot_request(&self) -> TimeslotRequest {
+        let mut products: Vec<TimeslotProduct> = Vec::new();
+        for line in &self.lines {
+            match products.iter_mut().find(|product| product.product_id == line.item) {
+                Some(product) => product.quantity = product.quantity.saturating_add(line.quantity),
+                None => products.push(TimeslotProduct {
+                    bong_category_id: 0,
+                    product_id: line.item.clone(),
+                    product_name: line.name.clone(),
+                    quantity: line.quantity,
+                }),
+            }
+        }
+        TimeslotRequest { route_name: self.vendor.clone(), products }
+    }
+
+    /// A plain text summary for the person placing the order.
+    fn summary(&self) -> String {
+        let mut summary = format!("Group order from {}\n\n", self.vendor_name);
+        let request = self.timeslot_request();
+        for product in &request.products {
+            let unit_cost = self.lines.iter().find(|line| line.item == product.product_id).map(|line| line.unit_cost).unwrap_or(0);
+            summary.push_str(&format!("{} x {} ({:.2} kr)\n", product.quantity, product.product_name, dkk(line_cost(unit_cost, product.quantity))));
+        }
+        summary.push_str("\nPer person:\n");
+        for member in &self.member_totals {
+            let items = self.lines.iter()
+                .filter(|line| line.token == member.token)
+                .map(|line| format!("{} x {}", line.quantity, line.name))
+                .collect::<Vec<String>>()
+                .join(", ");
+            summary.push_str(&format!("{}: {} ({:.2} kr)\n", member.member, items, member.total));
+        }
+        summary.push_str(&format!("\nTotal: {:.2} kr\n", self.total));
+        summary
+    }
+}
+
+#[post("/carts", data = "<body>")]
+#[instrument(skip(body))]
+pub async fn create_cart(
+    body: Json<NewCart>,
+    sources: &State<Arc<SourceRegistry>>,
+    order: &State<Mutex<VendorOrder>>,
+    carts: &State<Mutex<CartStore>>,
+) -> Result<Json<Cart>, (Status, String)> {
+    let locations = sources.locations(&*order.lock().await).await?;
+    let vendor = vendors(&locations).into_iter()
+        .find(|vendor| vendor.route_name == body.vendor)
+        .ok_or((Status::BadRequest, format!("Unknown vendor {}", body.vendor)))?;
+
+    let cart = carts.lock().await.add(vendor.route_name.clone(), vendor.name.clone(), Utc::now())?;
+    info!("Created cart {} for {}", cart.id, cart.vendor);
+    Ok(Json(cart))
+}
+
+#[get("/carts/<id>")]
+#[instrument]
+pub async fn get_cart(id: u64, carts: &State<Mutex<CartStore>>) -> Result<Json<Cart>, (Status, String)> {
+    carts.lock().await.carts.get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or((Status::NotFound, format!("No cart {}", id)))
+}
+
+#[post("/carts/<id>/items", data = "<body>")]
+#[instrument(skip(body))]
+pub async fn update_cart_item(
+    id: u64,
+    token: UserToken,
+    body: Json<CartItemUpdate>,
+    sources: &State<Arc<SourceRegistry>>,
+    carts: &State<Mutex<CartStore>>,
+) -> Result<Json<Cart>, (Status, String)> {
+    let update = body.into_inner();
+    if update.quantity > MAX_QUANTITY {
+        return Err((Status::BadRequest, format!("The quantity must be at most {}", MAX_QUANTITY)));
+    }
+    let vendor = carts.lock().await.carts.get(&id)
+        .map(|cart| cart.vendor.clone())
+        .ok_or((Status::NotFound, format!("No cart {}", id)))?;
+
+    let menu = sources.menu(&vendor).await?;
+    let menu = parse_menu(&menu.value)
+        .map_err(|er| (Status::BadGateway, format!("Unexpected menu format {:?}", er)))?;
+    let item = find_item(&menu, &update.item)
+        .ok_or((Status::BadRequest, format!("Item {} is not on the menu of {}", update.item, vendor)))?;
+    if update.quantity > 0 && !item.is_available() {
+        return Err((Status::Conflict, format!("{} is not available", item.name)));
+    }
+    if let Some(price) = update.price {
+        if (price * 100.0).round() as i64 != item.cost {
+            return Err((Status::Conflict, format!("The price of {} is now {:.2} kr", item.name, dkk(item.cost))));
+        }
+    }
+
+    let mut carts = carts.lock().await;
+    let cart = carts.carts.get_mut(&id)
+        .ok_or((Status::NotFound, format!("No cart {}", id)))?;
+    let is_new_line = !cart.lines.iter().any(|line| line.token == token && line.item == item.key);
+    if update.quantity > 0 && is_new_line && cart.lines.len() >= MAX_CART_LINES {
+        return Err((Status::Conflict, format!("Cart {} is full", id)));
+    }
+    cart.set_line(token, update.name, item.key.clone(), item.name.clone(), item.cost, update.quantity);
+    cart.updated_at = Utc::now();
+    Ok(Json(cart.clone()))
+}
+
+#[get("/carts/<id>/timeslots")]
+#[instrument]
+pub async fn get_cart_timeslots(id: u64, carts: &State<Mutex<CartStore>>, sources: &State<Arc<SourceRegistry>>) -> Result<RawJson<String>, (Status, String)> {
+    let request = carts.lock().await.carts.get(&id)
+        .map(|cart| cart.timeslot_request())
+        .ok_or((Status::NotFound, format!("No cart {}", id)))?;
+    if request.products.is_empty() {
+        return Err((Status::Conflict, format!("Cart {} is empty", id)));
+    }
+
+    let timeslots_json = sources.timeslots(&request).await?;
+    Ok(RawJson(timeslots_json))
+}
+
+#[get("/carts/<id>/summary")]
+#[instrument]
+pub async fn get_cart_summary(id: u64, carts: &State<Mutex<CartStore>>) -> Result<String, (Status, String)> {
+    carts.lock().await.carts.get(&id)
+        .map(|cart| cart.summary())
+        .ok_or((Status::NotFound, format!("No cart {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn cart() -> Cart {
+        Cart {
+            id: 1,
+            vendor: "compassdk_dbvendor1".to_string(),
+            vendor_name: "Dhaba".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            lines: Vec::new(),
+            member_totals: Vec::new(),
+            total: 0.0,
+        }
+    }
+
+    fn token(name: &str) -> UserToken {
+        UserToken(name.to_string())
+    }
+
+    #[test]
+    fn computes_totals_per_member_and_overall() {
+        let mut cart = cart();
+        cart.set_line(token("a"), Some("Anna".to_string()), "dal".to_string(), "Dal".to_string(), 6500, 2);
+        cart.set_line(token("b"), Some("Bo".to_string()), "dal".to_string(), "Dal".to_string(), 6500, 1);
+        cart.set_line(token("b"), None, "naan".to_string(), "Naan".to_string(), 1550, 1);
+        assert_eq!(cart.member_totals.iter().map(|total| (total.member.as_str(), total.total)).collect::<Vec<_>>(), vec![("Anna", 130.0), ("Bo", 80.5)]);
+        assert_eq!(cart.total, 210.5);
+    }
+
+    #[test]
+    fn keeps_members_with_the_same_name_apart() {
+        let mut cart = cart();
+        cart.set_line(token("a"), None, "dal".to_string(), "Dal".to_string(), 6500, 1);
+        cart.set_line(token("b"), None, "naan".to_string(), "Naan".to_string(), 1550, 2);
+        cart.set_line(token("c"), Some("Bo".to_string()), "dal".to_string(), "Dal".to_string(), 6500, 1);
+        cart.set_line(token("d"), Some("Bo".to_string()), "naan".to_string(), "Naan".to_string(), 1550, 1);
+        let totals: Vec<(&str, f64)> = cart.member_totals.iter().map(|total| (total.member.as_str(), total.total)).collect();
+        assert_eq!(totals, vec![("Anonymous", 65.0), ("Anonymous", 31.0), ("Bo", 65.0), ("Bo", 15.5)]);
+        assert!(cart.summary().contains("Anonymous: 2 x Naan (31.00 kr)\n"));
+
+        cart.set_line(token("a"), Some("Anna".to_string()), "naan".to_string(), "Naan".to_string(), 1550, 1);
+        assert!(cart.lines.iter().filter(|line| line.token == token("a")).all(|line| line.member == "Anna"));
+    }
+
+    #[test]
+    fn large_quantities_do_not_overflow() {
+        let mut cart = cart();
+        cart.set_line(token("a"), None, "dal".to_string(), "Dal".to_string(), i64::MAX / 2, u32::MAX);
+        cart.set_line(token("b"), None, "dal".to_string(), "Dal".to_string(), i64::MAX / 2, u32::MAX);
+        assert_eq!(cart.total, dkk(i64::MAX));
+        assert_eq!(cart.timeslot_request().products[0].quantity, u32::MAX);
+    }
+
+    #[test]
+    fn setting_quantity_replaces_and_zero_removes() {
+        let mut cart = cart();
+        cart.set_line(token("a"), Some("Anna".to_string()), "dal".to_string(), "Dal".to_string(), 6500, 2);
+        cart.set_line(token("a"), None, "dal".to_string(), "Dal".to_string(), 6500, 3);
+        assert_eq!(cart.lines.len(), 1);
+        assert_eq!(cart.lines[0].quantity, 3);
+        assert_eq!(cart.lines[0].member, "Anna");
+        cart.set_line(token("a"), None, "dal".to_string(), "Dal".to_string(), 6500, 0);
+        assert!(cart.lines.is_empty());
+        assert_eq!(cart.total, 0.0);
+    }
+
+    #[test]
+    fn combines_quantities_in_timeslot_request() {
+        let mut cart = cart();
+        cart.set_line(token("a"), None, "dal".to_string(), "Dal".to_string(), 6500, 2);
+        cart.set_line(token("b"), None, "dal".to_string(), "Dal".to_string(), 6500, 1);
+        cart.set_line(token("b"), None, "naan".to_string(), "Naan".to_string(), 1550, 1);
+        let request = cart.timeslot_request();
+        assert_eq!(request.route_name, "compassdk_dbvendor1");
+        assert_eq!(request.products.iter().map(|p| (p.product_id.as_str(), p.quantity)).collect::<Vec<_>>(), vec![("dal", 3), ("naan", 1)]);
+    }
+
+    #[test]
+    fn summarizes_order() {
+        let mut cart = cart();
+        cart.set_line(token("a"), Some("Anna".to_string()), "dal".to_string(), "Dal".to_string(), 6500, 2);
+        cart.set_line(token("b"), Some("Bo".to_string()), "naan".to_string(), "Naan".to_string(), 1550, 1);
+        assert_eq!(cart.summary(), "Group order from Dhaba\n\n2 x Dal (130.00 kr)\n1 x Naan (15.50 kr)\n\nPer person:\nAnna: 2 x Dal (130.00 kr)\nBo: 1 x Naan (15.50 kr)\n\nTotal: 145.50 kr\n");
+    }
+
+    #[test]
+    fn expires_idle_carts_and_caps_the_rest() {
+        let mut store = CartStore::default();
+        let now = Utc::now();
+        let idle = store.add("compassdk_dbvendor1".to_string(), "Dhaba".to_string(), now).unwrap().id;
+        let used = store.add("compassdk_dbvendor1".to_string(), "Dhaba".to_string(), now).unwrap().id;
+        store.carts.get_mut(&used).unwrap().updated_at = now + chrono::Duration::hours(12);
+
+        store.add("compassdk_dbvendor1".to_string(), "Dhaba".to_string(), now + CART_IDLE_TIME).unwrap();
+        assert!(!store.carts.contains_key(&idle));
+        assert!(store.carts.contains_key(&used));
+
+        while store.carts.len() < MAX_CARTS {
+            store.add("compassdk_dbvendor1".to_string(), "Dhaba".to_string(), now + CART_IDLE_TIME).unwrap();
+        }
+        assert_eq!(store.add("compassdk_dbvendor1".to_string(), "Dhaba".to_string(), now + CART_IDLE_TIME).unwrap_err().0, Status::ServiceUnavailable);
+    }
+}
@@ -0,0 +1,100 @@
+use base64::Engine;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+
+/// The hash Firebase computes of the data at a path, which queries send to get only data that changed.
+/// Missing data hashes to an empty string.
+///
+/// Children with a priority are ordered by key only, as PubQ does not use priorities.
+pub fn data_hash(data: &Value) -> String {
+    let priority = data.get(".priority").filter(|priority| !priority.is_null());
+    let mut text = priority.map(|priority| format!("priority:{}:", leaf_text(priority))).unwrap_or_default();
+    match data {
+        Value::Null => return String::new(),
+        Value::Object(children) if children.contains_key(".value") => text.push_str(&leaf_text(&children[".value"])),
+        Value::Object(children) => {
+            let mut keys: Vec<&String> = children.keys().filter(|key| !key.starts_with('.')).collect();
+            keys.sort_by(|a, b| compare_keys(a, b));
+            for key in keys {
+                let hash = data_hash(&children[key]);
+                if !hash.is_empty() {
+                    text.push_str(&format!(":{}:{}", key, hash));
+                }
+            }
+        },
+        Value::Array(children) => {
+            for (index, child) in children.iter().enumerate() {
+                let hash = data_hash(child);
+                if !hash.is_empty() {
+                    text.push_str(&format!(":{}:{}", index, hash));
+                }
+            }
+        },
+        leaf => text.push_str(&leaf_text(leaf)),
+    }
+    if text.is_empty() {
+        return String::new();
+    }
+    base64::engine::general_purpose::STANDARD.encode(Sha1::digest(text.as_bytes()))
+}
+
+fn leaf_text(value: &Value) -> String {
+    match value {
+        // Numbers are JavaScript doubles, hashed by their bytes.
+        Value::Number(number) => format!("number:{:016x}", number.as_f64().unwrap_or_default().to_bits()),
+        Value::String(text) => format!("string:{}", text),
+        Value::Bool(flag) => format!("boolean:{}", flag),
+        other => format!("string:{}", other),
+    }
+}
+
+/// Keys that are 32 bit integers come first in numeric order, then the rest in string order.
+fn compare_keys(a: &str, b: &str) -> Ordering {
+    match (integer_key(a), integer_key(b)) {
+        (Some(a_int), Some(b_int)) => a_int.cmp(&b_int).then(a.len().cmp(&b.len())),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+fn integer_key(key: &str) -> Option<i32> {
+    let digits = key.strip_prefix('-').unwrap_or(key);
+    let significant = digits.trim_start_matches('0');
+    if digits.is_empty() || significant.len() > 10 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    key.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hashes_leaves_like_firebase() {
+        // base64(sha1("string:Dhaba")), numbers are hashed as doubles
+        assert_eq!(data_hash(&json!("Dhaba")), "MFlKTeqzMS56X5xoz7/Jja4G+Jw=");
+        assert_eq!(data_hash(&json!(3500)), data_hash(&json!(3500.0)));
+        assert_eq!(data_hash(&Value::Null), "");
+        assert_eq!(data_hash(&json!({})), "");
+    }
+
+    #[test]
+    fn arrays_hash_like_index_keyed_objects() {
+        let array = json!([{"Name": "Hot dish", "Cost": 3500}, {"Name": "Soup", "Cost": 4000}]);
+        let object = json!({"1": {"Cost": 4000, "Name": "Soup"}, "0": {"Name": "Hot dish", "Cost": 3500}});
+        assert_eq!(data_hash(&array), data_hash(&object));
+        assert_ne!(data_hash(&array), data_hash(&json!([{"Name": "Hot dish", "Cost": 3000}])));
+    }
+
+    #[test]
+    fn orders_integer_keys_numerically() {
+        let mut keys = vec!["b", "10", "2", "a", "-1", "99999999999"];
+        keys.sort_by(|a, b| compare_keys(a, b));
+        assert_eq!(keys, vec!["-1", "2", "10", "99999999999", "a", "b"]);
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::sync::mpsc;
+
+/// Firebase splits messages into frames of at most this many characters.
+pub const MAX_FRAME_SIZE: usize = 16384;
+
+/// Frame counts are sent as a frame of at most this many digits.
+const MAX_COUNT_DIGITS: usize = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// The announced frames cannot fit within the maximum message size.
+    TooManyFrames { count: usize, max: usize },
+    /// The message grew past the maximum message size.
+    TooLarge { size: usize, max: usize },
+    /// A frame of a multi-frame message had more characters than Firebase sends in one.
+    FrameTooLarge { frame: usize, size: usize },
+    /// The connection ended or timed out before all announced frames arrived.
+    Truncated { expected: usize, received: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooManyFrames { count, max } => write!(f, "Message announced {} frames, at most {} are allowed", count, max),
+            FrameError::TooLarge { size, max } => write!(f, "Message of at least {} bytes is larger than the maximum of {} bytes", size, max),
+            FrameError::FrameTooLarge { frame, size } => write!(f, "Frame {} has {} characters, frames have at most {}", frame, size, MAX_FRAME_SIZE),
+            FrameError::Truncated { expected, received } => write!(f, "Message ended after {} of {} frames", received, expected),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// What a text frame turned out to be.
+#[derive(Debug, PartialEq)]
+pub enum Decoded<'a> {
+    /// A whole message in one frame.
+    Message(&'a str),
+    /// The start of a message sent in `count` frames.
+    Started { count: usize },
+    /// A frame of a multi-frame message, `last` when the message is complete.
+    Part { text: &'a str, last: bool },
+    /// An empty frame count, which servers send to keep the connection alive.
+    KeepAlive,
+}
+
+#[derive(Debug)]
+struct Pending {
+    expected: usize,
+    received: usize,
+    size: usize,
+}
+
+/// Splits the text frames of a Firebase WebSocket into messages.
+/// A message is either one frame, or a frame with the count of frames that follow and then those frames.
+/// Once a count is announced, every text frame belongs to that message, even when it looks like a count.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    max_message_size: usize,
+    pending: Option<Pending>,
+}
+
+impl FrameDecoder {
+    pub fn new(max_message_size: usize) -> Self {
+        FrameDecoder { max_message_size, pending: None }
+    }
+
+    pub fn decode<'a>(&mut self, frame: &'a str) -> Result<Decoded<'a>, FrameError> {
+        let Some(pending) = &mut self.pending else {
+            return self.decode_start(frame);
+        };
+        pending.received += 1;
+        pending.size += frame.len();
+        // The limit is in characters, so only frames with multi-byte characters need counting.
+        if frame.len() > MAX_FRAME_SIZE && frame.chars().count() > MAX_FRAME_SIZE {
+            let index = pending.received;
+            self.pending = None;
+            return Err(FrameError::FrameTooLarge { frame: index, size: frame.chars().count() });
+        }
+        if pending.size > self.max_message_size {
+            let size = pending.size;
+            self.pending = None;
+            return Err(FrameError::TooLarge { size, max: self.max_message_size });
+        }
+        let last = pending.received == pending.expected;
+        if last {
+            self.pending = None;
+        }
+        Ok(Decoded::Part { text: frame, last })
+    }
+
+    fn decode_start<'a>(&mut self, frame: &'a str) -> Result<Decoded<'a>, FrameError> {
+        let count = (frame.len() <= MAX_COUNT_DIGITS && !frame.is_empty() && frame.bytes().all(|byte| byte.is_ascii_digit()))
+            .then(|| frame.parse::<usize>().ok())
+            .flatten();
+        match count {
+            Some(0) => Ok(Decoded::KeepAlive),
+            Some(count) => {
+                let max = self.max_message_size.div_ceil(MAX_FRAME_SIZE).max(1);
+                if count > max {
+                    return Err(FrameError::TooManyFrames { count, max });
+                }
+                self.pending = Some(Pending { expected: count, received: 0, size: 0 });
+                Ok(Decoded::Started { count })
+            },
+            None if frame.len() > self.max_message_size => Err(FrameError::TooLarge { size: frame.len(), max: self.max_message_size }),
+            None => Ok(Decoded::Message(frame)),
+        }
+    }
+
+    /// Gives up on a message that is still being received, e.g. after a timeout.
+    pub fn abort(&mut self) -> Option<FrameError> {
+        self.pending.take().map(|pending| FrameError::Truncated { expected: pending.expected, received: pending.received })
+    }
+}
+
+/// Reads the frames of a message as they are sent through a channel, so it can be parsed while it arrives.
+/// Dropping the sender ends the message.
+pub struct FrameReader {
+    frames: mpsc::Receiver<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl FrameReader {
+    pub fn new() -> (mpsc::Sender<Vec<u8>>, Self) {
+        let (sender, frames) = mpsc::channel();
+        (sender, FrameReader { frames, current: Cursor::new(Vec::new()) })
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.frames.recv() {
+                Ok(frame) => self.current = Cursor::new(frame),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    fn split(message: &str, frame_size: usize) -> Vec<String> {
+        let chars: Vec<char> = message.chars().collect();
+        chars.chunks(frame_size).map(|chunk| chunk.iter().collect()).collect()
+    }
+
+    /// Frames `message` the way Firebase sends it.
+    fn encode(message: &str) -> Vec<String> {
+        let frames = split(message, MAX_FRAME_SIZE);
+        if frames.len() <= 1 {
+            return vec![message.to_string()];
+        }
+        std::iter::once(frames.len().to_string()).chain(frames).collect()
+    }
+
+    /// Decodes `frames` into whole messages.
+    fn decode_all(decoder: &mut FrameDecoder, frames: &[String]) -> Result<Vec<String>, FrameError> {
+        let mut messages = Vec::new();
+        let mut current = String::new();
+        for frame in frames {
+            match decoder.decode(frame)? {
+                Decoded::Message(text) => messages.push(text.to_string()),
+                Decoded::Started { .. } | Decoded::KeepAlive => {},
+                Decoded::Part { text, last } => {
+                    current.push_str(text);
+                    if last {
+                        messages.push(std::mem::take(&mut current));
+                    }
+                },
+            }
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn skips_keepalive_frames() {
+        let mut decoder = FrameDecoder::new(1024);
+        assert_eq!(decoder.decode("0"), Ok(Decoded::KeepAlive));
+        assert_eq!(decoder.decode(r#"{"t":"d"}"#), Ok(Decoded::Message(r#"{"t":"d"}"#)));
+    }
+
+    #[test]
+    fn frames_that_look_like_counts_are_parts() {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE * 2);
+        assert_eq!(decoder.decode("2"), Ok(Decoded::Started { count: 2 }));
+        assert_eq!(decoder.decode("12"), Ok(Decoded::Part { text: "12", last: false }));
+        assert_eq!(decoder.decode("0"), Ok(Decoded::Part { text: "0", last: true }));
+        assert_eq!(decoder.decode("0"), Ok(Decoded::KeepAlive));
+    }
+
+    #[test]
+    fn rejects_messages_over_the_limit() {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE * 2);
+        assert_eq!(decoder.decode("3"), Err(FrameError::TooManyFrames { count: 3, max: 2 }));
+
+        let mut decoder = FrameDecoder::new(10);
+        assert_eq!(decoder.decode("1"), Ok(Decoded::Started { count: 1 }));
+        assert_eq!(decoder.decode("01234567890"), Err(FrameError::TooLarge { size: 11, max: 10 }));
+        assert_eq!(decoder.decode(r#"{"d":"01234567890"}"#), Err(FrameError::TooLarge { size: 19, max: 10 }));
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE * 4);
+        decoder.decode("2").unwrap();
+        let frame = "ø".repeat(MAX_FRAME_SIZE + 1);
+        assert_eq!(decoder.decode(&frame), Err(FrameError::FrameTooLarge { frame: 1, size: MAX_FRAME_SIZE + 1 }));
+
+        // Multi-byte characters count once.
+        decoder.decode("2").unwrap();
+        assert!(decoder.decode(&"ø".repeat(MAX_FRAME_SIZE)).is_ok());
+    }
+
+    #[test]
+    fn reports_truncated_messages() {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE * 4);
+        assert_eq!(decoder.abort(), None);
+        decoder.decode("3").unwrap();
+        decoder.decode("{").unwrap();
+        assert_eq!(decoder.abort(), Some(FrameError::Truncated { expected: 3, received: 1 }));
+        assert_eq!(decoder.decode("{}"), Ok(Decoded::Message("{}")));
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_what_was_encoded(texts in prop::collection::vec(("[0-9a-zæøå\"{} ]{1,200}", 1..400usize), 1..5)) {
+            // Repeated, so messages span several frames and frames end anywhere, also in digits.
+            let messages: Vec<String> = texts.iter().map(|(text, times)| serde_json::json!({"t": "d", "d": text.repeat(*times)}).to_string()).collect();
+            let frames: Vec<String> = messages.iter().flat_map(|message| encode(message)).collect();
+            let mut decoder = FrameDecoder::new(1024 * 1024);
+            prop_assert_eq!(decode_all(&mut decoder, &frames)?, messages);
+            prop_assert_eq!(decoder.abort(), None);
+        }
+
+        #[test]
+        fn never_accepts_more_than_the_limit(frames in prop::collection::vec("[0-9]{1,3}|[a-z{}]{0,30}", 0..40), max in 1..200usize) {
+            let mut decoder = FrameDecoder::new(max);
+            let mut pending = 0;
+            for frame in &frames {
+                match decoder.decode(frame) {
+                    Ok(Decoded::Message(text)) => prop_assert!(text.len() <= max),
+                    Ok(Decoded::Started { .. }) => pending = 0,
+                    Ok(Decoded::Part { text, .. }) => {
+                        pending += text.len();
+                        prop_assert!(pending <= max);
+                    },
+                    Ok(Decoded::KeepAlive) => {},
+                    Err(_) => pending = 0,
+                }
+            }
+        }
+
+        #[test]
+        fn reader_parses_frames_as_one_message(text in "[a-zæøå ]{0,300}", frame_size in 1..50usize) {
+            let message = serde_json::json!({"t": "d", "d": {"b": {"d": text}}});
+            let (frames, reader) = FrameReader::new();
+            for frame in split(&message.to_string(), frame_size) {
+                frames.send(frame.into_bytes()).unwrap();
+            }
+            drop(frames);
+            let parsed: serde_json::Value = serde_json::from_reader(reader).unwrap();
+            prop_assert_eq!(parsed, message);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{instrument, warn};
+
+use crate::menu_source::SourceRegistry;
+use crate::pubq_client::{ConnectionState, PubqClient, SITE};
+use crate::telemetry::Telemetry;
+use crate::timeslots::{TimeSlotCache, TimeslotStatus};
+use crate::{VendorCache, VenderMenuCache, CACHE_TTL};
+
+#[derive(Serialize, Debug)]
+pub struct WebSocketHealth {
+    state: ConnectionState,
+    last_query_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheHealth {
+    age_seconds: u64,
+    fresh: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SourceHealth {
+    /// The sites of the source, empty when they failed to load.
+    sites: Vec<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TelemetryHealth {
+    /// `otlp` or `console`.
+    exporter: &'static str,
+    endpoint: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// `ok`, or `unavailable` when no vendor data has been loaded yet.
+    status: &'static str,
+    websocket: WebSocketHealth,
+    timeslot_service: Option<TimeslotStatus>,
+    /// Vendor list per site.
+    vendors: BTreeMap<String, CacheHealth>,
+    /// Every registered menu source by name, PubQ as well as static menu files.
+    sources: BTreeMap<String, SourceHealth>,
+    /// Menu per vendor, left out while a menu is being fetched.
+    menus: Option<BTreeMap<String, CacheHealth>>,
+    telemetry: TelemetryHealth,
+}
+
+fn cache_health(fetched: rocket::tokio::time::Instant) -> CacheHealth {
+    let age = fetched.elapsed();
+    CacheHealth { age_seconds: age.as_secs(), fresh: age < CACHE_TTL }
+}
+
+async fn report(
+    sources: &SourceRegistry,
+    client: &Mutex<PubqClient>,
+    vendor_cache: &Mutex<Option<VendorCache>>,
+    menu_cache: &Mutex<VenderMenuCache>,
+    timeslot_cache: &Mutex<TimeSlotCache>,
+    telemetry: &Option<Telemetry>,
+) -> Health {
+    // Queries hold the locks while waiting for PubQ, so do not wait for them.
+    let websocket = match client.try_lock() {
+        Some(client) => WebSocketHealth {
+            state: if client.is_connected() { ConnectionState::Connected } else { ConnectionState::Disconnected },
+            last_query_at: client.last_query_at(),
+        },
+        None => WebSocketHealth { state: ConnectionState::Busy, last_query_at: None },
+    };
+    let vendors: BTreeMap<String, CacheHealth> = vendor_cache.lock().await.as_ref()
+        .map(|cache| (SITE.to_string(), cache_health(cache.0)))
+        .into_iter()
+        .collect();
+    let menus = menu_cache.try_lock().map(|cache| cache.0.iter()
+        .map(|(vendor, (fetched, _, _, _))| (vendor.clone(), cache_health(*fetched)))
+        .collect());
+    let mut source_health = BTreeMap::new();
+    for source in sources.sources() {
+        let health = match source.sites().await {
+            Ok(sites) => SourceHealth { sites, error: None },
+            Err((_, er)) => SourceHealth { sites: Vec::new(), error: Some(er) },
+        };
+        source_health.insert(source.name().to_string(), health);
+    }
+    let telemetry = match telemetry {
+        Some(telemetry) => TelemetryHealth { exporter: "otlp", endpoint: Some(telemetry.endpoint.clone()) },
+        None => TelemetryHealth { exporter: "console", endpoint: None },
+    };
+
+    Health {
+        status: if vendors.is_empty() { "unavailable" } else { "ok" },
+        websocket,
+        timeslot_service: timeslot_cache.lock().await.last_status.clone(),
+        vendors,
+        sources: source_health,
+        menus,
+        telemetry,
+    }
+}
+
+#[get("/health")]
+#[instrument]
+pub fn health() -> &'static str {
+    "OK"
+}
+
+/// Whether the server is running. It does not depend on PubQ or the payments service.
+#[get("/health/live")]
+#[instrument(skip_all)]
+pub async fn live(
+    sources: &State<Arc<SourceRegistry>>,
+    client: &State<Arc<Mutex<PubqClient>>>,
+    vendor_cache: &State<Arc<Mutex<Option<VendorCache>>>>,
+    menu_cache: &State<Arc<Mutex<VenderMenuCache>>>,
+    timeslot_cache: &State<Arc<Mutex<TimeSlotCache>>>,
+    telemetry: &State<Option<Telemetry>>,
+) -> Json<Health> {
+    Json(report(sources, client, vendor_cache, menu_cache, timeslot_cache, telemetry).await)
+}
+
+/// Whether the server can serve vendors, which it can once vendor data has been loaded.
+#[get("/health/ready")]
+#[instrument(skip_all)]
+pub async fn ready(
+    sources: &State<Arc<SourceRegistry>>,
+    client: &State<Arc<Mutex<PubqClient>>>,
+    vendor_cache: &State<Arc<Mutex<Option<VendorCache>>>>,
+    menu_cache: &State<Arc<Mutex<VenderMenuCache>>>,
+    timeslot_cache: &State<Arc<Mutex<TimeSlotCache>>>,
+    telemetry: &State<Option<Telemetry>>,
+) -> (Status, Json<Health>) {
+    let health = report(sources, client, vendor_cache, menu_cache, timeslot_cache, telemetry).await;
+    if health.vendors.is_empty() {
+        warn!("Not ready, no vendor data has been loaded");
+        return (Status::ServiceUnavailable, Json(health));
+    }
+    (Status::Ok, Json(health))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::menu_source::{StaticSource, StaticSourceConfig};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[rocket::async_test]
+    async fn unavailable_until_vendors_are_loaded() {
+        let sources = SourceRegistry::new(Vec::new());
+        let client = Mutex::new(PubqClient::new());
+        let vendor_cache = Mutex::new(None);
+        let menu_cache = Mutex::new(VenderMenuCache(HashMap::new()));
+        let timeslot_cache = Mutex::new(TimeSlotCache::default());
+
+        let health = report(&sources, &client, &vendor_cache, &menu_cache, &timeslot_cache, &None).await;
+        assert_eq!(health.status, "unavailable");
+        assert_eq!(health.websocket.state, ConnectionState::Disconnected);
+        assert_eq!(health.telemetry.exporter, "console");
+
+        *vendor_cache.lock().await = Some(VendorCache(rocket::tokio::time::Instant::now(), "[]".to_string(), String::new(), chrono::Utc::now()));
+        let health = report(&sources, &client, &vendor_cache, &menu_cache, &timeslot_cache, &None).await;
+        assert_eq!(health.status, "ok");
+        assert!(health.vendors[SITE].fresh);
+    }
+
+    #[rocket::async_test]
+    async fn reports_static_sources() {
+        let path = std::env::temp_dir().join(format!("health_static_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"sites": {"campus": []}}"#).unwrap();
+        let sources = SourceRegistry::new(vec![
+            Arc::new(StaticSource::new("campus", StaticSourceConfig { path: path.clone() })),
+            Arc::new(StaticSource::new("missing", StaticSourceConfig { path: PathBuf::from("/nonexistent/menus.json") })),
+        ]);
+        let client = Mutex::new(PubqClient::new());
+        let vendor_cache = Mutex::new(None);
+        let menu_cache = Mutex::new(VenderMenuCache(HashMap::new()));
+        let timeslot_cache = Mutex::new(TimeSlotCache::default());
+
+        let health = report(&sources, &client, &vendor_cache, &menu_cache, &timeslot_cache, &None).await;
+        assert_eq!(health.sources["campus"].sites, vec!["campus".to_string()]);
+        assert_eq!(health.sources["campus"].error, None);
+        assert!(health.sources["missing"].sites.is_empty());
+        assert!(health.sources["missing"].error.is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::time::Duration;
+use sha1::{Digest, Sha1};
+use std::io::{Cursor, Write};
+use tracing::warn;
+
+/// Smaller bodies are sent uncompressed, as compressing them saves next to nothing.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Brotli quality, fast enough to compress menus on every request.
+const BROTLI_QUALITY: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+}
+
+/// A JSON body with validators and compression. Clients get `304 Not Modified` when they already have it.
+/// The ETag also covers changes made after the data was fetched, e.g. tag overrides, which `Last-Modified` misses.
+#[derive(Debug)]
+pub struct CachedJson {
+    pub body: String,
+    /// When the server got the data, sent as `Last-Modified`.
+    pub last_modified: DateTime<Utc>,
+    /// How long the server still serves the data from its cache, sent as `Cache-Control: max-age`.
+    pub max_age: Duration,
+}
+
+/// The best encoding in `Accept-Encoding`, brotli before gzip.
+fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+    let accepted: Vec<&str> = accept_encoding.unwrap_or_default().split(',')
+        .filter_map(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next()?;
+            let rejected = parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            (!rejected).then_some(name)
+        })
+        .collect();
+    [Encoding::Brotli, Encoding::Gzip].into_iter()
+        .find(|encoding| accepted.iter().any(|name| name.eq_ignore_ascii_case(encoding.name())))
+        .unwrap_or(Encoding::Identity)
+}
+
+fn compress(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22);
+            writer.write_all(body)?;
+            Ok(writer.into_inner())
+        },
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        },
+        Encoding::Identity => Ok(body.to_vec()),
+    }
+}
+
+/// A strong ETag of the body, which differs per encoding as the bytes sent differ.
+fn etag(body: &str, encoding: Encoding) -> String {
+    let hash = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha1::digest(body.as_bytes()));
+    match encoding {
+        Encoding::Identity => format!("\"{}\"", hash),
+        encoding => format!("\"{}-{}\"", hash, encoding.name()),
+    }
+}
+
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client's copy is current. `If-None-Match` wins over `If-Modified-Since`, as in RFC 9110.
+/// `If-None-Match` uses the weak comparison, so proxies that turned the ETag weak still get `304`.
+fn not_modified(request: &Request<'_>, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = request.headers().get_one("If-None-Match") {
+        let weak = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+        return if_none_match.split(',').map(str::trim).any(|tag| tag == "*" || weak(tag) == weak(etag));
+    }
+    request.headers().get_one("If-Modified-Since")
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp())
+}
+
+impl<'r> Responder<'r, 'static> for CachedJson {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let encoding = match negotiate(request.headers().get_one("Accept-Encoding")) {
+            _ if self.body.len() < MIN_COMPRESS_SIZE => Encoding::Identity,
+            encoding => encoding,
+        };
+        let etag = etag(&self.body, encoding);
+        let cache_control = match self.max_age.as_secs() {
+            0 => "no-cache".to_string(),
+            max_age => format!("max-age={}", max_age),
+        };
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", etag.clone()))
+            .header(Header::new("Last-Modified", http_date(self.last_modified)))
+            .header(Header::new("Cache-Control", cache_control))
+            .header(Header::new("Vary", "Accept-Encoding"));
+        if not_modified(request, &etag, self.last_modified) {
+            return response.status(Status::NotModified).ok();
+        }
+
+        let body = compress(self.body.as_bytes(), encoding).map_err(|er| {
+            warn!("Failed to compress response: {}", er);
+            Status::InternalServerError
+        })?;
+        if encoding != Encoding::Identity {
+            response.header(Header::new("Content-Encoding", encoding.name()));
+        }
+        response
+            .header(ContentType::JSON)
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::local::blocking::Client;
+    use std::io::Read;
+
+    const LAST_MODIFIED: &str = "Mon, 19 Oct 2026 10:00:00 GMT";
+
+    #[get("/menu")]
+    fn menu() -> CachedJson {
+        CachedJson {
+            body: serde_json::json!({"items": vec!["Smørrebrød"; 200]}).to_string(),
+            last_modified: DateTime::parse_from_rfc2822(LAST_MODIFIED).unwrap().with_timezone(&Utc),
+            max_age: Duration::from_secs(120),
+        }
+    }
+
+    fn client() -> Client {
+        Client::tracked(rocket::build().mount("/", routes![menu])).unwrap()
+    }
+
+    #[test]
+    fn answers_current_copies_with_not_modified() {
+        let client = client();
+        let response = client.get("/menu").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("max-age=120"));
+        assert_eq!(response.headers().get_one("Last-Modified"), Some(LAST_MODIFIED));
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        let response = client.get("/menu").header(Header::new("If-None-Match", etag.clone())).dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.into_bytes(), None);
+        let response = client.get("/menu").header(Header::new("If-None-Match", format!("\"other\", W/{}", etag))).dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+
+        let response = client.get("/menu").header(Header::new("If-None-Match", "\"old\"")).header(Header::new("If-Modified-Since", LAST_MODIFIED)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/menu").header(Header::new("If-Modified-Since", LAST_MODIFIED)).dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        let response = client.get("/menu").header(Header::new("If-Modified-Since", "Mon, 19 Oct 2026 09:59:59 GMT")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn compresses_large_bodies() {
+        let client = client();
+        let plain = client.get("/menu").dispatch();
+        let plain_etag = plain.headers().get_one("ETag").unwrap().to_string();
+        let plain = plain.into_string().unwrap();
+
+        let response = client.get("/menu").header(Header::new("Accept-Encoding", "gzip, deflate, br;q=0")).dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_ne!(response.headers().get_one("ETag"), Some(plain_etag.as_str()));
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(&response.into_bytes().unwrap()[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, plain);
+
+        let response = client.get("/menu").header(Header::new("Accept-Encoding", "gzip, br")).dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("br"));
+        let mut body = String::new();
+        brotli::Decompressor::new(&response.into_bytes().unwrap()[..], 4096).read_to_string(&mut body).unwrap();
+        assert_eq!(body, plain);
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        assert_eq!(negotiate(None), Encoding::Identity);
+        assert_eq!(negotiate(Some("gzip;q=0.5, BR")), Encoding::Brotli);
+        assert_eq!(negotiate(Some("br;q=0, gzip;q=0")), Encoding::Identity);
+    }
+}
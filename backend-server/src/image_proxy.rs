@@ -0,0 +1,480 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageReader, Limits};
+use rocket::futures::lock::{Mutex, MutexGuard};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::Deserialize;
+use rocket::tokio::time::Duration;
+use rocket::State;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+use tracing::{info, instrument, warn};
+
+use crate::menu_source::SourceRegistry;
+use crate::metrics::cache_lookup;
+
+/// Source images larger than this are not fetched.
+const MAX_SOURCE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Source images may be this many times larger than `max_dimension`, larger ones are not decoded.
+const MAX_SOURCE_SCALE: u32 = 4;
+
+/// Requested sizes are rounded up to one of these, or to `max_dimension`.
+const SIZE_BUCKETS: &[u32] = &[64, 120, 240, 480, 1024];
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const JPEG_QUALITY: u8 = 80;
+
+/// Resized images never change, so browsers may keep them for a year.
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// The image proxy, from `[<profile>.image_proxy]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ImageProxyConfig {
+    pub cache_dir: PathBuf,
+    /// The cache deletes the least recently used images beyond this size.
+    pub cache_bytes: u64,
+    /// Largest width and height served.
+    pub max_dimension: u32,
+}
+
+impl Default for ImageProxyConfig {
+    fn default() -> Self {
+        ImageProxyConfig { cache_dir: PathBuf::from("image_cache"), cache_bytes: 100 * 1024 * 1024, max_dimension: 1024 }
+    }
+}
+
+/// A cached file, by the hash of its key.
+struct CachedFile {
+    size: u64,
+    /// Larger is more recently used.
+    used: u64,
+}
+
+/// The files in the cache directory, loaded from it on first use and kept up to date after that.
+#[derive(Default)]
+struct CacheIndex {
+    loaded: bool,
+    files: HashMap<String, CachedFile>,
+    total_bytes: u64,
+    next_use: u64,
+}
+
+impl CacheIndex {
+    fn touch(&mut self, name: &str) {
+        self.next_use += 1;
+        if let Some(file) = self.files.get_mut(name) {
+            file.used = self.next_use;
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        self.next_use += 1;
+        if let Some(old) = self.files.insert(name, CachedFile { size, used: self.next_use }) {
+            self.total_bytes -= old.size;
+        }
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(old) = self.files.remove(name) {
+            self.total_bytes -= old.size;
+        }
+    }
+
+    /// The least recently used file.
+    fn oldest(&self) -> Option<String> {
+        self.files.iter().min_by_key(|(_, file)| file.used).map(|(name, _)| name.clone())
+    }
+}
+
+/// Source and resized images on disk, with their sizes and use kept in memory.
+/// The index is only locked for bookkeeping. Files are written to a temporary name and renamed into place,
+/// and evicted files are renamed aside, under the lock, so reads and writes never see a partial file.
+pub struct ImageCache {
+    config: ImageProxyConfig,
+    index: Mutex<CacheIndex>,
+    /// A lock per key being loaded, see `ImageCache::loading`.
+    loading: Mutex<HashMap<String, Weak<Mutex<()>>>>,
+    next_temp: AtomicU64,
+    client: reqwest::Client,
+}
+
+impl ImageCache {
+    pub fn new(config: ImageProxyConfig) -> Self {
+        // Redirects could lead anywhere, while only known image URLs may be fetched.
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("HTTP client for images");
+        ImageCache { config, index: Mutex::new(CacheIndex::default()), loading: Mutex::new(HashMap::new()), next_temp: AtomicU64::new(0), client }
+    }
+
+    fn name(key: &str) -> String {
+        Sha1::digest(key.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Locks the index, after loading the files already in the cache directory, oldest first.
+    async fn index(&self) -> MutexGuard<'_, CacheIndex> {
+        let mut index = self.index.lock().await;
+        if !index.loaded {
+            let dir = self.config.cache_dir.clone();
+            let files = rocket::tokio::task::spawn_blocking(move || list_files(&dir)).await
+                .unwrap_or_else(|er| Err(std::io::Error::other(er)))
+                .unwrap_or_else(|er| {
+                    if er.kind() != std::io::ErrorKind::NotFound {
+                        warn!("Failed to list cached images in {:?}: {}", self.config.cache_dir, er);
+                    }
+                    Vec::new()
+                });
+            for (_, size, name) in files {
+                index.insert(name, size);
+            }
+            index.loaded = true;
+        }
+        index
+    }
+
+    /// A name in the cache directory that no cached file has, for a file that is being written or deleted.
+    fn temp_name(&self) -> String {
+        format!("{}.{}.tmp", std::process::id(), self.next_temp.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The cached bytes for `key`, marked as just used.
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let name = Self::name(key);
+        {
+            let mut index = self.index().await;
+            if !index.files.contains_key(&name) {
+                return None;
+            }
+            index.touch(&name);
+        }
+        match rocket::tokio::fs::read(self.config.cache_dir.join(&name)).await {
+            Ok(bytes) => Some(bytes),
+            Err(er) => {
+                // Evicted since it was looked up.
+                if er.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to read cached image {}: {}", name, er);
+                }
+                self.index().await.remove(&name);
+                None
+            },
+        }
+    }
+
+    /// Stores `bytes` for `key`, then deletes the least recently used files until the cache fits in `cache_bytes`.
+    async fn put(&self, key: &str, bytes: &[u8]) {
+        let name = Self::name(key);
+        let temp = self.config.cache_dir.join(self.temp_name());
+        let result = async {
+            rocket::tokio::fs::create_dir_all(&self.config.cache_dir).await?;
+            rocket::tokio::fs::write(&temp, bytes).await
+        }.await;
+        if let Err(er) = result {
+            warn!("Failed to cache image in {:?}: {}", self.config.cache_dir, er);
+            let _ = rocket::tokio::fs::remove_file(&temp).await;
+            return;
+        }
+
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index().await;
+            if let Err(er) = std::fs::rename(&temp, self.config.cache_dir.join(&name)) {
+                warn!("Failed to cache image {}: {}", name, er);
+                evicted.push(temp);
+            } else {
+                index.insert(name, bytes.len() as u64);
+            }
+            while index.total_bytes > self.config.cache_bytes {
+                let Some(oldest) = index.oldest() else { break };
+                index.remove(&oldest);
+                let aside = self.config.cache_dir.join(self.temp_name());
+                match std::fs::rename(self.config.cache_dir.join(&oldest), &aside) {
+                    Ok(()) => evicted.push(aside),
+                    Err(er) if er.kind() == std::io::ErrorKind::NotFound => {},
+                    Err(er) => warn!("Failed to delete cached image {}: {}", oldest, er),
+                }
+            }
+        }
+
+        for path in evicted {
+            if let Err(er) = rocket::tokio::fs::remove_file(&path).await {
+                warn!("Failed to delete cached image {:?}: {}", path, er);
+            }
+        }
+    }
+
+    /// A lock for loading `key`, shared by everyone loading it at the same time,
+    /// so concurrent misses for the same image fetch and resize it once.
+    async fn loading(&self, key: &str) -> Arc<Mutex<()>> {
+        let mut loading = self.loading.lock().await;
+        loading.retain(|_, lock| lock.strong_count() > 0);
+        if let Some(lock) = loading.get(key).and_then(Weak::upgrade) {
+            return lock;
+        }
+        let lock = Arc::new(Mutex::new(()));
+        loading.insert(key.to_string(), Arc::downgrade(&lock));
+        lock
+    }
+}
+
+/// The files in `dir` with their modification time and size, sorted from least recently used.
+/// Temporary files, which have a `.` in their name, are left out.
+fn list_files(dir: &Path) -> std::io::Result<Vec<(SystemTime, u64, String)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && !entry.file_name().to_string_lossy().contains('.') {
+            files.push((metadata.modified()?, metadata.len(), entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The image URLs in vendor and menu data, in `imageUrl` of locations and `ImageUrl` of items.
+pub fn collect_image_urls(value: &Value, urls: &mut HashSet<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields {
+                match field {
+                    Value::String(url) if key == "imageUrl" || key == "ImageUrl" => { urls.insert(url.clone()); },
+                    field => collect_image_urls(field, urls),
+                }
+            }
+        },
+        Value::Array(values) => values.iter().for_each(|value| collect_image_urls(value, urls)),
+        _ => {},
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Jpeg,
+    WebP,
+}
+
+/// Scales `source` down to fit in `width` x `height`, and encodes it as JPEG,
+/// or as WebP when it has transparency, which JPEG cannot store.
+/// Sources larger than `MAX_SOURCE_SCALE` times `max_dimension` are rejected before they are decoded.
+fn resize(source: &[u8], width: u32, height: u32, max_dimension: u32) -> Result<(Vec<u8>, Format), image::ImageError> {
+    let max_source_dimension = max_dimension.saturating_mul(MAX_SOURCE_SCALE);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_source_dimension);
+    limits.max_image_height = Some(max_source_dimension);
+    limits.max_alloc = Some(max_source_dimension as u64 * max_source_dimension as u64 * 4);
+    let mut reader = ImageReader::new(Cursor::new(source)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+    let image = if image.width() > width || image.height() > height {
+        image.resize(width, height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    let mut encoded = Vec::new();
+    // Logos are often RGBA without any transparent pixel.
+    let transparent = image.has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX);
+    if transparent {
+        DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(WebPEncoder::new_lossless(&mut encoded))?;
+        Ok((encoded, Format::WebP))
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY))?;
+        Ok((encoded, Format::Jpeg))
+    }
+}
+
+/// The format of an encoded image, from its first bytes.
+fn format_of(bytes: &[u8]) -> Format {
+    if bytes.starts_with(b"RIFF") { Format::WebP } else { Format::Jpeg }
+}
+
+async fn fetch_source(src: &str, cache: &ImageCache) -> Result<Vec<u8>, (Status, String)> {
+    let key = format!("source {}", src);
+    if let Some(bytes) = cache.get(&key).await {
+        return Ok(bytes);
+    }
+    let loading = cache.loading(&key).await;
+    let _loading = loading.lock().await;
+    // Someone else may have fetched it while we waited.
+    if let Some(bytes) = cache.get(&key).await {
+        return Ok(bytes);
+    }
+    info!("Fetching image {}", src);
+    let fetch_error = |er: reqwest::Error| (Status::BadGateway, format!("Fetching image failed {}", er));
+    let mut response = cache.client.get(src).send().await.map_err(fetch_error)?;
+    if !response.status().is_success() {
+        return Err((Status::BadGateway, format!("Fetching image failed with status {}", response.status())));
+    }
+    // Read in chunks, so a wrong Content-Length cannot make it read more than the limit.
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > MAX_SOURCE_BYTES {
+            return Err((Status::BadGateway, format!("Image is larger than {} bytes", MAX_SOURCE_BYTES)));
+        }
+    }
+    cache.put(&key, &bytes).await;
+    Ok(bytes)
+}
+
+/// A resized image, which never changes for the same URL.
+pub struct ProxiedImage(Vec<u8>, Format);
+
+impl<'r> Responder<'r, 'static> for ProxiedImage {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = match self.1 {
+            Format::Jpeg => ContentType::JPEG,
+            Format::WebP => ContentType::WEBP,
+        };
+        Response::build()
+            .header(content_type)
+            .header(Header::new("Cache-Control", CACHE_CONTROL))
+            .sized_body(self.0.len(), Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// The smallest size bucket that holds `size`, so a handful of sizes is cached per image.
+fn bucket(size: u32, max_dimension: u32) -> u32 {
+    SIZE_BUCKETS.iter().copied()
+        .find(|bucket| *bucket >= size && *bucket < max_dimension)
+        .unwrap_or(max_dimension)
+}
+
+/// An image of a vendor or menu item, scaled down to fit in `w` x `h`, rounded up to a size bucket.
+#[get("/img?<src>&<w>&<h>")]
+#[instrument(skip(sources, cache))]
+pub async fn get_image(
+    src: &str,
+    w: Option<u32>,
+    h: Option<u32>,
+    sources: &State<Arc<SourceRegistry>>,
+    cache: &State<ImageCache>,
+) -> Result<ProxiedImage, (Status, String)> {
+    let max_dimension = cache.config.max_dimension;
+    let (width, height) = (w.unwrap_or(max_dimension), h.unwrap_or(max_dimension));
+    if width == 0 || height == 0 || width > max_dimension || height > max_dimension {
+        return Err((Status::BadRequest, format!("Width and height must be between 1 and {}", max_dimension)));
+    }
+    let (width, height) = (bucket(width, max_dimension), bucket(height, max_dimension));
+    // Only images of loaded vendors and menus, so the proxy cannot be used to fetch anything else.
+    if !src.starts_with("https://") || !sources.knows_image(src).await {
+        warn!("Refusing to proxy unknown image {}", src);
+        return Err((Status::NotFound, format!("Unknown image {}", src)));
+    }
+
+    let key = format!("{}x{} {}", width, height, src);
+    if let Some(bytes) = cache.get(&key).await {
+        cache_lookup("images", true);
+        let format = format_of(&bytes);
+        return Ok(ProxiedImage(bytes, format));
+    }
+    cache_lookup("images", false);
+
+    let loading = cache.loading(&key).await;
+    let _loading = loading.lock().await;
+    // Someone else may have resized it while we waited.
+    if let Some(bytes) = cache.get(&key).await {
+        let format = format_of(&bytes);
+        return Ok(ProxiedImage(bytes, format));
+    }
+    let source = fetch_source(src, cache).await?;
+    let (bytes, format) = rocket::tokio::task::spawn_blocking(move || resize(&source, width, height, max_dimension)).await
+        .map_err(|er| (Status::InternalServerError, format!("Resizing image failed {}", er)))?
+        .map_err(|er| (Status::BadGateway, format!("Unexpected image format {}", er)))?;
+    cache.put(&key, &bytes).await;
+    Ok(ProxiedImage(bytes, format))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    fn png(width: u32, height: u32, alpha: u8) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(width, height, Rgba([200, 80, 20, alpha])).write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn scales_down_and_keeps_transparency() {
+        let (jpeg, format) = resize(&png(400, 200, 255), 120, 120, 1024).unwrap();
+        assert_eq!(format, Format::Jpeg);
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (120, 60));
+
+        let (webp, format) = resize(&png(40, 20, 128), 120, 120, 1024).unwrap();
+        assert_eq!((format, format_of(&webp)), (Format::WebP, Format::WebP));
+        let image = image::load_from_memory(&webp).unwrap();
+        assert_eq!((image.width(), image.height()), (40, 20));
+    }
+
+    #[test]
+    fn rejects_huge_sources_before_decoding() {
+        assert!(resize(&png(4100, 1, 255), 120, 120, 1024).is_err());
+        assert!(resize(&png(4100, 1, 255), 120, 120, 2048).is_ok());
+    }
+
+    #[test]
+    fn rounds_sizes_up_to_buckets() {
+        assert_eq!(bucket(1, 1024), 64);
+        assert_eq!(bucket(64, 1024), 64);
+        assert_eq!(bucket(65, 1024), 120);
+        assert_eq!(bucket(500, 1024), 1024);
+        assert_eq!(bucket(300, 400), 400);
+    }
+
+    #[rocket::async_test]
+    async fn evicts_least_recently_used_images() {
+        let dir = std::env::temp_dir().join(format!("image_cache_{}", std::process::id()));
+        let config = ImageProxyConfig { cache_dir: dir.clone(), cache_bytes: 250, ..Default::default() };
+        let cache = ImageCache::new(config.clone());
+        cache.put("first", &[0; 100]).await;
+        cache.put("second", &[0; 100]).await;
+        assert!(cache.get("first").await.is_some());
+        cache.put("third", &[0; 100]).await;
+
+        assert!(cache.get("first").await.is_some());
+        assert!(cache.get("second").await.is_none());
+        assert!(cache.get("third").await.is_some());
+
+        // A new cache knows the files that are already there.
+        let cache = ImageCache::new(config);
+        assert!(cache.get("first").await.is_some());
+        assert_eq!(cache.index().await.total_bytes, 200);
+        // Evicted and temporary files are gone.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn shares_the_lock_of_a_key_while_it_loads() {
+        let cache = ImageCache::new(ImageProxyConfig::default());
+        let first = cache.loading("a").await;
+        assert!(Arc::ptr_eq(&first, &cache.loading("a").await));
+        assert!(!Arc::ptr_eq(&first, &cache.loading("b").await));
+
+        let guard = first.lock().await;
+        assert!(cache.loading("a").await.try_lock().is_none());
+        drop(guard);
+        drop(first);
+        cache.loading("c").await;
+        assert_eq!(cache.loading.lock().await.len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+use rocket::futures::lock::Mutex;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::broadcast;
+use rocket::tokio::{select, time::{sleep, Duration}};
+use rocket::Shutdown;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::metrics::PUBQ_CONNECTED;
+use crate::pubq_client::{ConnectionEvent, ConnectionState, PubqClient};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the PubQ connection is kept alive, from `[<profile>.pubq_keepalive]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    /// Seconds between pings while the connection is idle.
+    pub interval_secs: u64,
+    pub pong_timeout_secs: u64,
+    /// Upper bound of the wait between reconnect attempts.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig { interval_secs: 30, pong_timeout_secs: 10, max_backoff_secs: 300 }
+    }
+}
+
+/// The wait before reconnect attempt `attempt`, doubling from one second up to `max`.
+/// Half of it is random, so restarted servers do not reconnect in lockstep.
+fn backoff(attempt: u32, max: Duration, random: u64) -> Duration {
+    let ceiling = Duration::from_secs(1).saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(max);
+    ceiling / 2 + (ceiling / 2).mul_f64((random % 1000) as f64 / 1000.0)
+}
+
+fn random() -> u64 {
+    RandomState::new().hash_one(std::time::SystemTime::now())
+}
+
+/// Pings PubQ while connected and reconnects with backoff when the connection is lost, until shutdown.
+pub async fn run(client: Arc<Mutex<PubqClient>>, config: KeepaliveConfig, shutdown: Shutdown) {
+    let interval = Duration::from_secs(config.interval_secs);
+    let pong_timeout = Duration::from_secs(config.pong_timeout_secs);
+    let max_backoff = Duration::from_secs(config.max_backoff_secs);
+    let mut failures = 0;
+    loop {
+        let wait = if failures == 0 { interval } else { backoff(failures, max_backoff, random()) };
+        select! {
+            _ = shutdown.clone() => return,
+            _ = sleep(wait) => {},
+        }
+
+        // Running queries hold the client, and show the connection is alive anyway.
+        let result = {
+            let mut client = client.lock().await;
+            let result = if client.is_connected() {
+                client.ping(pong_timeout).await
+            } else {
+                client.connect(CONNECT_TIMEOUT).await
+            };
+            result.map_err(|er| er.to_string())
+        };
+        match result {
+            Ok(()) => failures = 0,
+            Err(er) => {
+                failures += 1;
+                warn!("PubQ keepalive failed {} times in a row: {}", failures, er);
+            }
+        }
+    }
+}
+
+/// Logs connection state changes and exports the state as a metric.
+pub async fn watch_events(mut events: broadcast::Receiver<ConnectionEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                info!("PubQ connection {:?}: {}", event.state, event.reason);
+                PUBQ_CONNECTED.set((event.state == ConnectionState::Connected) as i64);
+            },
+            Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Missed {} PubQ connection events", missed),
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max_with_jitter() {
+        let max = Duration::from_secs(60);
+        assert_eq!(backoff(1, max, 0), Duration::from_millis(500));
+        assert_eq!(backoff(1, max, 500), Duration::from_millis(750));
+        assert_eq!(backoff(4, max, 0), Duration::from_secs(4));
+        assert_eq!(backoff(30, max, 0), Duration::from_secs(30));
+        assert!(backoff(30, max, 999) <= max);
+    }
+}
@@ -1,37 +1,89 @@
-use rocket::State;
+use rocket::{Orbit, Rocket, State};
 use rocket::futures::lock::Mutex;
 use rocket::http::Status;
-use rocket::response::content::RawJson;
-use rocket::serde::{json::Json, Deserialize, Serialize};
 use rocket::tokio::time::{Instant, Duration};
+use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
 use std::collections::HashMap;
-// Tracing and logging
-use opentelemetry_otlp::{Protocol, WithExportConfig};
-use opentelemetry_appender_tracing::layer;
-use opentelemetry_sdk::logs::SdkLoggerProvider;
-use opentelemetry_sdk::Resource;
+use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Europe::Copenhagen;
 use tracing::{error, info, instrument};
-use tracing_subscriber:: {prelude::*, EnvFilter};
 
-use crate::pubq_client::PubqClient;
+use crate::http_cache::CachedJson;
+use crate::image_proxy::{ImageCache, ImageProxyConfig};
+use crate::menu_source::{MenuSource, PubqSource, SourceData, SourceRegistry, StaticSource, StaticSourceConfig};
+use crate::menu::{filter_menu, parse_locations, parse_menu, MenuFilter};
+use crate::keepalive::KeepaliveConfig;
+use crate::pubq_auth::StaticToken;
+use crate::pubq_client::{Fetched, PubqClient};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::security::{setup_cors, CorsConfig, SecurityHeaders};
+use crate::snapshot::CacheSnapshot;
+use crate::stock::StockTracker;
+use crate::tags::{TagRule, Tagger};
+use crate::timeslots::{get_item_timeslots, TimeSlotCache};
+use crate::vendor_order::{VendorOrder, VendorSettings};
+mod admin;
+mod blurhash;
+mod calendar;
+mod health;
+mod http_cache;
+mod image_proxy;
+mod keepalive;
+mod cart;
+mod data_hash;
+mod frame_decoder;
+mod menu;
+mod menu_source;
+mod metrics;
+mod poll;
+mod pubq_auth;
 mod pubq_client;
+mod random;
+mod rate_limit;
+mod search;
+mod security;
+mod snapshot;
+mod stock;
+mod tags;
+mod telemetry;
+mod timeslots;
+mod token;
+mod vendor_order;
 
 #[macro_use] extern crate rocket;
 
-struct VendorCache(Instant, String);
+/// How long vendors and menus are served from the cache.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// How long shutdown waits for running PubQ queries, and then for the connection to close.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When the vendor list was fetched or last found unchanged, its JSON, its hash, see `data_hash`, and when it last changed.
+struct VendorCache(Instant, String, String, DateTime<Utc>);
 
 #[get("/vendors")]
 #[instrument]
-async fn get_vendors(client : &State<Mutex<PubqClient>>, cache: &State<Mutex<Option<VendorCache>>>) -> Result<RawJson<String>, (rocket::http::Status, String)> {
-    {
+async fn get_vendors(sources: &State<Arc<SourceRegistry>>, order: &State<Mutex<VendorOrder>>) -> Result<CachedJson, (rocket::http::Status, String)> {
+    let vendors = sources.vendors(&*order.lock().await, Utc::now().with_timezone(&Copenhagen).ordinal()).await?;
+    Ok(CachedJson { body: vendors.value.to_string(), last_modified: vendors.changed_at, max_age: vendors.max_age })
+}
+
+/// Returns the vendor JSON, from the cache if it is fresh, otherwise from PubQ.
+async fn fetch_vendors(client : &Mutex<PubqClient>, cache: &Mutex<Option<VendorCache>>) -> Result<String, (rocket::http::Status, String)> {
+    let known_hash = {
         let cache = &cache.lock().await;
         if let Some(vendor_cache) = cache.as_ref() {
-            if vendor_cache.0.elapsed() < Duration::from_secs(300) {
-                return Ok(RawJson(vendor_cache.1.clone()));
+            if vendor_cache.0.elapsed() < CACHE_TTL {
+                metrics::cache_lookup("vendors", true);
+                return Ok(vendor_cache.1.clone());
             }
         }
-    }
+        cache.as_ref().map(|vendor_cache| vendor_cache.2.clone()).unwrap_or_default()
+    };
+    metrics::cache_lookup("vendors", false);
 
     info!("Fetching vendors from PubQ");
     let mut client = client.lock().await;
@@ -41,7 +93,7 @@ async fn get_vendors(client : &State<Mutex<PubqClient>>, cache: &State<Mutex<Opt
     let mut attempts = 0;
     let vendors = loop {
         attempts += 1;
-        match client.get_vendors(Duration::from_secs(5)).await.map_err(|e| format!("Get vendors failed {:?}", e)) {
+        match client.get_vendors(&known_hash, Duration::from_secs(5)).await.map_err(|e| format!("Get vendors failed {:?}", e)) {
             Ok(v) => break v,
             Err(e) if attempts >= 3 => { 
                 error!("Get vendors failed after {} attempts: {:?}", attempts, e);
@@ -54,33 +106,68 @@ async fn get_vendors(client : &State<Mutex<PubqClient>>, cache: &State<Mutex<Opt
         }
     };
 
+    let (vendors, hash) = match vendors {
+        Fetched::Changed { data, hash } => (data, hash),
+        Fetched::Unchanged => {
+            let mut cache = cache.lock().await;
+            if let Some(vendor_cache) = cache.as_mut() {
+                metrics::cache_revalidation("vendors", true);
+                vendor_cache.0 = Instant::now();
+                return Ok(vendor_cache.1.clone());
+            }
+            return Err((Status::InternalServerError, "Vendors unchanged, but no longer cached".to_string()));
+        },
+    };
+    if !known_hash.is_empty() {
+        metrics::cache_revalidation("vendors", false);
+    }
+
+    if let Ok(locations) = parse_locations(&vendors) {
+        metrics::KNOWN_VENDORS.set(menu::vendors(&locations).len() as i64);
+    }
+
     let vendors_json = serde_json::to_string(&vendors)
         .map_err(|er| { 
             error!("Failed to serialize vendors: {:?}", er);
             (Status::InternalServerError, format!("Serialization failed {:?}", er))
     })?;
     let cache = &mut cache.lock().await;
-    **cache = Some(VendorCache(Instant::now(), vendors_json.clone()));
-    Ok(RawJson(vendors_json))
+    **cache = Some(VendorCache(Instant::now(), vendors_json.clone(), hash, Utc::now()));
+    Ok(vendors_json)
 }
 
-struct VenderMenuCache(HashMap<String, (Instant, serde_json::Value)>);
+/// Per vendor, when the menu was fetched or last found unchanged, the menu, its hash, see `data_hash`, and when it last changed.
+struct VenderMenuCache(HashMap<String, (Instant, serde_json::Value, String, DateTime<Utc>)>);
 
-#[get("/menu/<vendor_id>")]
+#[get("/menu/<vendor_id>?<filter..>")]
 #[instrument]
-async fn get_menu(vendor_id: &str, client : &State<Mutex<PubqClient>>, vendor_cache : &State<Mutex<VenderMenuCache>>) -> Result<RawJson<String>, (rocket::http::Status, String)> {
+async fn get_menu(vendor_id: &str, filter: MenuFilter, sources : &State<Arc<SourceRegistry>>, tagger : &State<Mutex<Tagger>>, stock : &State<Arc<Mutex<StockTracker>>>) -> Result<CachedJson, (rocket::http::Status, String)> {
+    let SourceData { value: mut menu, changed_at, max_age } = sources.menu(vendor_id).await?;
+    tagger.lock().await.annotate(&mut menu);
+    // Stock is sampled in the background, see `stock::run`.
+    stock.lock().await.annotate(&mut menu);
+    if !filter.is_empty() {
+        filter_menu(&mut menu, &filter);
+    }
+    let menu_json = serde_json::to_string(&menu)
+        .map_err(|er| {
+            error!("Failed to serialize menu: {:?}", er);
+            (Status::InternalServerError, format!("Serialization failed {:?}", er))
+        })?;
+    Ok(CachedJson { body: menu_json, last_modified: changed_at, max_age })
+}
+
+/// Returns the menu of a vendor, from the cache if it is fresh, otherwise from PubQ.
+async fn fetch_menu(vendor_id: &str, client : &Mutex<PubqClient>, vendor_cache : &Mutex<VenderMenuCache>) -> Result<serde_json::Value, (rocket::http::Status, String)> {
     let cache = &mut vendor_cache.lock().await.0;
-    if let Some((timestamp, cached_menu)) = cache.get(vendor_id) {
-
-        if timestamp.elapsed() < Duration::from_secs(300) {
-            let menu_json = serde_json::to_string(cached_menu)
-                .map_err(|er| {
-                    error!("Failed to serialize cached menu: {:?}", er);
-                    (rocket::http::Status::InternalServerError, format!("Serialization failed {:?}", er))
-                })?;
-            return Ok(RawJson(menu_json));
+    if let Some((timestamp, cached_menu, _, _)) = cache.get(vendor_id) {
+        if timestamp.elapsed() < CACHE_TTL {
+            metrics::cache_lookup("menus", true);
+            return Ok(cached_menu.clone());
         }
     }
+    metrics::cache_lookup("menus", false);
+    let known_hash = cache.get(vendor_id).map(|(_, _, hash, _)| hash.clone()).unwrap_or_default();
 
     info!("Fetching menu for vendor {} from PubQ", vendor_id);
     let mut client = client.lock().await;
@@ -90,7 +177,7 @@ async fn get_menu(vendor_id: &str, client : &State<Mutex<PubqClient>>, vendor_ca
     let mut attempts = 0;
     let menu = loop {
         attempts += 1;
-        match client.get_vender_menu(vendor_id, Duration::from_secs(5)).await
+        match client.get_vender_menu(vendor_id, &known_hash, Duration::from_secs(5)).await
             .map_err(|er| format!("Get menu failed {:?}", er)) {
             Ok(menu) => break menu,
             Err(e) if attempts < 3 => {
@@ -105,141 +192,151 @@ async fn get_menu(vendor_id: &str, client : &State<Mutex<PubqClient>>, vendor_ca
         }    
     };
 
-    cache.insert(vendor_id.to_string(), (Instant::now(), menu.clone()));
-
-    let menu_json = serde_json::to_string(&menu)
-        .map_err(|er| {
-            error!("Failed to serialize menu: {:?}", er);
-            (Status::InternalServerError, format!("Serialization failed {:?}", er))
-        })?;
-    Ok(RawJson(menu_json))
-}
-
-#[derive(Deserialize, Serialize)]
-struct TimeslotRequest {
-    #[serde(rename = "routeName")]
-    route_name: String,
-    products: Vec<TimeslotProduct>,
-}
+    let (menu, hash) = match menu {
+        Fetched::Changed { data, hash } => (data, hash),
+        Fetched::Unchanged => {
+            let Some((timestamp, cached_menu, _, _)) = cache.get_mut(vendor_id) else {
+                return Err((Status::InternalServerError, format!("Menu of {} unchanged, but no longer cached", vendor_id)));
+            };
+            metrics::cache_revalidation("menus", true);
+            *timestamp = Instant::now();
+            return Ok(cached_menu.clone());
+        },
+    };
+    if !known_hash.is_empty() {
+        metrics::cache_revalidation("menus", false);
+    }
 
-#[derive(Deserialize, Serialize)]
-struct TimeslotProduct {
-    #[serde(rename = "bongCategoryId")]
-    bong_category_id: i32,
-    #[serde(rename = "productId")]
-    product_id: String,
-    #[serde(rename = "productName")]
-    product_name: String,
-    quantity: u32,
+    if let Ok(categories) = parse_menu(&menu) {
+        metrics::KNOWN_ITEMS.with_label_values(&[vendor_id]).set(categories.iter().map(|category| category.items.len() as i64).sum());
+    }
+    cache.insert(vendor_id.to_string(), (Instant::now(), menu.clone(), hash, Utc::now()));
+    Ok(menu)
 }
 
-struct TimeSlotCache(HashMap<String, (Instant, String)>);
-
-#[post("/timeslots", data = "<body>")]
-#[instrument(skip(body))]
-async fn get_item_timeslots(body : Json<TimeslotRequest>, timeslot_cache : &State<Mutex<TimeSlotCache>>) -> Result<RawJson<String>, (rocket::http::Status, String)> {
-    let cache_key = format!(
-        "{}-{}",
-        body.route_name,
-        &body.products.iter()
-            .map(|p| p.product_id.clone())
-            .collect::<Vec<String>>().join("|"));
-
-    {
-        let cache = &mut timeslot_cache.lock().await.0;
-            
-        if let Some((timestamp, cached_timeslots)) = cache.get(&cache_key) {
-            if timestamp.elapsed() < Duration::from_secs(300) {
-                return Ok(RawJson(cached_timeslots.clone()));
-            }
+/// Closes the PubQ connection once running queries are done, saves the caches and flushes telemetry.
+async fn shutdown(rocket: &Rocket<Orbit>, cache_snapshot_path: Option<PathBuf>) {
+    info!("Shutting down");
+    if let Some(client) = rocket.state::<Arc<Mutex<PubqClient>>>() {
+        // Queries hold the client while they run, so getting it means they are done.
+        match rocket::tokio::time::timeout(SHUTDOWN_TIMEOUT, client.lock()).await {
+            Ok(mut client) => client.shutdown(SHUTDOWN_TIMEOUT).await,
+            Err(_) => warn!("PubQ queries still running after {:?}, not closing the connection", SHUTDOWN_TIMEOUT),
         }
     }
-    
-    info!("Fetching timeslots for key {} from external service", cache_key);
-    let request = body.into_inner();
-    let json = serde_json::to_string(&request)
-        .map_err(|er| (Status::InternalServerError, format!("Serialization failed {:?}", er)))?;
-    let response = reqwest::Client::new()
-        .post("https://payments2-jaonrqeeaq-ew.a.run.app/v1/orders/timeslots")
-        .header("Content-Type", "application/json")
-        .body(json)
-        .send()
-        .await
-        .map_err(|er| (Status::InternalServerError, format!("HTTP request failed {:?}", er)))?;    
-    let timeslots_json = response.text().await
-        .map_err(|er| (Status::InternalServerError, format!("Deserializing response failed {:?}", er)))?;
-    let cache = &mut timeslot_cache.lock().await.0;
-
-    cache.insert(cache_key, (Instant::now(), timeslots_json.clone()));
-    Ok(RawJson(timeslots_json))
-}
-
-#[get("/health")]
-#[instrument]
-fn health() -> &'static str {
-    "OK"
-}
 
-fn setup_cors() -> rocket_cors::Cors {
-    let allowed_origins = rocket_cors::AllowedOrigins::all();
-    let cors = rocket_cors::CorsOptions {
-        allowed_origins,
-        allow_credentials: true,
-        ..Default::default()
-    }.to_cors().expect("Error creating CORS fairing");
-    cors
-}
+    if let (Some(path), Some(vendor_cache), Some(menu_cache)) = (cache_snapshot_path, rocket.state::<Arc<Mutex<Option<VendorCache>>>>(), rocket.state::<Arc<Mutex<VenderMenuCache>>>()) {
+        let snapshot = rocket::tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            CacheSnapshot::take(&*vendor_cache.lock().await, &*menu_cache.lock().await)
+        }).await;
+        match snapshot {
+            Ok(snapshot) => snapshot.save(&path).await,
+            Err(_) => warn!("Caches still in use after {:?}, not saving them", SHUTDOWN_TIMEOUT),
+        }
+    }
 
-fn setup_telemetry(otel_endpoint: &str) {
-    let otel_exporter = opentelemetry_otlp::LogExporter::builder()
-        .with_http()
-        .with_protocol(Protocol::HttpBinary)
-        .with_endpoint(otel_endpoint)
-        .build()
-        .expect("Failed to create OTLP Log Exporter");
-
-    let provider : SdkLoggerProvider = SdkLoggerProvider::builder()
-        .with_resource(
-            Resource::builder()
-                .with_service_name("market-food-dashboard-backend")
-                .build(),
-        )
-        .with_batch_exporter(otel_exporter)
-        .build();
-
-    // TODO: Remove logs fomr select components
-    let filter = EnvFilter::new("info")
-        .add_directive("rocket=warn".parse().unwrap());
-    let otel_layer  = layer::OpenTelemetryTracingBridge::new(&provider)
-        .with_filter(filter);
-
-    // This causes output to the console
-    let filter_fmt = EnvFilter::new("info")
-        .add_directive("opentelemetry=info".parse().unwrap())
-        .add_directive("rocket=warn".parse().unwrap());
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_thread_names(true)
-        .with_filter(filter_fmt);
-
-    tracing_subscriber::registry()
-        .with(otel_layer)
-        .with(fmt_layer)
-        .init();
+    if let Some(Some(telemetry)) = rocket.state::<Option<telemetry::Telemetry>>() {
+        let telemetry = telemetry.clone();
+        // Flushing blocks until the collector answers.
+        if let Err(er) = rocket::tokio::task::spawn_blocking(move || telemetry.shutdown()).await {
+            error!("Failed to flush telemetry: {:?}", er);
+        }
+    }
 }
 
 #[launch]
 fn rocket() -> _ {
     let figment = rocket::Config::figment();
-    let otel_endpoint: String = figment.extract_inner("otel_endpoint").expect("Missing 'otel_endpoint' configuration in Rocket.toml");
-    setup_telemetry(&otel_endpoint);
+    let otel_endpoint: Option<String> = figment.extract_inner("otel_endpoint").ok();
+    let telemetry = telemetry::setup_telemetry(otel_endpoint.as_deref());
+    
+    let tag_rules: HashMap<String, TagRule> = figment.extract_inner("tag_rules").unwrap_or_default();
+    let tag_overrides_path: Option<PathBuf> = figment.extract_inner("tag_overrides_path").ok();
+    let stock_history_path: Option<PathBuf> = figment.extract_inner("stock_history_path").ok();
+    let vendor_settings: HashMap<String, VendorSettings> = figment.extract_inner("vendor_settings").unwrap_or_default();
+    let vendor_settings_path: Option<PathBuf> = figment.extract_inner("vendor_settings_path").ok();
+    let rate_limits: HashMap<String, RateLimit> = figment.extract_inner("rate_limits").unwrap_or_default();
+    let rate_limit_ip_header: Option<String> = figment.extract_inner("rate_limit_ip_header").ok();
+    let cors_config: CorsConfig = figment.extract_inner("cors").unwrap_or_default();
+    let security_headers: SecurityHeaders = figment.extract_inner("security_headers").unwrap_or_default();
+    let pubq_url: Option<String> = figment.extract_inner("pubq_url").ok();
+    let pubq_auth: Option<StaticToken> = figment.extract_inner("pubq_auth").ok();
+    let pubq_max_message_bytes: Option<usize> = figment.extract_inner("pubq_max_message_bytes").ok();
+    let keepalive_config: KeepaliveConfig = figment.extract_inner("pubq_keepalive").unwrap_or_default();
+    let cache_snapshot_path: Option<PathBuf> = figment.extract_inner("cache_snapshot_path").ok();
+    let image_proxy_config: ImageProxyConfig = figment.extract_inner("image_proxy").unwrap_or_default();
+    let admin_token: Option<String> = figment.extract_inner("admin_token").ok();
+    let menu_sources: HashMap<String, StaticSourceConfig> = figment.extract_inner("menu_sources").unwrap_or_default();
+    let (vendor_cache, menu_cache) = cache_snapshot_path.as_deref().map(|path| CacheSnapshot::load(path).restore())
+        .unwrap_or_else(|| (None, VenderMenuCache(HashMap::new())));
     
-    let cors = setup_cors();
+    let mut pubq_client = PubqClient::new();
+    if let Some(pubq_url) = &pubq_url {
+        pubq_client = pubq_client.with_socket_url(pubq_url);
+    }
+    if let Some(pubq_max_message_bytes) = pubq_max_message_bytes {
+        pubq_client = pubq_client.with_max_message_size(pubq_max_message_bytes);
+    }
+    if let Some(pubq_auth) = pubq_auth {
+        pubq_client = pubq_client.with_auth(Arc::new(pubq_auth));
+    }
+    
+    let pubq_client = Arc::new(Mutex::new(pubq_client));
+    let vendor_cache = Arc::new(Mutex::new(vendor_cache));
+    let menu_cache = Arc::new(Mutex::new(menu_cache));
+    let timeslot_cache = Arc::new(Mutex::new(TimeSlotCache::default()));
+    let mut sources: Vec<Arc<dyn MenuSource>> = vec![Arc::new(PubqSource {
+        client: pubq_client.clone(),
+        vendor_cache: vendor_cache.clone(),
+        menu_cache: menu_cache.clone(),
+        timeslot_cache: timeslot_cache.clone(),
+    })];
+    let mut menu_sources: Vec<(String, StaticSourceConfig)> = menu_sources.into_iter().collect();
+    menu_sources.sort_by(|a, b| a.0.cmp(&b.0));
+    sources.extend(menu_sources.into_iter().map(|(name, config)| Arc::new(StaticSource::new(&name, config)) as Arc<dyn MenuSource>));
+
+    metrics::init();
+    let request_metrics = metrics::RequestMetrics::new(telemetry.as_ref().map(|telemetry| telemetry.meter()));
+    let cors = setup_cors(&cors_config);
     rocket::build()
-        .mount("/api", routes![get_vendors, get_menu, get_item_timeslots, health])
+        .mount("/api", telemetry::traced(routes![get_vendors, get_menu, get_item_timeslots, health::health, health::live, health::ready, calendar::get_calendar, search::search,
+            tags::get_overrides, tags::put_override, tags::delete_override,
+            poll::create_poll, poll::get_poll, poll::vote, poll::get_result,
+            cart::create_cart, cart::get_cart, cart::update_cart_item, cart::get_cart_timeslots, cart::get_cart_summary, random::random_dish,
+            vendor_order::get_settings, vendor_order::put_settings, vendor_order::delete_settings, rate_limit::rate_limited,
+            image_proxy::get_image, blurhash::get_placeholder, admin::get_cache, admin::delete_cache, admin::refresh, admin::get_pubq, admin::reconnect]))
+        .mount("/", routes![metrics::metrics])
         .mount("/", FileServer::from("../front-end"))
-        .manage(Mutex::new(PubqClient::new()))
-        .manage(Mutex::new(VenderMenuCache(HashMap::new())))
-        .manage(Mutex::new(TimeSlotCache(HashMap::new())))
-        .manage(Mutex::new(Option::None::<VendorCache>))
-        .attach(cors)        
+        .manage(telemetry)
+        .manage(pubq_client)
+        .manage(menu_cache)
+        .manage(timeslot_cache)
+        .manage(vendor_cache)
+        .manage(Arc::new(SourceRegistry::new(sources)))
+        .manage(admin::AdminConfig { token: admin_token })
+        .manage(ImageCache::new(image_proxy_config))
+        .manage(Mutex::new(Tagger::new(tag_rules, tag_overrides_path)))
+        .manage(Arc::new(Mutex::new(StockTracker::new(stock_history_path))))
+        .manage(Mutex::new(poll::PollStore::default()))
+        .manage(Mutex::new(cart::CartStore::default()))
+        .manage(Mutex::new(random::DishPicker::default()))
+        .manage(Mutex::new(VendorOrder::new(vendor_settings, vendor_settings_path)))
+        .attach(cors)
+        .attach(security_headers)
+        .attach(request_metrics)
+        .attach(RateLimiter::new(rate_limits, rate_limit_ip_header))
+        .attach(AdHoc::on_liftoff("Connect to PubQ", move |rocket| Box::pin(async move {
+            let (Some(client), Some(cache)) = (rocket.state::<Arc<Mutex<PubqClient>>>(), rocket.state::<Arc<Mutex<Option<VendorCache>>>>()) else { return };
+            rocket::tokio::spawn(keepalive::watch_events(client.lock().await.subscribe()));
+            // Readiness waits for the first vendor list, so load it right away.
+            if let Err((_, er)) = fetch_vendors(client, cache).await {
+                error!("Failed to load vendors at startup: {}", er);
+            }
+            rocket::tokio::spawn(keepalive::run(client.clone(), keepalive_config, rocket.shutdown()));
+        })))
+        .attach(AdHoc::on_liftoff("Sample stock", |rocket| Box::pin(async move {
+            let (Some(sources), Some(tracker)) = (rocket.state::<Arc<SourceRegistry>>(), rocket.state::<Arc<Mutex<StockTracker>>>()) else { return };
+            rocket::tokio::spawn(stock::run(sources.clone(), tracker.clone(), rocket.shutdown()));
+        })))
+        .attach(AdHoc::on_shutdown("Shutdown", move |rocket| Box::pin(shutdown(rocket, cache_snapshot_path.clone()))))        
 }
\ No newline at end of file
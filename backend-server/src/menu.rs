@@ -0,0 +1,374 @@
+use rocket::serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A location from `/clientUnits/<site>/all`. Food courts list their vendors in `children`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Location {
+    pub name: String,
+    #[serde(rename = "routeName")]
+    pub route_name: String,
+    pub address: String,
+    #[serde(rename = "imageUrl")]
+    pub image_url: String,
+    #[serde(rename = "blurHash")]
+    pub blur_hash: Option<String>,
+    pub enabled: Option<bool>,
+    pub visible: Option<bool>,
+    #[serde(deserialize_with = "firebase_list")]
+    pub children: Vec<Location>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MenuCategory {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub category_type: String,
+    #[serde(deserialize_with = "firebase_list")]
+    pub items: Vec<MenuItem>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MenuItem {
+    pub key: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "NameInternal")]
+    pub name_internal: String,
+    #[serde(rename = "Description")]
+    pub description: String,
+    #[serde(rename = "DescriptionLong")]
+    pub description_long: String,
+    /// Price in øre.
+    #[serde(rename = "Cost", deserialize_with = "lenient_number")]
+    pub cost: i64,
+    #[serde(rename = "ImageUrl")]
+    pub image_url: String,
+    #[serde(rename = "blurHash")]
+    pub blur_hash: Option<String>,
+    #[serde(rename = "bongCategoryLabel")]
+    pub bong_category_label: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(rename = "type")]
+    pub item_type: ItemType,
+    #[serde(rename = "restrictedItem")]
+    pub restricted_item: bool,
+    /// Why the item is disabled, e.g. when the canteen has closed. Empty for items that sold out.
+    #[serde(rename = "disabledReason")]
+    pub disabled_reason: String,
+    #[serde(rename = "useStockBalance")]
+    pub use_stock_balance: bool,
+    #[serde(rename = "stockBalance", deserialize_with = "lenient_number")]
+    pub stock_balance: i64,
+    /// Whether the vendor shows the stock balance to customers.
+    #[serde(rename = "showStockBalance")]
+    pub show_stock_balance: bool,
+    /// Which channels (`web`, `app`, `kiosk`, ...) the item is shown in.
+    #[serde(rename = "displayConfig")]
+    pub display_config: HashMap<String, bool>,
+    /// Dietary and allergen tags, added by the `Tagger`.
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ItemType {
+    #[serde(rename = "bongCategoryType")]
+    pub bong_category_type: CategoryType,
+    #[serde(rename = "productCategoryType")]
+    pub product_category_type: CategoryType,
+    #[serde(rename = "containsAlcohol")]
+    pub contains_alcohol: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CategoryType {
+    pub id: String,
+    /// E.g. `Mad` or `Drikke`.
+    pub label: String,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Web,
+    App,
+    Kiosk,
+}
+
+impl Channel {
+    fn key(&self) -> &'static str {
+        match self {
+            Channel::Web => "web",
+            Channel::App => "app",
+            Channel::Kiosk => "kiosk",
+        }
+    }
+}
+
+/// Query parameters for narrowing down a menu.
+#[derive(FromForm, Debug, Default)]
+pub struct MenuFilter {
+    /// Highest price in DKK.
+    pub max_price: Option<f64>,
+    /// Matched against the bong and product category labels, e.g. `Mad` or `Drikke`.
+    pub category: Option<String>,
+    /// Also excludes age restricted items.
+    pub exclude_alcohol: bool,
+    /// Only enabled items that are in stock.
+    pub only_available: bool,
+    pub channel: Option<Channel>,
+    /// Tags the item must have, e.g. `vegetarian`.
+    pub tags: Vec<String>,
+    /// Tags the item must not have, e.g. `contains-nuts`.
+    pub exclude_tags: Vec<String>,
+}
+
+impl MenuFilter {
+    pub fn is_empty(&self) -> bool {
+        self.max_price.is_none()
+            && self.category.is_none()
+            && !self.exclude_alcohol
+            && !self.only_available
+            && self.channel.is_none()
+            && self.tags.is_empty()
+            && self.exclude_tags.is_empty()
+    }
+
+    pub fn matches(&self, item: &MenuItem) -> bool {
+        if self.max_price.is_some_and(|max_price| item.cost as f64 > max_price * 100.0) {
+            return false;
+        }
+        if let Some(category) = &self.category {
+            let labels = [&item.item_type.bong_category_type.label, &item.item_type.product_category_type.label];
+            if !labels.iter().any(|label| label.eq_ignore_ascii_case(category)) {
+                return false;
+            }
+        }
+        if self.exclude_alcohol && (item.item_type.contains_alcohol || item.restricted_item) {
+            return false;
+        }
+        if self.only_available && !item.is_available() {
+            return false;
+        }
+        if let Some(channel) = self.channel {
+            if item.display_config.get(channel.key()) == Some(&false) {
+                return false;
+            }
+        }
+        if !self.tags.iter().all(|tag| item.tags.contains(tag)) {
+            return false;
+        }
+        if self.exclude_tags.iter().any(|tag| item.tags.contains(tag)) {
+            return false;
+        }
+        true
+    }
+}
+
+impl MenuItem {
+    /// An item can be ordered when it is enabled and, if stock is counted, not out of stock.
+    pub fn is_available(&self) -> bool {
+        self.enabled && (!self.use_stock_balance || self.stock_balance > 0)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Locations(#[serde(deserialize_with = "firebase_list")] Vec<Location>);
+
+#[derive(Deserialize)]
+struct Menu(#[serde(deserialize_with = "firebase_list")] Vec<MenuCategory>);
+
+/// Parses the payload of a `clientUnits` query.
+pub fn parse_locations(value: &Value) -> Result<Vec<Location>, serde_json::Error> {
+    Locations::deserialize(value).map(|locations| locations.0)
+}
+
+/// Parses the payload of an `activeMenu/categories` query.
+pub fn parse_menu(value: &Value) -> Result<Vec<MenuCategory>, serde_json::Error> {
+    Menu::deserialize(value).map(|menu| menu.0)
+}
+
+/// Flattens locations into the vendors that have their own menu, the same way the front-end does.
+pub fn vendors(locations: &[Location]) -> Vec<&Location> {
+    locations.iter()
+        .flat_map(|location| {
+            if location.children.is_empty() {
+                vec![location]
+            } else {
+                location.children.iter().collect()
+            }
+        })
+        .filter(|vendor| !vendor.route_name.is_empty())
+        .collect()
+}
+
+/// All items of a raw menu.
+pub fn items_mut(menu: &mut Value) -> Vec<&mut Value> {
+    let categories: Vec<&mut Value> = match menu {
+        Value::Array(categories) => categories.iter_mut().collect(),
+        Value::Object(categories) => categories.values_mut().collect(),
+        _ => return Vec::new(),
+    };
+    categories.into_iter()
+        .flat_map(|category| match category.get_mut("items") {
+            Some(Value::Array(items)) => items.iter_mut().filter(|item| !item.is_null()).collect(),
+            Some(Value::Object(items)) => items.values_mut().collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Removes the items not matching the filter from a raw menu, keeping the Firebase structure intact.
+pub fn filter_menu(menu: &mut Value, filter: &MenuFilter) {
+    let keep = |item: &Value| MenuItem::deserialize(item).is_ok_and(|item| filter.matches(&item));
+    let categories: Vec<&mut Value> = match menu {
+        Value::Array(categories) => categories.iter_mut().collect(),
+        Value::Object(categories) => categories.values_mut().collect(),
+        _ => return,
+    };
+    for category in categories {
+        match category.get_mut("items") {
+            Some(Value::Array(items)) => items.retain(|item| item.is_null() || keep(item)),
+            Some(Value::Object(items)) => items.retain(|_, item| keep(item)),
+            _ => {},
+        }
+    }
+}
+
+pub fn find_item<'a>(menu: &'a [MenuCategory], key: &str) -> Option<&'a MenuItem> {
+    menu.iter()
+        .flat_map(|category| category.items.iter())
+        .find(|item| item.key == key)
+}
+
+/// Accepts numbers that are sent as strings, falling back to zero for anything else.
+fn lenient_number<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(number) => number.as_i64().or_else(|| number.as_f64().map(|n| n as i64)).unwrap_or(0),
+        Value::String(text) => text.trim().parse().unwrap_or(0),
+        _ => 0,
+    })
+}
+
+/// Firebase returns lists either as arrays (possibly with `null` holes) or as objects keyed by index.
+fn firebase_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum FirebaseList<T> {
+        Array(Vec<Option<T>>),
+        Object(HashMap<String, T>),
+    }
+
+    Ok(match Option::<FirebaseList<T>>::deserialize(deserializer)? {
+        Some(FirebaseList::Array(values)) => values.into_iter().flatten().collect(),
+        Some(FirebaseList::Object(values)) => {
+            let mut values: Vec<(String, T)> = values.into_iter().collect();
+            values.sort_by_key(|(key, _)| (key.parse::<u64>().unwrap_or(u64::MAX), key.clone()));
+            values.into_iter().map(|(_, value)| value).collect()
+        },
+        None => Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn can_parse_indexed_object_lists_in_order() {
+        let raw = serde_json::json!({
+            "10": {"name": "Last", "items": {}},
+            "2": {"name": "Second", "items": [null, {"key": "b", "Name": "Bread", "Cost": "2500"}]},
+            "0": {"name": "First"}
+        });
+        let menu = parse_menu(&raw).unwrap();
+        assert_eq!(menu.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["First", "Second", "Last"]);
+        assert_eq!(menu[1].items.len(), 1);
+        assert_eq!(menu[1].items[0].cost, 2500);
+        assert!(menu[1].items[0].enabled);
+        assert_eq!(find_item(&menu, "b").map(|item| item.name.as_str()), Some("Bread"));
+    }
+
+    fn item(raw: Value) -> MenuItem {
+        MenuItem::deserialize(&raw).unwrap()
+    }
+
+    #[test]
+    fn can_parse_item_type_and_display_config() {
+        let item = item(serde_json::json!({
+            "Cost": 3500, "enabled": true, "restrictedItem": false, "useStockBalance": true, "stockBalance": 4,
+            "displayConfig": {"-1": true, "app": true, "kiosk": false, "web": true},
+            "type": {"bongCategoryType": {"id": "4", "label": "Mad"}, "containsAlcohol": false, "productCategoryType": {"id": "2", "label": "Mad"}, "productTypeId": 1}
+        }));
+        assert_eq!(item.item_type.bong_category_type.label, "Mad");
+        assert_eq!(item.stock_balance, 4);
+        assert_eq!(item.display_config.get("kiosk"), Some(&false));
+    }
+
+    #[test]
+    fn filters_by_price_category_alcohol_availability_and_channel() {
+        let food = item(serde_json::json!({"Cost": 6500, "type": {"bongCategoryType": {"label": "Mad"}}, "displayConfig": {"web": true, "kiosk": false}}));
+        let beer = item(serde_json::json!({"Cost": 4500, "type": {"bongCategoryType": {"label": "Drikke"}, "containsAlcohol": true}}));
+        let sold_out = item(serde_json::json!({"Cost": 2500, "useStockBalance": true, "stockBalance": 0}));
+
+        let filter = MenuFilter { max_price: Some(50.0), ..Default::default() };
+        assert!(!filter.matches(&food) && filter.matches(&beer));
+        let filter = MenuFilter { category: Some("mad".to_string()), ..Default::default() };
+        assert!(filter.matches(&food) && !filter.matches(&beer));
+        let filter = MenuFilter { exclude_alcohol: true, ..Default::default() };
+        assert!(filter.matches(&food) && !filter.matches(&beer));
+        let filter = MenuFilter { only_available: true, ..Default::default() };
+        assert!(filter.matches(&food) && !filter.matches(&sold_out));
+        let filter = MenuFilter { channel: Some(Channel::Kiosk), ..Default::default() };
+        assert!(!filter.matches(&food) && filter.matches(&beer));
+        let vegan = MenuItem { tags: vec!["vegan".to_string(), "contains-nuts".to_string()], ..Default::default() };
+        let filter = MenuFilter { tags: vec!["vegan".to_string()], ..Default::default() };
+        assert!(filter.matches(&vegan) && !filter.matches(&food));
+        let filter = MenuFilter { exclude_tags: vec!["contains-nuts".to_string()], ..Default::default() };
+        assert!(!filter.matches(&vegan) && filter.matches(&food));
+        assert!(MenuFilter::default().is_empty());
+    }
+
+    #[test]
+    fn filter_keeps_raw_menu_structure() {
+        let mut menu = serde_json::json!({"0": {"name": "Food", "items": {
+            "0": {"key": "a", "Name": "Soup", "Cost": 4000, "ExtraField": 1},
+            "1": {"key": "b", "Name": "Steak", "Cost": 14000}
+        }}});
+        filter_menu(&mut menu, &MenuFilter { max_price: Some(100.0), ..Default::default() });
+        assert_eq!(menu, serde_json::json!({"0": {"name": "Food", "items": {
+            "0": {"key": "a", "Name": "Soup", "Cost": 4000, "ExtraField": 1}
+        }}}));
+    }
+
+    #[test]
+    fn flattens_locations_into_vendors() {
+        let raw = serde_json::json!({
+            "0": {"name": "The Market", "routeName": "compassdk_centralcafe", "children": {
+                "0": {"name": "Dhaba", "routeName": "compassdk_dbvendor1", "visible": true},
+                "1": {"name": "Grød", "routeName": "compassdk_dbvendor3"}
+            }},
+            "1": {"name": "The Salad Lab", "routeName": "compassdk_dbpopup", "location": {"latitude": "0", "longitude": "0"}}
+        });
+        let locations = parse_locations(&raw).unwrap();
+        let routes = vendors(&locations).iter().map(|v| v.route_name.as_str()).collect::<Vec<_>>();
+        assert_eq!(routes, vec!["compassdk_dbvendor1", "compassdk_dbvendor3", "compassdk_dbpopup"]);
+    }
+}
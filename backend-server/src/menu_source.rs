@@ -0,0 +1,488 @@
+use chrono::{DateTime, Utc};
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::image_proxy::collect_image_urls;
+use crate::menu::{self, parse_locations, parse_menu, Location};
+use crate::pubq_client::{PubqClient, SITE};
+use crate::search::{SearchHit, SearchIndex};
+use crate::timeslots::{fetch_timeslots, validate_request, TimeSlotCache, TimeslotRequest};
+use crate::vendor_order::VendorOrder;
+use crate::{fetch_menu, fetch_vendors, VendorCache, VenderMenuCache, CACHE_TTL};
+
+/// Data from a `MenuSource`, with when it last changed and how long the source keeps serving it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceData {
+    pub value: Value,
+    pub changed_at: DateTime<Utc>,
+    pub max_age: Duration,
+}
+
+impl SourceData {
+    /// Data from a cache entry fetched or revalidated at `fetched`, which is served until it is `CACHE_TTL` old.
+    fn cached(value: Value, entry: Option<(rocket::tokio::time::Instant, DateTime<Utc>)>) -> Self {
+        match entry {
+            Some((fetched, changed_at)) => SourceData { value, changed_at, max_age: CACHE_TTL.saturating_sub(fetched.elapsed()) },
+            // Invalidated right after it was fetched.
+            None => SourceData { value, changed_at: Utc::now(), max_age: Duration::ZERO },
+        }
+    }
+}
+
+/// Where vendors and their menus come from. Menus are in the format of PubQ `activeMenu/categories`, see `menu::parse_menu`.
+#[rocket::async_trait]
+pub trait MenuSource: Send + Sync {
+    /// Shown as `source` on the vendors of this source.
+    fn name(&self) -> &str;
+    async fn sites(&self) -> Result<Vec<String>, (Status, String)>;
+    /// The locations of a site, in the format of PubQ `clientUnits/<site>/all`, see `menu::parse_locations`.
+    async fn vendors(&self, site: &str) -> Result<SourceData, (Status, String)>;
+    async fn menu(&self, vendor_id: &str) -> Result<SourceData, (Status, String)>;
+    /// The raw timeslot JSON for a request, see `timeslots::parse_enabled_slots`.
+    async fn timeslots(&self, request: &TimeslotRequest) -> Result<String, (Status, String)>;
+}
+
+/// Vendors and menus from PubQ, with timeslots from the payments service.
+pub struct PubqSource {
+    pub client: Arc<Mutex<PubqClient>>,
+    pub vendor_cache: Arc<Mutex<Option<VendorCache>>>,
+    pub menu_cache: Arc<Mutex<VenderMenuCache>>,
+    pub timeslot_cache: Arc<Mutex<TimeSlotCache>>,
+}
+
+#[rocket::async_trait]
+impl MenuSource for PubqSource {
+    fn name(&self) -> &str {
+        "pubq"
+    }
+
+    async fn sites(&self) -> Result<Vec<String>, (Status, String)> {
+        Ok(vec![SITE.to_string()])
+    }
+
+    async fn vendors(&self, site: &str) -> Result<SourceData, (Status, String)> {
+        if site != SITE {
+            return Err((Status::NotFound, format!("Unknown site {}", site)));
+        }
+        let vendors_json = fetch_vendors(&self.client, &self.vendor_cache).await?;
+        let vendors = serde_json::from_str(&vendors_json)
+            .map_err(|er| (Status::InternalServerError, format!("Unexpected vendor format {}", er)))?;
+        let entry = self.vendor_cache.lock().await.as_ref().map(|cache| (cache.0, cache.3));
+        Ok(SourceData::cached(vendors, entry))
+    }
+
+    async fn menu(&self, vendor_id: &str) -> Result<SourceData, (Status, String)> {
+        let menu = fetch_menu(vendor_id, &self.client, &self.menu_cache).await?;
+        let entry = self.menu_cache.lock().await.0.get(vendor_id).map(|(fetched, _, _, changed_at)| (*fetched, *changed_at));
+        Ok(SourceData::cached(menu, entry))
+    }
+
+    async fn timeslots(&self, request: &TimeslotRequest) -> Result<String, (Status, String)> {
+        fetch_timeslots(request, &self.timeslot_cache).await
+    }
+}
+
+/// A hand-maintained menu file, from `[<profile>.menu_sources.<name>]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct StaticSourceConfig {
+    pub path: PathBuf,
+}
+
+/// The contents of a static menu file: the locations per site and the menu per vendor route name.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct StaticMenus {
+    sites: BTreeMap<String, Value>,
+    menus: HashMap<String, Value>,
+}
+
+/// Vendors and menus from a JSON file. The file is read on every request, so edits show up right away.
+/// These vendors take no orders, so they have no timeslots.
+pub struct StaticSource {
+    name: String,
+    path: PathBuf,
+}
+
+impl StaticSource {
+    pub fn new(name: &str, config: StaticSourceConfig) -> Self {
+        StaticSource { name: name.to_string(), path: config.path }
+    }
+
+    /// The file contents and when the file was last changed.
+    async fn load(&self) -> Result<(StaticMenus, DateTime<Utc>), (Status, String)> {
+        let read_error = |er: std::io::Error| (Status::InternalServerError, format!("Failed to read menus of {} from {:?}: {}", self.name, self.path, er));
+        let json = rocket::tokio::fs::read_to_string(&self.path).await.map_err(read_error)?;
+        let modified = rocket::tokio::fs::metadata(&self.path).await.and_then(|metadata| metadata.modified()).map_err(read_error)?;
+        let menus = serde_json::from_str(&json)
+            .map_err(|er| (Status::InternalServerError, format!("Unexpected menu file format of {}: {}", self.name, er)))?;
+        Ok((menus, modified.into()))
+    }
+}
+
+#[rocket::async_trait]
+impl MenuSource for StaticSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn sites(&self) -> Result<Vec<String>, (Status, String)> {
+        Ok(self.load().await?.0.sites.into_keys().collect())
+    }
+
+    // Edits show up right away, so clients always revalidate.
+    async fn vendors(&self, site: &str) -> Result<SourceData, (Status, String)> {
+        let (mut menus, modified) = self.load().await?;
+        let vendors = menus.sites.remove(site).ok_or_else(|| (Status::NotFound, format!("Unknown site {}", site)))?;
+        Ok(SourceData { value: vendors, changed_at: modified, max_age: Duration::ZERO })
+    }
+
+    async fn menu(&self, vendor_id: &str) -> Result<SourceData, (Status, String)> {
+        let (mut menus, modified) = self.load().await?;
+        let menu = menus.menus.remove(vendor_id).ok_or_else(|| (Status::NotFound, format!("No menu for {}", vendor_id)))?;
+        Ok(SourceData { value: menu, changed_at: modified, max_age: Duration::ZERO })
+    }
+
+    async fn timeslots(&self, request: &TimeslotRequest) -> Result<String, (Status, String)> {
+        Err((Status::NotFound, format!("{} takes no orders through {}", request.route_name, self.name)))
+    }
+}
+
+/// A vendor as of the last time the vendors were loaded.
+struct IndexedVendor {
+    /// The position of its source in `SourceRegistry::sources`.
+    source: usize,
+    name: String,
+}
+
+/// Which source lists each vendor, by route name, as of the last time the vendors were loaded,
+/// and the images of the vendors and menus loaded since.
+#[derive(Default)]
+struct SourceIndex {
+    built: Option<Instant>,
+    vendors: HashMap<String, IndexedVendor>,
+    vendor_images: HashSet<String>,
+    menu_images: HashMap<String, HashSet<String>>,
+}
+
+/// All menu sources, in the order their vendors are listed.
+/// Menus are added to the search index as they load, and vendors that are no longer listed are removed from it.
+pub struct SourceRegistry {
+    sources: Vec<Arc<dyn MenuSource>>,
+    index: Mutex<SourceIndex>,
+    search: Mutex<SearchIndex>,
+}
+
+impl fmt::Debug for SourceRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.sources.iter().map(|source| source.name())).finish()
+    }
+}
+
+impl SourceRegistry {
+    pub fn new(sources: Vec<Arc<dyn MenuSource>>) -> Self {
+        SourceRegistry { sources, index: Mutex::new(SourceIndex::default()), search: Mutex::new(SearchIndex::default()) }
+    }
+
+    /// The registered sources, in the order their vendors are listed.
+    pub fn sources(&self) -> &[Arc<dyn MenuSource>] {
+        &self.sources
+    }
+
+    /// The locations of all sites of all sources, arranged per site, with the name of their source in `source`.
+    /// Sources that fail are left out, unless all of them fail.
+    /// The result changed when any site changed, and is fresh as long as all sites are.
+    pub async fn vendors(&self, order: &VendorOrder, day_of_year: u32) -> Result<SourceData, (Status, String)> {
+        let mut locations = Vec::new();
+        let mut changed_at: Option<DateTime<Utc>> = None;
+        let mut max_age = CACHE_TTL;
+        let mut vendors = HashMap::new();
+        let mut images = HashSet::new();
+        let mut failed = HashSet::new();
+        let mut first_error = None;
+        for (position, source) in self.sources.iter().enumerate() {
+            match source_vendors(source.as_ref(), order, day_of_year).await {
+                Ok(sites) => for site_data in sites {
+                    changed_at = changed_at.max(Some(site_data.changed_at));
+                    max_age = max_age.min(site_data.max_age);
+                    collect_image_urls(&site_data.value, &mut images);
+                    match parse_locations(&site_data.value) {
+                        Ok(parsed) => for vendor in menu::vendors(&parsed) {
+                            vendors.entry(vendor.route_name.clone()).or_insert(IndexedVendor { source: position, name: vendor.name.clone() });
+                        },
+                        Err(er) => warn!("Unexpected vendor format of {}: {}", source.name(), er),
+                    }
+                    if let Value::Array(site_locations) = site_data.value {
+                        locations.extend(site_locations);
+                    }
+                },
+                Err(er) => {
+                    warn!("Leaving out vendors of {}: {}", source.name(), er.1);
+                    failed.insert(position);
+                    first_error.get_or_insert(er);
+                },
+            }
+        }
+
+        let mut index = self.index.lock().await;
+        // Vendors of failing sources stay known until their source answers again.
+        for (route_name, vendor) in index.vendors.drain() {
+            if failed.contains(&vendor.source) {
+                vendors.entry(route_name).or_insert(vendor);
+            }
+        }
+        let listed: HashSet<&str> = vendors.keys().map(String::as_str).collect();
+        {
+            let mut search = self.search.lock().await;
+            if search.retain_vendors(&listed) {
+                search.rebuild();
+            }
+        }
+        index.vendors = vendors;
+        if !failed.is_empty() {
+            images.extend(index.vendor_images.drain());
+        }
+        index.vendor_images = images;
+        index.built = Some(Instant::now());
+        drop(index);
+
+        match (first_error, changed_at) {
+            (Some(er), None) => Err(er),
+            // Without any sites there is nothing that changed, so the list is as new as the request.
+            (_, changed_at) => Ok(SourceData { value: Value::Array(locations), changed_at: changed_at.unwrap_or_else(Utc::now), max_age }),
+        }
+    }
+
+    /// The source that lists `vendor_id`, so only known route names are passed on to a source.
+    /// Looks in the vendors loaded last, and only loads them again when they are older than `CACHE_TTL`.
+    pub async fn find(&self, vendor_id: &str) -> Result<Arc<dyn MenuSource>, (Status, String)> {
+        let fresh = {
+            let index = self.index.lock().await;
+            if let Some(vendor) = index.vendors.get(vendor_id) {
+                return Ok(self.sources[vendor.source].clone());
+            }
+            index.built.is_some_and(|built| built.elapsed() < CACHE_TTL)
+        };
+        if !fresh {
+            // Errors are logged, and vendors of failing sources are not found.
+            let _ = self.vendors(&VendorOrder::new(HashMap::new(), None), 0).await;
+            if let Some(vendor) = self.index.lock().await.vendors.get(vendor_id) {
+                return Ok(self.sources[vendor.source].clone());
+            }
+        }
+        warn!("Unknown vendor {}", vendor_id);
+        Err((Status::NotFound, format!("Unknown vendor {}", vendor_id)))
+    }
+
+    /// The menu of `vendor_id` from the source that lists it. A menu that changed is indexed for search.
+    pub async fn menu(&self, vendor_id: &str) -> Result<SourceData, (Status, String)> {
+        let menu = self.find(vendor_id).await?.menu(vendor_id).await?;
+        let mut images = HashSet::new();
+        collect_image_urls(&menu.value, &mut images);
+        let vendor_name = {
+            let mut index = self.index.lock().await;
+            index.menu_images.insert(vendor_id.to_string(), images);
+            index.vendors.get(vendor_id).map(|vendor| vendor.name.clone()).unwrap_or_default()
+        };
+
+        let mut search = self.search.lock().await;
+        if !search.is_indexed(vendor_id, menu.changed_at) {
+            match parse_menu(&menu.value) {
+                Ok(categories) => {
+                    search.update_vendor(vendor_id, &vendor_name, menu.changed_at, &categories);
+                    search.rebuild();
+                },
+                Err(er) => warn!("Leaving {} out of the search, unexpected menu format: {:?}", vendor_id, er),
+            }
+        }
+        Ok(menu)
+    }
+
+    /// The items of the loaded menus that match `query`, best match first, see `SearchIndex::search`.
+    pub async fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        self.search.lock().await.search(query, limit)
+    }
+
+    /// The locations of all sources, see `vendors`, for routes that look up vendors by route name.
+    pub async fn locations(&self, order: &VendorOrder) -> Result<Vec<Location>, (Status, String)> {
+        let vendors = self.vendors(order, 0).await?;
+        parse_locations(&vendors.value)
+            .map_err(|er| (Status::BadGateway, format!("Unexpected vendor format {}", er)))
+    }
+
+    /// The raw timeslot JSON for a request, from the source that lists its vendor.
+    /// Requests are validated here, so every caller keeps to the limits of the payments service.
+    pub async fn timeslots(&self, request: &TimeslotRequest) -> Result<String, (Status, String)> {
+        validate_request(request)?;
+        self.find(&request.route_name).await?.timeslots(request).await
+    }
+
+    /// Whether `url` is an image of the vendors or of a menu that was loaded.
+    pub async fn knows_image(&self, url: &str) -> bool {
+        let index = self.index.lock().await;
+        index.vendor_images.contains(url) || index.menu_images.values().any(|images| images.contains(url))
+    }
+}
+
+/// The locations of all sites of `source`, only once all of them were loaded.
+async fn source_vendors(source: &dyn MenuSource, order: &VendorOrder, day_of_year: u32) -> Result<Vec<SourceData>, (Status, String)> {
+    let mut sites = Vec::new();
+    for site in source.sites().await? {
+        let mut site_data = source.vendors(&site).await?;
+        order.arrange(&site, &mut site_data.value, day_of_year);
+        if let Value::Array(locations) = &mut site_data.value {
+            locations.iter_mut().for_each(|location| tag_source(location, source.name()));
+        }
+        sites.push(site_data);
+    }
+    Ok(sites)
+}
+
+/// Sets `source` on a location and on the vendors in its `children`.
+fn tag_source(location: &mut Value, source: &str) {
+    if let Some(Value::Array(children)) = location.get_mut("children") {
+        for child in children.iter_mut() {
+            tag_source(child, source);
+        }
+    }
+    if let Value::Object(location) = location {
+        location.insert("source".to_string(), Value::String(source.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    fn static_source(name: &str, menus: Value) -> StaticSource {
+        let path = std::env::temp_dir().join(format!("menu_source_{}_{}.json", name, std::process::id()));
+        std::fs::write(&path, menus.to_string()).unwrap();
+        StaticSource::new(name, StaticSourceConfig { path })
+    }
+
+    #[rocket::async_test]
+    async fn merges_vendors_of_all_sources() {
+        let canteen = static_source("canteen", json!({
+            "sites": {"campus_north": [{"name": "Food court", "routeName": "food_court", "children": [{"name": "Noodles", "routeName": "noodles"}]}]},
+            "menus": {"noodles": [{"name": "Mains", "items": [{"key": "ramen", "Name": "Ramen", "Cost": 6500}]}]},
+        }));
+        let cafe = static_source("cafe", json!({"sites": {"campus_south": [{"name": "Cafe", "routeName": "cafe"}]}}));
+        let registry = SourceRegistry::new(vec![Arc::new(canteen), Arc::new(cafe)]);
+
+        let vendors = registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap();
+        assert_eq!(vendors.max_age, Duration::ZERO);
+        let vendors = vendors.value;
+        assert_eq!(vendors[0]["source"], "canteen");
+        assert_eq!(vendors[0]["children"][0]["source"], "canteen");
+        assert_eq!(vendors[1]["source"], "cafe");
+
+        let source = registry.find("noodles").await.unwrap();
+        assert_eq!(source.name(), "canteen");
+        assert_eq!(source.menu("noodles").await.unwrap().value[0]["items"][0]["Name"], "Ramen");
+        assert_eq!(registry.find("cafe").await.unwrap().name(), "cafe");
+        assert_eq!(registry.find("food_court").await.err().map(|er| er.0), Some(Status::NotFound));
+    }
+
+    #[rocket::async_test]
+    async fn looks_up_locations_and_timeslots_through_the_source_of_the_vendor() {
+        let canteen = static_source("lookups", json!({"sites": {"campus_north": [{"name": "Noodles", "routeName": "noodles"}]}}));
+        let registry = SourceRegistry::new(vec![Arc::new(canteen)]);
+        let locations = registry.locations(&VendorOrder::new(HashMap::new(), None)).await.unwrap();
+        assert_eq!(menu::vendors(&locations).iter().map(|vendor| vendor.route_name.as_str()).collect::<Vec<_>>(), vec!["noodles"]);
+
+        let request = |route_name: &str| TimeslotRequest {
+            route_name: route_name.to_string(),
+            products: vec![crate::timeslots::TimeslotProduct { bong_category_id: 0, product_id: "ramen".to_string(), product_name: "Ramen".to_string(), quantity: 1 }],
+        };
+        let (status, message) = registry.timeslots(&request("noodles")).await.unwrap_err();
+        assert_eq!((status, message.as_str()), (Status::NotFound, "noodles takes no orders through lookups"));
+        assert_eq!(registry.timeslots(&request("bakery")).await.unwrap_err().1, "Unknown vendor bakery");
+        let empty = TimeslotRequest { route_name: "noodles".to_string(), products: Vec::new() };
+        assert_eq!(registry.timeslots(&empty).await.unwrap_err().0, Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn leaves_out_failing_sources() {
+        let cafe = static_source("working_cafe", json!({"sites": {"campus_south": [{"name": "Cafe", "routeName": "cafe"}]}}));
+        let missing = StaticSource::new("missing", StaticSourceConfig { path: PathBuf::from("/nonexistent/menus.json") });
+        let registry = SourceRegistry::new(vec![Arc::new(missing), Arc::new(cafe)]);
+        let vendors = registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap();
+        assert_eq!(vendors.value.as_array().unwrap().len(), 1);
+
+        let registry = SourceRegistry::new(vec![Arc::new(StaticSource::new("missing", StaticSourceConfig { path: PathBuf::from("/nonexistent/menus.json") }))]);
+        assert_eq!(registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap_err().0, Status::InternalServerError);
+    }
+
+    #[rocket::async_test]
+    async fn finds_vendors_in_the_last_loaded_list() {
+        let cafe = static_source("indexed_cafe", json!({"sites": {"campus_south": [{"name": "Cafe", "routeName": "cafe"}]}}));
+        let path = cafe.path.clone();
+        let registry = SourceRegistry::new(vec![Arc::new(cafe)]);
+        assert_eq!(registry.find("cafe").await.unwrap().name(), "indexed_cafe");
+
+        // Found without reading the file again, and kept while the source fails.
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(registry.find("cafe").await.unwrap().name(), "indexed_cafe");
+        assert!(registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.is_err());
+        assert_eq!(registry.find("cafe").await.unwrap().name(), "indexed_cafe");
+        assert_eq!(registry.find("bakery").await.err().map(|er| er.0), Some(Status::NotFound));
+    }
+
+    #[rocket::async_test]
+    async fn empty_sources_are_as_new_as_the_request() {
+        let registry = SourceRegistry::new(vec![Arc::new(static_source("empty", json!({})))]);
+        let vendors = registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap();
+        assert_eq!(vendors.value, json!([]));
+        assert!(Utc::now() - vendors.changed_at < chrono::Duration::seconds(5));
+    }
+
+    #[rocket::async_test]
+    async fn indexes_menus_as_they_load() {
+        let menus = |vendors: Value| json!({
+            "sites": {"campus_north": vendors},
+            "menus": {"noodles": [{"name": "Mains", "items": [{"key": "ramen", "Name": "Ramen", "Cost": 6500}]}]},
+        });
+        let canteen = static_source("searched", menus(json!([{"name": "Noodle bar", "routeName": "noodles"}])));
+        let path = canteen.path.clone();
+        let registry = SourceRegistry::new(vec![Arc::new(canteen)]);
+        assert!(registry.search("ramen", 10).await.is_empty());
+
+        registry.menu("noodles").await.unwrap();
+        let hits = registry.search("ramen", 10).await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(serde_json::to_value(&hits[0]).unwrap()["vendor_name"], "Noodle bar");
+
+        std::fs::write(&path, menus(json!([])).to_string()).unwrap();
+        registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap();
+        assert!(registry.search("ramen", 10).await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn knows_images_of_loaded_vendors_and_menus() {
+        let canteen = static_source("pictures", json!({
+            "sites": {"campus_north": [{"name": "Food court", "routeName": "food_court", "imageUrl": "https://firebasestorage.googleapis.com/logo.png",
+                "children": [{"name": "Noodles", "routeName": "noodles", "imageUrl": "https://firebasestorage.googleapis.com/child.png"}]}]},
+            "menus": {"noodles": [{"name": "Mains", "items": [{"key": "ramen", "Name": "Ramen", "ImageUrl": "https://firebasestorage.googleapis.com/dish.jpg"}]}]},
+        }));
+        let registry = SourceRegistry::new(vec![Arc::new(canteen)]);
+        assert!(!registry.knows_image("https://firebasestorage.googleapis.com/logo.png").await);
+
+        registry.vendors(&VendorOrder::new(HashMap::new(), None), 1).await.unwrap();
+        assert!(registry.knows_image("https://firebasestorage.googleapis.com/logo.png").await);
+        assert!(registry.knows_image("https://firebasestorage.googleapis.com/child.png").await);
+        assert!(!registry.knows_image("https://firebasestorage.googleapis.com/dish.jpg").await);
+
+        registry.menu("noodles").await.unwrap();
+        assert!(registry.knows_image("https://firebasestorage.googleapis.com/dish.jpg").await);
+        assert!(!registry.knows_image("https://example.com/dish.jpg").await);
+    }
+}
@@ -0,0 +1,170 @@
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::{Data, Request, Response};
+use std::sync::LazyLock;
+use std::time::Instant;
+use tracing::{error, instrument};
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).expect("Metric registered twice");
+    metric
+}
+
+/// Upstream WebSocket connections by outcome: `connected`, `reconnected` or `failed`.
+pub static PUBQ_CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("pubq_connections_total", "WebSocket connections to PubQ by outcome"),
+    &["outcome"]).unwrap()));
+
+pub static PUBQ_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| register(IntGauge::new(
+    "pubq_connected", "Whether the WebSocket connection to PubQ is open").unwrap()));
+
+pub static PUBQ_RECEIVE_TIMEOUTS: LazyLock<IntCounter> = LazyLock::new(|| register(IntCounter::new(
+    "pubq_receive_timeouts_total", "Times no PubQ message arrived within the timeout").unwrap()));
+
+pub static PUBQ_CHUNKED_MESSAGES: LazyLock<IntCounter> = LazyLock::new(|| register(IntCounter::new(
+    "pubq_chunked_messages_total", "PubQ responses sent in chunks").unwrap()));
+
+pub static PUBQ_CHUNKED_MESSAGE_BYTES: LazyLock<Histogram> = LazyLock::new(|| register(Histogram::with_opts(
+    HistogramOpts::new("pubq_chunked_message_bytes", "Size of PubQ responses sent in chunks")
+        .buckets(exponential_buckets(16384.0, 2.0, 10).unwrap())).unwrap()));
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("http_requests_total", "Handled requests by route and status"),
+    &["method", "route", "status"]).unwrap()));
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("http_request_duration_seconds", "Time to handle a request by route"),
+    &["method", "route"]).unwrap()));
+
+/// Cache lookups by cache and result: `hit` or `miss`.
+pub static CACHE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("cache_requests_total", "Cache lookups by cache and result"),
+    &["cache", "result"]).unwrap()));
+
+/// Refreshes of expired cache entries by cache and result: `unchanged` when PubQ only confirmed the hash, otherwise `changed`.
+pub static CACHE_REVALIDATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("cache_revalidations_total", "Refreshes of expired cache entries by cache and result"),
+    &["cache", "result"]).unwrap()));
+
+pub static TIMESLOT_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("timeslot_request_duration_seconds", "Time for the payments service to answer a timeslot request by status"),
+    &["status"]).unwrap()));
+
+pub static KNOWN_VENDORS: LazyLock<IntGauge> = LazyLock::new(|| register(IntGauge::new(
+    "known_vendors", "Vendors in the last fetched vendor list").unwrap()));
+
+pub static KNOWN_ITEMS: LazyLock<IntGaugeVec> = LazyLock::new(|| register(IntGaugeVec::new(
+    Opts::new("known_items", "Items in the last fetched menu by vendor"),
+    &["vendor"]).unwrap()));
+
+/// Registers every metric, so they are exported before they are first used.
+pub fn init() {
+    LazyLock::force(&PUBQ_CONNECTIONS);
+    LazyLock::force(&PUBQ_CONNECTED);
+    LazyLock::force(&PUBQ_RECEIVE_TIMEOUTS);
+    LazyLock::force(&PUBQ_CHUNKED_MESSAGES);
+    LazyLock::force(&PUBQ_CHUNKED_MESSAGE_BYTES);
+    LazyLock::force(&HTTP_REQUESTS);
+    LazyLock::force(&HTTP_REQUEST_DURATION);
+    LazyLock::force(&CACHE_REQUESTS);
+    LazyLock::force(&CACHE_REVALIDATIONS);
+    LazyLock::force(&TIMESLOT_REQUEST_DURATION);
+    LazyLock::force(&KNOWN_VENDORS);
+    LazyLock::force(&KNOWN_ITEMS);
+}
+
+/// Counts a lookup in `cache`.
+pub fn cache_lookup(cache: &str, hit: bool) {
+    CACHE_REQUESTS.with_label_values(&[cache, if hit { "hit" } else { "miss" }]).inc();
+}
+
+/// Counts a refresh of an expired entry in `cache`.
+pub fn cache_revalidation(cache: &str, unchanged: bool) {
+    CACHE_REVALIDATIONS.with_label_values(&[cache, if unchanged { "unchanged" } else { "changed" }]).inc();
+}
+
+/// Records latency and status of every request under its route, so `/api/menu/<vendor_id>` is one series.
+/// With a meter, the latency is also exported over OpenTelemetry.
+pub struct RequestMetrics {
+    otel_duration: Option<opentelemetry::metrics::Histogram<f64>>,
+}
+
+impl RequestMetrics {
+    pub fn new(meter: Option<Meter>) -> Self {
+        let otel_duration = meter.map(|meter| meter.f64_histogram("http.server.request.duration")
+            .with_unit("s")
+            .with_description("Duration of HTTP server requests")
+            .build());
+        RequestMetrics { otel_duration }
+    }
+}
+
+struct RequestStart(Instant);
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info { name: "Request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let route = request.route()
+            .map(|route| route.uri.as_str().split('?').next().unwrap_or_default().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let method = request.method().as_str();
+        let status = response.status().code;
+        let elapsed = start.0.elapsed().as_secs_f64();
+        HTTP_REQUEST_DURATION.with_label_values(&[method, &route]).observe(elapsed);
+        HTTP_REQUESTS.with_label_values(&[method, &route, &status.to_string()]).inc();
+        if let Some(otel_duration) = &self.otel_duration {
+            otel_duration.record(elapsed, &[
+                KeyValue::new("http.request.method", method),
+                KeyValue::new("http.route", route),
+                KeyValue::new("http.response.status_code", status as i64),
+            ]);
+        }
+    }
+}
+
+#[get("/metrics")]
+#[instrument]
+pub fn metrics() -> Result<(ContentType, String), (Status, String)> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|er| {
+            error!("Failed to encode metrics: {:?}", er);
+            (Status::InternalServerError, format!("Encoding metrics failed {:?}", er))
+        })?;
+    let text = String::from_utf8(buffer)
+        .map_err(|er| (Status::InternalServerError, format!("Encoding metrics failed {:?}", er)))?;
+    Ok((ContentType::new("text", "plain").with_params(("version", "0.0.4")), text))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn exports_registered_metrics() {
+        cache_lookup("test", true);
+        cache_lookup("test", false);
+        cache_lookup("test", false);
+        let (_, text) = metrics().unwrap();
+        assert!(text.contains(r#"cache_requests_total{cache="test",result="hit"} 1"#));
+        assert!(text.contains(r#"cache_requests_total{cache="test",result="miss"} 2"#));
+    }
+}
@@ -0,0 +1,450 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use chrono_tz::Europe::Copenhagen;
+use chrono_tz::Tz;
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+
+use crate::menu::{find_item, parse_menu, vendors};
+use crate::menu_source::SourceRegistry;
+use crate::timeslots::{parse_enabled_slots, TimeslotProduct, TimeslotRequest};
+use crate::token::UserToken;
+use crate::vendor_order::VendorOrder;
+
+/// Closed polls keep their result this long.
+const POLL_RETENTION: chrono::Duration = chrono::Duration::days(1);
+
+/// Polls kept at once, new polls are refused beyond this.
+const MAX_POLLS: usize = 1000;
+
+/// Dishes of a winning vendor asked about timeslots, each one is a request to the payments service.
+const MAX_TIMESLOT_CHECKS: usize = 5;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollMode {
+    /// Voters pick any number of options, the most picked option wins.
+    Approval,
+    /// Voters rank options, decided by instant runoff.
+    RankedChoice,
+}
+
+/// A vendor, or a single dish when `item` is set.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PollOption {
+    pub vendor: String,
+    #[serde(default)]
+    pub item: Option<String>,
+    #[serde(default)]
+    pub label: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewPoll {
+    date: NaiveDate,
+    mode: PollMode,
+    closes_at: DateTime<Utc>,
+    /// Defaults to all of today's visible vendors.
+    #[serde(default)]
+    options: Vec<PollOption>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Ballot {
+    /// Indices into the poll options. In ranked choice mode, the most preferred comes first.
+    choices: Vec<usize>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Poll {
+    id: u64,
+    date: NaiveDate,
+    mode: PollMode,
+    closes_at: DateTime<Utc>,
+    options: Vec<PollOption>,
+    vote_count: usize,
+    #[serde(skip)]
+    votes: HashMap<UserToken, Vec<usize>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PollResult {
+    closed: bool,
+    vote_count: usize,
+    /// Votes per option for approval polls, and first preferences per option in each runoff round for ranked choice.
+    rounds: Vec<Vec<u32>>,
+    winner: Option<PollOption>,
+    earliest_timeslot: Option<DateTime<Tz>>,
+}
+
+/// The polls in memory, they are not kept across restarts.
+#[derive(Default)]
+pub struct PollStore {
+    polls: HashMap<u64, Poll>,
+    next_id: u64,
+}
+
+impl PollStore {
+    /// Adds a poll with the next id, after removing polls that closed more than `POLL_RETENTION` ago.
+    fn add(&mut self, date: NaiveDate, mode: PollMode, closes_at: DateTime<Utc>, options: Vec<PollOption>, now: DateTime<Utc>) -> Result<Poll, (Status, String)> {
+        self.polls.retain(|_, poll| poll.closes_at + POLL_RETENTION > now);
+        if self.polls.len() >= MAX_POLLS {
+            warn!("Refusing a new poll, {} polls are open", self.polls.len());
+            return Err((Status::ServiceUnavailable, "Too many polls, try again later".to_string()));
+        }
+
+        self.next_id += 1;
+        let poll = Poll { id: self.next_id, date, mode, closes_at, options, vote_count: 0, votes: HashMap::new() };
+        self.polls.insert(poll.id, poll.clone());
+        Ok(poll)
+    }
+}
+
+impl Poll {
+    fn is_closed(&self, now: DateTime<Utc>) -> bool {
+        now >= self.closes_at
+    }
+
+    /// Registers the ballot of a voter, replacing any earlier ballot of theirs.
+    fn vote(&mut self, token: UserToken, choices: Vec<usize>, now: DateTime<Utc>) -> Result<(), (Status, String)> {
+        if self.is_closed(now) {
+            return Err((Status::Forbidden, format!("Poll {} closed at {}", self.id, self.closes_at)));
+        }
+        if choices.is_empty() {
+            return Err((Status::BadRequest, "A ballot needs at least one choice".to_string()));
+        }
+        if let Some(choice) = choices.iter().find(|choice| **choice >= self.options.len()) {
+            return Err((Status::BadRequest, format!("Unknown option {}", choice)));
+        }
+        if choices.iter().enumerate().any(|(i, choice)| choices[..i].contains(choice)) {
+            return Err((Status::BadRequest, "An option can only be chosen once".to_string()));
+        }
+
+        self.votes.insert(token, choices);
+        self.vote_count = self.votes.len();
+        Ok(())
+    }
+
+    /// The winning option index and the tallies that decided it.
+    fn tally(&self) -> (Option<usize>, Vec<Vec<u32>>) {
+        let ballots: Vec<&Vec<usize>> = self.votes.values().collect();
+        match self.mode {
+            PollMode::Approval => approval(self.options.len(), &ballots),
+            PollMode::RankedChoice => instant_runoff(self.options.len(), &ballots),
+        }
+    }
+}
+
+fn approval(option_count: usize, ballots: &[&Vec<usize>]) -> (Option<usize>, Vec<Vec<u32>>) {
+    let mut counts = vec![0u32; option_count];
+    for choice in ballots.iter().flat_map(|ballot| ballot.iter()) {
+        counts[*choice] += 1;
+    }
+    let winner = most_votes(&counts, &vec![true; option_count]).filter(|winner| counts[*winner] > 0);
+    (winner, vec![counts])
+}
+
+fn instant_runoff(option_count: usize, ballots: &[&Vec<usize>]) -> (Option<usize>, Vec<Vec<u32>>) {
+    let mut remaining = vec![true; option_count];
+    let mut rounds = Vec::new();
+    loop {
+        let mut counts = vec![0u32; option_count];
+        let mut active = 0;
+        for ballot in ballots {
+            if let Some(choice) = ballot.iter().find(|choice| remaining[**choice]) {
+                counts[*choice] += 1;
+                active += 1;
+            }
+        }
+        rounds.push(counts.clone());
+
+        let Some(leader) = most_votes(&counts, &remaining).filter(|_| active > 0) else {
+            return (None, rounds);
+        };
+        if counts[leader] * 2 > active || remaining.iter().filter(|r| **r).count() <= 1 {
+            return (Some(leader), rounds);
+        }
+
+        // Eliminate the option with the fewest votes, the last listed one on ties.
+        let loser = (0..option_count).rev()
+            .filter(|option| remaining[*option])
+            .min_by_key(|option| counts[*option])
+            .unwrap_or(leader);
+        remaining[loser] = false;
+    }
+}
+
+/// The option with the most votes among the eligible ones, the first listed one on ties.
+fn most_votes(counts: &[u32], eligible: &[bool]) -> Option<usize> {
+    (0..counts.len())
+        .filter(|option| eligible[*option])
+        .fold(None, |best: Option<usize>, option| match best {
+            Some(best) if counts[best] >= counts[option] => Some(best),
+            _ => Some(option),
+        })
+}
+
+/// Checks that a poll for `date` closes after `now` and no later than the end of `date` in Copenhagen.
+fn check_closing_time(date: NaiveDate, closes_at: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), (Status, String)> {
+    if date < now.with_timezone(&Copenhagen).date_naive() {
+        return Err((Status::BadRequest, format!("{} is in the past", date)));
+    }
+    if closes_at <= now {
+        return Err((Status::BadRequest, format!("Closing time {} is in the past", closes_at)));
+    }
+    let end_of_day = date.succ_opt()
+        .and_then(|next_day| Copenhagen.from_local_datetime(&next_day.and_time(chrono::NaiveTime::MIN)).earliest());
+    if end_of_day.is_none_or(|end_of_day| closes_at > end_of_day) {
+        return Err((Status::BadRequest, format!("Closing time {} is after {}", closes_at, date)));
+    }
+    Ok(())
+}
+
+#[post("/polls", data = "<body>")]
+#[instrument(skip(body))]
+pub async fn create_poll(
+    body: Json<NewPoll>,
+    sources: &State<Arc<SourceRegistry>>,
+    order: &State<Mutex<VendorOrder>>,
+    polls: &State<Mutex<PollStore>>,
+) -> Result<Json<Poll>, (Status, String)> {
+    let new_poll = body.into_inner();
+    let now = Utc::now();
+    check_closing_time(new_poll.date, new_poll.closes_at, now)?;
+
+    let locations = sources.locations(&*order.lock().await).await?;
+    let known_vendors = vendors(&locations);
+    let mut options = Vec::new();
+    if new_poll.options.is_empty() {
+        for vendor in known_vendors.iter().filter(|vendor| vendor.enabled != Some(false) && vendor.visible != Some(false)) {
+            options.push(PollOption { vendor: vendor.route_name.clone(), item: None, label: vendor.name.clone() });
+        }
+    }
+    for option in new_poll.options {
+        let vendor = known_vendors.iter()
+            .find(|vendor| vendor.route_name == option.vendor)
+            .ok_or((Status::BadRequest, format!("Unknown vendor {}", option.vendor)))?;
+        let label = match &option.item {
+            Some(key) => {
+                let menu = sources.menu(&option.vendor).await?;
+                let menu = parse_menu(&menu.value)
+                    .map_err(|er| (Status::BadGateway, format!("Unexpected menu format {:?}", er)))?;
+                let item = find_item(&menu, key)
+                    .ok_or((Status::BadRequest, format!("Item {} is not on the menu of {}", key, option.vendor)))?;
+                format!("{} ({})", item.name, vendor.name)
+            },
+            None => vendor.name.clone(),
+        };
+        options.push(PollOption { label, ..option });
+    }
+    if options.is_empty() {
+        return Err((Status::BadRequest, "A poll needs at least one option".to_string()));
+    }
+
+    let poll = polls.lock().await.add(new_poll.date, new_poll.mode, new_poll.closes_at, options, now)?;
+    info!("Created poll {} for {} with {} options", poll.id, poll.date, poll.options.len());
+    Ok(Json(poll))
+}
+
+#[get("/polls/<id>")]
+#[instrument]
+pub async fn get_poll(id: u64, polls: &State<Mutex<PollStore>>) -> Result<Json<Poll>, (Status, String)> {
+    polls.lock().await.polls.get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or((Status::NotFound, format!("No poll {}", id)))
+}
+
+#[post("/polls/<id>/votes", data = "<body>")]
+#[instrument(skip(body))]
+pub async fn vote(id: u64, token: UserToken, body: Json<Ballot>, polls: &State<Mutex<PollStore>>) -> Result<Status, (Status, String)> {
+    let mut polls = polls.lock().await;
+    let poll = polls.polls.get_mut(&id)
+        .ok_or((Status::NotFound, format!("No poll {}", id)))?;
+    poll.vote(token, body.into_inner().choices, Utc::now())?;
+    Ok(Status::NoContent)
+}
+
+#[get("/polls/<id>/result")]
+#[instrument]
+pub async fn get_result(
+    id: u64,
+    sources: &State<Arc<SourceRegistry>>,
+    polls: &State<Mutex<PollStore>>,
+) -> Result<Json<PollResult>, (Status, String)> {
+    let poll = polls.lock().await.polls.get(&id)
+        .cloned()
+        .ok_or((Status::NotFound, format!("No poll {}", id)))?;
+    let (winner, rounds) = poll.tally();
+    let winner = winner.map(|winner| poll.options[winner].clone());
+
+    let earliest_timeslot = match &winner {
+        Some(winner) => earliest_timeslot(&poll, winner, sources).await
+            .unwrap_or_else(|(_, er)| {
+                warn!("No timeslot for the winner of poll {}: {}", id, er);
+                None
+            }),
+        None => None,
+    };
+
+    Ok(Json(PollResult {
+        closed: poll.is_closed(Utc::now()),
+        vote_count: poll.vote_count,
+        rounds,
+        winner,
+        earliest_timeslot,
+    }))
+}
+
+/// The earliest enabled slot on the poll date for the winner.
+/// A winning dish stands alone, a winning vendor has the earliest slot of any of its dishes in the poll,
+/// or else of the first available dishes on its menu. Each dish is asked about on its own.
+async fn earliest_timeslot(
+    poll: &Poll,
+    winner: &PollOption,
+    sources: &SourceRegistry,
+) -> Result<Option<DateTime<Tz>>, (Status, String)> {
+    let menu = sources.menu(&winner.vendor).await?;
+    let menu = parse_menu(&menu.value)
+        .map_err(|er| (Status::BadGateway, format!("Unexpected menu format {:?}", er)))?;
+
+    let keys: Vec<&String> = match &winner.item {
+        Some(key) => vec![key],
+        None => poll.options.iter()
+            .filter(|option| option.vendor == winner.vendor)
+            .filter_map(|option| option.item.as_ref())
+            .collect(),
+    };
+    let items: Vec<_> = if keys.is_empty() {
+        menu.iter().flat_map(|category| category.items.iter()).filter(|item| item.is_available()).collect()
+    } else {
+        keys.iter().filter_map(|key| find_item(&menu, key)).collect()
+    };
+
+    let mut earliest: Option<DateTime<Tz>> = None;
+    for item in items.into_iter().take(MAX_TIMESLOT_CHECKS) {
+        let request = TimeslotRequest {
+            route_name: winner.vendor.clone(),
+            products: vec![TimeslotProduct { bong_category_id: 0, product_id: item.key.clone(), product_name: item.name.clone(), quantity: 1 }],
+        };
+        let slot = match sources.timeslots(&request).await {
+            Ok(timeslots_json) => parse_enabled_slots(&timeslots_json)?.into_iter().find(|slot| slot.date_naive() == poll.date),
+            Err((_, er)) => {
+                warn!("No timeslots for {} of {}: {}", item.key, winner.vendor, er);
+                None
+            }
+        };
+        if let Some(slot) = slot {
+            earliest = Some(earliest.map_or(slot, |earliest| earliest.min(slot)));
+        }
+    }
+    Ok(earliest)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn poll(mode: PollMode, option_count: usize) -> Poll {
+        Poll {
+            id: 1,
+            date: NaiveDate::from_ymd_opt(2025, 12, 11).unwrap(),
+            mode,
+            closes_at: DateTime::parse_from_rfc3339("2025-12-11T10:30:00Z").unwrap().with_timezone(&Utc),
+            options: (0..option_count).map(|i| PollOption { vendor: format!("vendor{}", i), item: None, label: String::new() }).collect(),
+            vote_count: 0,
+            votes: HashMap::new(),
+        }
+    }
+
+    fn before_closing() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-12-11T09:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    fn token(name: &str) -> UserToken {
+        UserToken(name.to_string())
+    }
+
+    #[test]
+    fn approval_counts_every_approved_option() {
+        let mut poll = poll(PollMode::Approval, 3);
+        poll.vote(token("a"), vec![0, 1], before_closing()).unwrap();
+        poll.vote(token("b"), vec![1], before_closing()).unwrap();
+        poll.vote(token("c"), vec![2, 1], before_closing()).unwrap();
+        assert_eq!(poll.tally(), (Some(1), vec![vec![1, 3, 1]]));
+    }
+
+    #[test]
+    fn one_vote_per_token() {
+        let mut poll = poll(PollMode::Approval, 2);
+        poll.vote(token("a"), vec![0], before_closing()).unwrap();
+        poll.vote(token("a"), vec![1], before_closing()).unwrap();
+        assert_eq!(poll.vote_count, 1);
+        assert_eq!(poll.tally(), (Some(1), vec![vec![0, 1]]));
+    }
+
+    #[test]
+    fn rejects_invalid_ballots_and_late_votes() {
+        let mut poll = poll(PollMode::RankedChoice, 2);
+        assert_eq!(poll.vote(token("a"), vec![], before_closing()).unwrap_err().0, Status::BadRequest);
+        assert_eq!(poll.vote(token("a"), vec![2], before_closing()).unwrap_err().0, Status::BadRequest);
+        assert_eq!(poll.vote(token("a"), vec![1, 1], before_closing()).unwrap_err().0, Status::BadRequest);
+        let late = DateTime::parse_from_rfc3339("2025-12-11T10:30:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(poll.vote(token("a"), vec![0], late).unwrap_err().0, Status::Forbidden);
+    }
+
+    #[test]
+    fn ranked_choice_transfers_votes_of_eliminated_options() {
+        let mut poll = poll(PollMode::RankedChoice, 3);
+        poll.vote(token("a"), vec![0, 1], before_closing()).unwrap();
+        poll.vote(token("b"), vec![0], before_closing()).unwrap();
+        poll.vote(token("c"), vec![1, 2], before_closing()).unwrap();
+        poll.vote(token("d"), vec![2, 1], before_closing()).unwrap();
+        poll.vote(token("e"), vec![2, 1], before_closing()).unwrap();
+        // Nobody has a majority, option 1 goes out and c moves to option 2.
+        assert_eq!(poll.tally(), (Some(2), vec![vec![2, 1, 2], vec![2, 0, 3]]));
+    }
+
+    #[test]
+    fn no_winner_without_votes() {
+        assert_eq!(poll(PollMode::Approval, 2).tally().0, None);
+        assert_eq!(poll(PollMode::RankedChoice, 2).tally().0, None);
+    }
+
+    #[test]
+    fn closes_no_later_than_the_end_of_the_poll_date() {
+        let date = NaiveDate::from_ymd_opt(2025, 12, 11).unwrap();
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        assert!(check_closing_time(date, at("2025-12-11T10:30:00Z"), before_closing()).is_ok());
+        // Midnight in Copenhagen is 23:00 UTC in winter.
+        assert!(check_closing_time(date, at("2025-12-11T23:00:00Z"), before_closing()).is_ok());
+        assert_eq!(check_closing_time(date, at("2025-12-11T23:00:01Z"), before_closing()).unwrap_err().0, Status::BadRequest);
+        assert_eq!(check_closing_time(date, at("2099-01-01T00:00:00Z"), before_closing()).unwrap_err().0, Status::BadRequest);
+        assert_eq!(check_closing_time(date, at("2025-12-11T08:00:00Z"), before_closing()).unwrap_err().0, Status::BadRequest);
+        assert_eq!(check_closing_time(date.pred_opt().unwrap(), at("2025-12-11T10:30:00Z"), before_closing()).unwrap_err().0, Status::BadRequest);
+    }
+
+    #[test]
+    fn expires_closed_polls_and_caps_the_rest() {
+        let mut store = PollStore::default();
+        let closed = poll(PollMode::Approval, 1);
+        let add = |store: &mut PollStore, now: DateTime<Utc>| store.add(closed.date, closed.mode, closed.closes_at, closed.options.clone(), now);
+        let id = add(&mut store, before_closing()).unwrap().id;
+
+        add(&mut store, closed.closes_at + chrono::Duration::hours(23)).unwrap();
+        assert!(store.polls.contains_key(&id));
+        add(&mut store, closed.closes_at + POLL_RETENTION).unwrap();
+        assert!(!store.polls.contains_key(&id));
+        assert_eq!(store.polls.len(), 1);
+
+        for _ in 1..MAX_POLLS {
+            add(&mut store, before_closing()).unwrap();
+        }
+        assert_eq!(add(&mut store, before_closing()).unwrap_err().0, Status::ServiceUnavailable);
+    }
+}
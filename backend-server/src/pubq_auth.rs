@@ -0,0 +1,60 @@
+use rocket::serde::Deserialize;
+use std::fmt;
+
+/// Which auth request a token is sent with.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// A Firebase ID token or database secret, sent with `auth`.
+    #[default]
+    Firebase,
+    /// A Google OAuth access token, sent with `gauth`.
+    Google,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credential {
+    pub token: String,
+    pub kind: TokenKind,
+}
+
+/// Gives the token to authenticate with. It is asked again after every reconnect, so it can hand out fresh tokens.
+#[rocket::async_trait]
+pub trait TokenProvider: Send + Sync {
+    async fn credential(&self) -> Result<Credential, String>;
+}
+
+/// A fixed token from `[<profile>.pubq_auth]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct StaticToken {
+    pub token: String,
+    #[serde(default)]
+    pub kind: TokenKind,
+}
+
+#[rocket::async_trait]
+impl TokenProvider for StaticToken {
+    async fn credential(&self) -> Result<Credential, String> {
+        Ok(Credential { token: self.token.clone(), kind: self.kind })
+    }
+}
+
+/// Auth errors, which callers can tell apart from connection errors with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// No token could be had, or PubQ rejected it.
+    Failed { status: String, reason: String },
+    /// PubQ revoked the session, e.g. because the token expired. The connection is dropped, so the next one authenticates again.
+    Revoked { status: String, reason: String },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Failed { status, reason } => write!(f, "PubQ authentication failed ({}): {}", status, reason),
+            AuthError::Revoked { status, reason } => write!(f, "PubQ authentication revoked ({}): {}", status, reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
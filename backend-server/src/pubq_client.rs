@@ -1,9 +1,22 @@
-use rocket::{futures::{SinkExt, TryStreamExt}, serde::{Deserialize, Serialize}, tokio::select};
+use rocket::{futures::{SinkExt, TryStreamExt}, serde::{Deserialize, Serialize}, tokio::sync::broadcast};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use std::sync::Arc;
 use std::time::Duration;
-use tracing::{error, info, warn};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::data_hash::data_hash;
+use crate::frame_decoder::{Decoded, FrameDecoder, FrameError, FrameReader};
+use crate::metrics::{PUBQ_CHUNKED_MESSAGES, PUBQ_CHUNKED_MESSAGE_BYTES, PUBQ_CONNECTIONS, PUBQ_RECEIVE_TIMEOUTS};
+use crate::pubq_auth::{AuthError, TokenKind, TokenProvider};
 
 const SOCKET_URL : &str = "wss://s-usc1a-nss-2040.firebaseio.com/.ws?v=5&ns=pq-dev";
+/// Messages larger than this are rejected, the largest menus are a few hundred KiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+/// The site whose vendors are listed.
+pub const SITE : &str = "compassdk_danskebank";
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "t", content = "d")]
@@ -41,10 +54,20 @@ struct  Data {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 enum RequestAction {
+    /// Listens to a path. The server sends its data, and then changes to it until it gets `Unlisten`.
     #[serde(rename = "q")]
     Query,
+    #[serde(rename = "n")]
+    Unlisten,
     #[serde(rename = "d")]
     Data,
+    #[serde(rename = "auth")]
+    Auth,
+    #[serde(rename = "gauth")]
+    GoogleAuth,
+    /// Sent by the server when it revokes the session.
+    #[serde(rename = "ac")]
+    AuthRevoked,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -57,6 +80,46 @@ struct RequestBody {
     status: Option<RequestStatus>,
     #[serde(rename = "d", skip_serializing_if = "Option::is_none")]
     data: Option<Value>,
+    #[serde(rename = "q", skip_serializing_if = "Option::is_none")]
+    query: Option<QueryOptions>,
+    #[serde(rename = "cred", skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+}
+
+/// Firebase query parameters, sent the way the Firebase SDKs send them over the WebSocket, e.g.
+/// `QueryOptions { order_by: Some("name".to_string()), limit: Some(10), view_from: Some(ViewFrom::First), ..Default::default() }`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct QueryOptions {
+    /// Only the keys of the children, as `true`. The WebSocket protocol has no such parameter, so it is applied here.
+    #[serde(skip)]
+    pub shallow: bool,
+    /// `.key`, `.value`, `.priority` or the path of a child.
+    #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
+    pub order_by: Option<String>,
+    #[serde(rename = "sp", skip_serializing_if = "Option::is_none")]
+    pub start_at: Option<Value>,
+    #[serde(rename = "ep", skip_serializing_if = "Option::is_none")]
+    pub end_at: Option<Value>,
+    /// Like `limitToFirst` or `limitToLast`, depending on `view_from`.
+    #[serde(rename = "l", skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(rename = "vf", skip_serializing_if = "Option::is_none")]
+    pub view_from: Option<ViewFrom>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ViewFrom {
+    #[serde(rename = "l")]
+    First,
+    #[serde(rename = "r")]
+    Last,
+}
+
+impl QueryOptions {
+    /// Whether there is nothing to send to the server.
+    fn is_empty(&self) -> bool {
+        QueryOptions { shallow: false, ..self.clone() } == QueryOptions::default()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -65,228 +128,566 @@ enum RequestStatus {
     Ok,
     #[serde(rename = "fail")]
     Fail,
+    /// Auth errors such as `invalid_token` or `expired_token`.
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl Data {
+    /// The status and the message the server gave with it.
+    fn status_and_reason(&self) -> (String, String) {
+        let status = match &self.body.status {
+            Some(RequestStatus::Ok) => "ok".to_string(),
+            Some(RequestStatus::Fail) => "fail".to_string(),
+            Some(RequestStatus::Other(status)) => status.clone(),
+            None => "none".to_string(),
+        };
+        let reason = match &self.body.data {
+            Some(Value::String(reason)) => reason.clone(),
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        (status, reason)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    /// A query is running, so the client could not be inspected. Only reported by the health check.
+    Busy,
+}
+
+/// A change of the connection state, see `PubqClient::subscribe`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConnectionEvent {
+    pub state: ConnectionState,
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+/// The last error of a `PubqClient`, see `PubqClient::status`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PubqError {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// What a `PubqClient` is connected to and how it is doing.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PubqStatus {
+    pub socket_url: String,
+    pub state: ConnectionState,
+    /// The host and session of the connection, from the header PubQ sends first.
+    pub host: Option<String>,
+    pub session_id: Option<String>,
+    pub next_request_id: u64,
+    pub last_query_at: Option<DateTime<Utc>>,
+    pub last_error: Option<PubqError>,
 }
 
 pub struct PubqClient {
     socket_url: String,
+    host: Option<String>,
+    session_id: Option<String>,
+    last_error: Option<PubqError>,
     next_id: u64,
     stream: Option<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
     is_connected: bool,
+    has_connected: bool,
+    last_query_at: Option<DateTime<Utc>>,
+    events: broadcast::Sender<ConnectionEvent>,
+    auth: Option<Arc<dyn TokenProvider>>,
+    max_message_size: usize,
+    decoder: FrameDecoder,
 }
 
 impl PubqClient {
     pub fn new() -> Self {
         PubqClient {
             socket_url: SOCKET_URL.to_string(),
+            host: None,
+            session_id: None,
+            last_error: None,
             next_id: 1,
             stream: None,
             is_connected: false,
+            has_connected: false,
+            last_query_at: None,
+            events: broadcast::channel(16).0,
+            auth: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            decoder: FrameDecoder::new(DEFAULT_MAX_MESSAGE_SIZE),
+        }
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self.decoder = FrameDecoder::new(max_message_size);
+        self
+    }
+
+    /// Connects to another database, e.g. `wss://<host>/.ws?v=5&ns=<namespace>`.
+    pub fn with_socket_url(mut self, socket_url: &str) -> Self {
+        self.socket_url = socket_url.to_string();
+        self
+    }
+
+    /// Authenticates every new connection with a token from `provider`.
+    pub fn with_auth(mut self, provider: Arc<dyn TokenProvider>) -> Self {
+        self.auth = Some(provider);
+        self
+    }
+
+    /// Connection state changes from now on. Slow subscribers miss the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    fn set_connected(&mut self, connected: bool, reason: &str) {
+        if self.is_connected == connected {
+            return;
+        }
+        self.is_connected = connected;
+        let state = if connected { ConnectionState::Connected } else { ConnectionState::Disconnected };
+        // Sending fails only when nobody is subscribed.
+        let _ = self.events.send(ConnectionEvent { state, at: Utc::now(), reason: reason.to_string() });
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    /// When a query last got an answer.
+    pub fn last_query_at(&self) -> Option<DateTime<Utc>> {
+        self.last_query_at
+    }
+
+    pub fn status(&self) -> PubqStatus {
+        PubqStatus {
+            socket_url: self.socket_url.clone(),
+            state: if self.is_connected { ConnectionState::Connected } else { ConnectionState::Disconnected },
+            host: self.host.clone(),
+            session_id: self.session_id.clone(),
+            next_request_id: self.next_id,
+            last_query_at: self.last_query_at,
+            last_error: self.last_error.clone(),
         }
     }
 
+    fn record_error(&mut self, error: &dyn std::error::Error) {
+        self.last_error = Some(PubqError { at: Utc::now(), message: error.to_string() });
+    }
+
+    #[instrument(skip(self))]
     pub async fn connect(&mut self, timeout : Duration) -> Result<(), Box<dyn std::error::Error>> {
         if self.is_connected {    
             return Ok(());
         }
 
+        let result = self.open(timeout).await;
+        let outcome = match (&result, self.has_connected) {
+            (Err(_), _) => "failed",
+            (Ok(()), true) => "reconnected",
+            (Ok(()), false) => "connected",
+        };
+        PUBQ_CONNECTIONS.with_label_values(&[outcome]).inc();
+        self.has_connected |= result.is_ok();
+        if let Err(er) = &result {
+            self.record_error(er.as_ref());
+        }
+        result
+    }
+
+    /// Closes the connection and opens a new one, e.g. to get onto another PubQ host.
+    #[instrument(skip(self))]
+    pub async fn reconnect(&mut self, timeout : Duration) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_connected(false, "reconnect");
+        self.shutdown(timeout).await;
+        self.connect(timeout).await
+    }
+
+    async fn open(&mut self, timeout : Duration) -> Result<(), Box<dyn std::error::Error>> {
         let (ws_stream, _) = tokio_tungstenite::connect_async(&self.socket_url).await?;
         self.stream = Some(ws_stream);
         self.next_id = 1;
+        self.decoder = FrameDecoder::new(self.max_message_size);
         let header = self.receive_message(timeout).await?;
         let header = serde_json::from_str::<MessageWrapper>(&header)?;
         match header {
-            MessageWrapper::Control(_) => {
-                self.is_connected = true;
-                return Ok(());
+            MessageWrapper::Control(Control::Header { header: host, session_id, .. }) => {
+                self.host = Some(host);
+                self.session_id = Some(session_id);
+                if let Some(provider) = self.auth.clone() {
+                    if let Err(er) = self.authenticate(provider.as_ref(), timeout).await {
+                        self.stream = None;
+                        return Err(er);
+                    }
+                }
+                self.set_connected(true, "connected");
+                Ok(())
             },
             _ => {
                 let error = "Expected control header message";
                 error!("{}", error);
-                return Err(error.into());
+                Err(error.into())
             }
         }
     }
 
+    async fn authenticate(&mut self, provider: &dyn TokenProvider, timeout : Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let credential = provider.credential().await
+            .map_err(|reason| AuthError::Failed { status: "no_token".to_string(), reason })?;
+        let request = MessageWrapper::Data(Data {
+            request_id: Some(self.next_id),
+            action: Some(match credential.kind {
+                TokenKind::Firebase => RequestAction::Auth,
+                TokenKind::Google => RequestAction::GoogleAuth,
+            }),
+            body: RequestBody { credential: Some(credential.token), ..Default::default() },
+        });
+        self.next_id += 1;
+        self.send(&request).await?;
+
+        let response = self.receive_data(timeout).await?;
+        if response.body.status == Some(RequestStatus::Ok) {
+            info!("Authenticated with PubQ");
+            return Ok(());
+        }
+        let (status, reason) = response.status_and_reason();
+        error!("PubQ rejected the token: {} {}", status, reason);
+        Err(Box::new(AuthError::Failed { status, reason }))
+    }
+
+    /// Drops the connection after the server revoked its authentication.
+    fn revoked(&mut self, data: &Data) -> AuthError {
+        let (status, reason) = data.status_and_reason();
+        warn!("PubQ revoked the authentication: {} {}", status, reason);
+        self.set_connected(false, "auth revoked");
+        self.stream = None;
+        let error = AuthError::Revoked { status, reason };
+        self.record_error(&error);
+        error
+    }
+
+    async fn send(&mut self, request: &MessageWrapper) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(stream) = &mut self.stream else {
+            error!("Failed to send request: not connected");
+            return Err("Not connected".into());
+        };
+        let request_text = serde_json::to_string(request)?;
+        if let Err(e) = stream.send(Message::Text(request_text.into())).await {
+            // Treat as disconnected (send failed). Clean up stream and return error.
+            self.set_connected(false, "send failed");
+            self.stream = None;
+            error!("Failed to send request: {}", e);
+            return Err(Box::new(e));
+        }
+        Ok(())
+    }
+
+    /// Sends a close frame and waits up to `timeout` for the server to close the connection.
+    #[instrument(skip(self))]
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        self.set_connected(false, "shutdown");
+        let Some(mut stream) = self.stream.take() else { return };
+        let close = async {
+            stream.close(None).await?;
+            // The server answers with its own close frame, after which the stream ends.
+            while stream.try_next().await?.is_some() {}
+            Ok::<(), tokio_tungstenite::tungstenite::Error>(())
+        };
+        match tokio::time::timeout(timeout, close).await {
+            Ok(Ok(())) | Ok(Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)) => info!("Closed the PubQ connection."),
+            Ok(Err(er)) => warn!("Error while closing the PubQ connection: {:?}", er),
+            Err(_) => warn!("PubQ did not close the connection within {:?}.", timeout),
+        }
+    }
+
     async fn receive_message(&mut self, timeout : Duration) -> Result<String, Box<dyn std::error::Error>> {
-        if let Some(stream) = &mut self.stream {
-            select! {
-                _ = tokio::spawn(async move { tokio::time::sleep(timeout).await }) => {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Some(stream) = &mut self.stream {
+            let msg = match tokio::time::timeout_at(deadline, stream.try_next()).await {
+                Err(_) => {
+                    PUBQ_RECEIVE_TIMEOUTS.inc();
                     warn!("Timeout reached while waiting for messages.");
+                    break;
                 },
-                msg = stream.try_next() =>  {
-                    let msg = match msg {
-                        Ok(Some(m)) => m,
-                        Ok(None) => {
-                            // Stream closed (EOF). Clean up state and return an error so callers know.
-                            self.is_connected = false;
-                            // Drop the stream by taking it out of the option to avoid holding a mutable borrow.
-                            self.stream = None;
-                            info!("WebSocket connection closed by server.");
-                            return Err("WebSocket closed (EOF)".into());
-                        }
-                        Err(e) => {
-                            // On underlying error, mark disconnected and drop stream.
-                            self.is_connected = false;
-                            self.stream = None;
-                            error!("Error receiving message: {:?}", e);
-                            return Err(Box::new(e));
-                        }
-                    };
-                    if msg.is_text() {
-                        let text = msg.into_text()?;
-                        return Ok(text.to_string());
-                    }
-                    else {
-                        warn!("Received non-text message: {:?}", msg);
-                    }
+                Ok(Ok(Some(m))) => m,
+                Ok(Ok(None)) => {
+                    // Stream closed (EOF). Clean up state and return an error so callers know.
+                    self.set_connected(false, "closed by server");
+                    self.stream = None;
+                    info!("WebSocket connection closed by server.");
+                    return Err("WebSocket closed (EOF)".into());
                 }
+                Ok(Err(e)) => {
+                    // On underlying error, mark disconnected and drop stream.
+                    self.set_connected(false, "receive failed");
+                    self.stream = None;
+                    error!("Error receiving message: {:?}", e);
+                    return Err(Box::new(e));
+                }
+            };
+            match msg {
+                Message::Text(text) => return Ok(text.to_string()),
+                // Late pongs from a keepalive, tungstenite answers pings itself.
+                Message::Ping(_) | Message::Pong(_) => debug!("Skipping control frame {:?}", msg),
+                _ => warn!("Received non-text message: {:?}", msg),
             }
         }
         warn!("No message received within timeout.");
         Err("No message received".into())
     }
 
-    async fn handle_response(&mut self, timeout : Duration) -> Result<MessageWrapper, Box<dyn std::error::Error>> {
-        let mut response_text = self.receive_message(timeout).await?;
-        // Can it be parsed as integer?
-        if let Some(num_chunks) = response_text.trim().parse::<u64>().ok() {
-            // Message is a sent in the number of chunks to follow.
-            let mut full_message = String::new();
-            for _ in 0..num_chunks {
-                let chunk = self.receive_message(timeout).await?;
-                full_message.push_str(&chunk);
+    /// Sends a WebSocket ping and waits up to `timeout` for the pong.
+    /// Without a pong the connection is dropped, as it is likely half-open.
+    #[instrument(skip(self))]
+    pub async fn ping(&mut self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(stream) = &mut self.stream else { return Err("Not connected".into()) };
+        let pong = async {
+            stream.send(Message::Ping("keepalive".into())).await?;
+            while let Some(msg) = stream.try_next().await? {
+                match msg {
+                    Message::Pong(_) => return Ok(None),
+                    Message::Text(text) => match serde_json::from_str::<MessageWrapper>(&text) {
+                        Ok(MessageWrapper::Data(data)) if data.action == Some(RequestAction::AuthRevoked) => return Ok(Some(data)),
+                        _ => warn!("Ignoring message while waiting for pong: {}", text),
+                    },
+                    _ => warn!("Ignoring message while waiting for pong: {:?}", msg),
+                }
             }
-            response_text = full_message;
-        }
-        
-        let response = serde_json::from_str::<MessageWrapper>(&response_text)?;
-        let response = match response {
-            MessageWrapper::Data(data) => data,
-            _ => {
-                error!("Expected data message, got this instead: {:?}", response);
-                return Err("Expected data message".into());
-            },
+            Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)
         };
-
-        let status_text = self.receive_message(timeout).await?;
-        let status = serde_json::from_str::<MessageWrapper>(&status_text)?;
-        let status = match status {
-            MessageWrapper::Data(data) => data,
-            _ => {
-                error!("Expected data message, got this instead: {:?}", status);
-                return Err("Expected data message".into());
-            },
+        let error = match tokio::time::timeout(timeout, pong).await {
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Ok(Some(revocation))) => return Err(Box::new(self.revoked(&revocation))),
+            Ok(Err(er)) => format!("ping failed: {}", er),
+            Err(_) => format!("no pong within {:?}", timeout),
         };
-        
-        if status.body.status.is_some() {
-            if status.body.status == Some(RequestStatus::Ok) {
-                return Ok(MessageWrapper::Data(response));
-            } else {
-                error!("Request failed with status: {:?}", status.body.status);
-                return Err("Request failed".into());
+        warn!("PubQ connection lost, {}", error);
+        self.last_error = Some(PubqError { at: Utc::now(), message: error.clone() });
+        self.set_connected(false, &error);
+        self.stream = None;
+        Err(error.into())
+    }
+
+    /// Receives the next message. Messages sent in several frames are parsed while the frames arrive.
+    async fn receive_full_message(&mut self, timeout : Duration) -> Result<MessageWrapper, Box<dyn std::error::Error>> {
+        // A message left unfinished, e.g. by a cancelled query, puts the stream out of step.
+        if let Some(truncated) = self.decoder.abort() {
+            return Err(Box::new(self.protocol_error(truncated)));
+        }
+        loop {
+            let frame = self.receive_message(timeout).await?;
+            match self.decoder.decode(&frame) {
+                Ok(Decoded::Message(text)) => return Ok(serde_json::from_str(text)?),
+                Ok(Decoded::KeepAlive) => debug!("Received keepalive frame"),
+                Ok(Decoded::Started { count }) => return self.receive_frames(count, timeout).await,
+                Ok(Decoded::Part { .. }) => unreachable!("Parts only follow a frame count"),
+                Err(er) => return Err(Box::new(self.protocol_error(er))),
             }
         }
-
-        error!("No status found in response messages");
-        Err("No status found in response".into())
     }
 
-    pub async fn get_vendors(&mut self, timeout: Duration) -> Result<Value, Box<dyn std::error::Error>> {
-        if let Some(stream) = &mut self.stream {
-            let request = MessageWrapper::Data(Data {
-                request_id: Some(self.next_id),
-                action: Some(RequestAction::Query),
-                body: RequestBody {
-                    path: Some("/clientUnits/compassdk_danskebank/all".to_string()),
-                    hash: Some("".to_string()),
-                    ..Default::default()
-                }
-            });
-            let request_text = serde_json::to_string(&request)?;
-            match stream.send(tokio_tungstenite::tungstenite::Message::Text(request_text.into())).await {
-                Err(e) => {
-                    // Treat as disconnected (send failed). Clean up stream and return error.
-                    self.is_connected = false;
-                    self.stream = None;
-                    error!("Failed to send get_vendors request {}", e);
-                },
-                Ok(()) => {}
-            }
-            self.next_id += 1;
-            let response = self.handle_response(timeout).await?;
-            let mut vendors_opt: Option<Value> = None;
-            println!("Response from get_vendors: {:?}", response);
-            match response {
-                MessageWrapper::Data(data) => {
-                    if data.action == Some(RequestAction::Data) {
-                        vendors_opt = data.body.data;
-                    }
+    async fn receive_frames(&mut self, count: usize, timeout : Duration) -> Result<MessageWrapper, Box<dyn std::error::Error>> {
+        let (frames, reader) = FrameReader::new();
+        let parser = tokio::task::spawn_blocking(move || serde_json::from_reader::<_, MessageWrapper>(reader));
+        let mut size = 0;
+        for _ in 0..count {
+            let frame = match self.receive_message(timeout).await {
+                Ok(frame) => frame,
+                Err(er) => match self.decoder.abort() {
+                    Some(truncated) => return Err(Box::new(self.protocol_error(truncated))),
+                    None => return Err(er),
                 },
-                m => {
-                    error!("Expected data response: {:?}", m);
-                    return Err("Expected data message".into())
+            };
+            match self.decoder.decode(&frame) {
+                Ok(Decoded::Part { text, .. }) => {
+                    size += text.len();
+                    // Fails only when the parser already gave up, its error is returned below.
+                    let _ = frames.send(text.as_bytes().to_vec());
                 },
+                Ok(decoded) => unreachable!("Expected a part, the decoder returned {:?}", decoded),
+                Err(er) => return Err(Box::new(self.protocol_error(er))),
             }
-
-            return vendors_opt.ok_or("No vendors data found".into());
         }
+        drop(frames);
+        PUBQ_CHUNKED_MESSAGES.inc();
+        PUBQ_CHUNKED_MESSAGE_BYTES.observe(size as f64);
+        Ok(parser.await??)
+    }
 
-        error!("Failed to get vendors: not connected");
-        Err("Failed to get vendors".into())
+    /// Drops the connection, as the frames that follow a framing error cannot be told apart.
+    fn protocol_error(&mut self, error: FrameError) -> FrameError {
+        error!("PubQ protocol error: {}", error);
+        self.set_connected(false, "protocol error");
+        self.stream = None;
+        self.decoder.abort();
+        error
     }
 
-    pub async fn get_vender_menu(&mut self, vendor_route: &str, timeout: Duration) -> Result<Value, Box<dyn std::error::Error>> {
-        if let Some(stream) = &mut self.stream {
-            let path = format!("/Clients/{}/activeMenu/categories", vendor_route);
-            let request = MessageWrapper::Data(Data {
-                request_id: Some(self.next_id),
-                action: Some(RequestAction::Query),
-                body: RequestBody {
-                    path: Some(path),
-                    hash: Some("".to_string()),
-                    ..Default::default()
+    async fn receive_data(&mut self, timeout : Duration) -> Result<Data, Box<dyn std::error::Error>> {
+        match self.receive_full_message(timeout).await? {
+            MessageWrapper::Data(data) if data.action == Some(RequestAction::AuthRevoked) => Err(Box::new(self.revoked(&data))),
+            MessageWrapper::Data(data) => Ok(data),
+            message => {
+                error!("Expected data message, got this instead: {:?}", message);
+                Err("Expected data message".into())
+            },
+        }
+    }
+
+    /// Reads the answer to query `request_id` of `path`: the data, which is left out when the sent hash matched, and then the status.
+    /// Statuses of other requests and data of other paths, e.g. changes pushed for earlier queries, are skipped.
+    async fn handle_response(&mut self, request_id: u64, path: &str, timeout : Duration) -> Result<Option<Data>, Box<dyn std::error::Error>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut response = None;
+        let status = loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                return Err(format!("No response to request {} within {:?}", request_id, timeout).into());
+            }
+            let data = self.receive_data(remaining).await?;
+            if data.body.status.is_some() {
+                if data.request_id == Some(request_id) {
+                    break data;
                 }
-            });
-            let request_text = serde_json::to_string(&request)?;
-            match stream.send(tokio_tungstenite::tungstenite::Message::Text(request_text.into())).await {
-                Err(_) => {
-                    // Treat as disconnected (send failed). Clean up stream and return error.
-                    self.is_connected = false;
-                    self.stream = None;
-                    error!("Failed to send get_vender_menu request");
-                },
-                Ok(()) => {}  
-            };
-            
-            self.next_id += 1;
-            let response = self.handle_response(timeout).await?;
-            let mut menu_opt: Option<Value> = None;
-            match response {
-                MessageWrapper::Data(data) => {
-                    if data.action == Some(RequestAction::Data) {
-                        menu_opt = data.body.data;
-                    }
-                },
-                m => { 
-                    error!("Expected data response : {:?}", m);
-                    return Err("Expected data message".into()) 
-                },
+                debug!("Skipping status of request {:?}", data.request_id);
+            } else if data.action == Some(RequestAction::Data) && data.body.path.as_deref().is_some_and(|data_path| same_path(data_path, path)) {
+                response = Some(data);
+            } else {
+                debug!("Skipping data of {:?}", data.body.path);
             }
-            return menu_opt.ok_or("No menu data found".into());
+        };
+
+        if status.body.status == Some(RequestStatus::Ok) {
+            Ok(response)
+        } else {
+            error!("Request failed with status: {:?}", status.body.status);
+            Err("Request failed".into())
+        }
+    }
+
+    /// Queries `path` and deserializes the data found there. A missing path is `null`, so ask for an `Option` to allow it.
+    /// `hash` is that of the data the caller already has, see `data_hash`, or empty to always get the data.
+    /// When the data at `path` still has that hash, it is not sent again and the result is `Unchanged`.
+    #[instrument(skip(self, options))]
+    pub async fn query<T: DeserializeOwned>(&mut self, path: &str, options: &QueryOptions, hash: &str, timeout: Duration) -> Result<Fetched<T>, Box<dyn std::error::Error>> {
+        let response = self.request(path, options, hash, timeout).await;
+        if let Err(er) = &response {
+            self.record_error(er.as_ref());
+        }
+        let Some(data) = response? else {
+            if hash.is_empty() {
+                return Ok(Fetched::Changed { data: serde_json::from_value(Value::Null)?, hash: String::new() });
+            }
+            return Ok(Fetched::Unchanged);
+        };
+        let new_hash = data_hash(&data);
+        if !hash.is_empty() && new_hash == hash {
+            return Ok(Fetched::Unchanged);
         }
+        let data = if options.shallow { shallow(data) } else { data };
+        Ok(Fetched::Changed { data: serde_json::from_value(data)?, hash: new_hash })
+    }
+
+    /// Sends a query and returns the data, or `None` when the server sent none because `hash` matched.
+    async fn request(&mut self, path: &str, options: &QueryOptions, hash: &str, timeout: Duration) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        let request_id = self.next_id;
+        let query = (!options.is_empty()).then(|| options.clone());
+        let request = MessageWrapper::Data(Data {
+            request_id: Some(request_id),
+            action: Some(RequestAction::Query),
+            body: RequestBody {
+                path: Some(path.to_string()),
+                hash: Some(hash.to_string()),
+                query: query.clone(),
+                ..Default::default()
+            }
+        });
+        self.next_id += 1;
+        self.send(&request).await?;
+        let response = self.handle_response(request_id, path, timeout).await?;
+
+        // Queries keep listening, so stop before changes are pushed. Its status is skipped by the next response.
+        let unlisten = MessageWrapper::Data(Data {
+            request_id: Some(self.next_id),
+            action: Some(RequestAction::Unlisten),
+            body: RequestBody { path: Some(path.to_string()), query, ..Default::default() },
+        });
+        self.next_id += 1;
+        if let Err(er) = self.send(&unlisten).await {
+            warn!("Failed to stop listening to {}: {}", path, er);
+        }
+
+        let data = match response {
+            Some(data) if data.action == Some(RequestAction::Data) => Some(data.body.data.unwrap_or(Value::Null)),
+            Some(data) => {
+                error!("Expected data response: {:?}", data);
+                return Err("Expected data message".into())
+            },
+            None => None,
+        };
+        self.last_query_at = Some(Utc::now());
+        Ok(data)
+    }
+
+    /// The vendor list, unless it still has `hash`.
+    #[instrument(skip(self))]
+    pub async fn get_vendors(&mut self, hash: &str, timeout: Duration) -> Result<Fetched<Value>, Box<dyn std::error::Error>> {
+        let vendors = self.query(&format!("/clientUnits/{}/all", SITE), &QueryOptions::default(), hash, timeout).await?;
+        Ok(vendors.required("No vendors data found")?)
+    }
+
+    /// The menu of a vendor, unless it still has `hash`.
+    #[instrument(skip(self))]
+    pub async fn get_vender_menu(&mut self, vendor_route: &str, hash: &str, timeout: Duration) -> Result<Fetched<Value>, Box<dyn std::error::Error>> {
+        let menu = self.query(&format!("/Clients/{}/activeMenu/categories", vendor_route), &QueryOptions::default(), hash, timeout).await?;
+        Ok(menu.required("No menu data found")?)
+    }
+
+}
+
+/// Data from `PubqClient::query`.
+#[derive(Debug, PartialEq)]
+pub enum Fetched<T> {
+    Changed { data: T, hash: String },
+    /// The data still has the hash that was sent.
+    Unchanged,
+}
 
-        error!("Connection failure when getting vendor menu");
-        Err("Connection failure".into())
+impl<T> Fetched<Option<T>> {
+    fn required(self, error: &'static str) -> Result<Fetched<T>, &'static str> {
+        match self {
+            Fetched::Changed { data: Some(data), hash } => Ok(Fetched::Changed { data, hash }),
+            Fetched::Changed { data: None, .. } => Err(error),
+            Fetched::Unchanged => Ok(Fetched::Unchanged),
+        }
     }
+}
+
+/// Whether two paths are the same, as the server leaves out the leading slash.
+fn same_path(a: &str, b: &str) -> bool {
+    a.trim_matches('/') == b.trim_matches('/')
+}
 
+/// Replaces the children of `data` with `true`, like the `shallow` parameter of the Firebase REST API.
+fn shallow(data: Value) -> Value {
+    match data {
+        Value::Object(children) => Value::Object(children.into_iter().map(|(key, _)| (key, Value::Bool(true))).collect()),
+        Value::Array(children) => Value::Object((0..children.len()).map(|index| (index.to_string(), Value::Bool(true))).collect()),
+        other => other,
+    }
 }
 
 impl Drop for PubqClient {
     fn drop(&mut self) {
-        info!("Dropping PubqClient and closing connection.");
-        if let Some(mut s) = self.stream.take()
-        {
-            _ = s.close(None);
+        // A close frame can only be sent from async code, see `shutdown`.
+        if self.stream.is_some() {
+            warn!("Dropping PubqClient without shutdown, the connection is closed without a close frame.");
         }
     }
 }
@@ -295,6 +696,303 @@ impl Drop for PubqClient {
 mod tests {
 
     use super::*;
+    use std::collections::HashMap;
+
+    /// A local stand-in for PubQ that sends the header and, if `read` is set, reads until the connection closes.
+    /// It returns whether a close frame arrived. Without reading, pings are never answered.
+    async fn fake_server(read: bool) -> (String, tokio::task::JoinHandle<bool>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(socket).await.unwrap();
+            let header = r#"{"t":"c","d":{"t":"h","d":{"ts":1763912344687,"v":"5","h":"localhost","s":"session"}}}"#;
+            stream.send(Message::Text(header.into())).await.unwrap();
+            if !read {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                return false;
+            }
+            let mut closed = false;
+            while let Ok(Some(message)) = stream.try_next().await {
+                closed |= message.is_close();
+            }
+            closed
+        });
+        (format!("ws://{}", address), server)
+    }
+
+    async fn connected_client(socket_url: String) -> PubqClient {
+        let mut client = PubqClient::new();
+        client.socket_url = socket_url;
+        client.connect(Duration::from_secs(5)).await.unwrap();
+        client
+    }
+
+    #[rocket::async_test]
+    async fn shutdown_sends_close_frame() {
+        let (socket_url, server) = fake_server(true).await;
+        let mut client = connected_client(socket_url).await;
+        assert!(client.is_connected());
+        client.shutdown(Duration::from_secs(5)).await;
+
+        assert!(!client.is_connected());
+        assert!(server.await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn ping_gets_pong() {
+        let (socket_url, _server) = fake_server(true).await;
+        let mut client = connected_client(socket_url).await;
+        client.ping(Duration::from_secs(5)).await.unwrap();
+        assert!(client.is_connected());
+    }
+
+    #[rocket::async_test]
+    async fn status_reports_the_session() {
+        let (socket_url, _server) = fake_server(false).await;
+        let mut client = connected_client(socket_url).await;
+        let status = client.status();
+        assert_eq!(status.state, ConnectionState::Connected);
+        assert_eq!((status.host.as_deref(), status.session_id.as_deref()), (Some("localhost"), Some("session")));
+        assert_eq!(status.next_request_id, 1);
+
+        assert!(client.ping(Duration::from_millis(200)).await.is_err());
+        assert!(client.status().last_error.unwrap().message.contains("no pong"));
+    }
+
+    #[rocket::async_test]
+    async fn missed_pong_disconnects() {
+        let (socket_url, _server) = fake_server(false).await;
+        let mut client = PubqClient::new();
+        client.socket_url = socket_url;
+        let mut events = client.subscribe();
+        client.connect(Duration::from_secs(5)).await.unwrap();
+
+        assert!(client.ping(Duration::from_millis(200)).await.is_err());
+        assert!(!client.is_connected());
+        assert_eq!(events.try_recv().unwrap().state, ConnectionState::Connected);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.state, ConnectionState::Disconnected);
+        assert!(event.reason.contains("no pong"));
+    }
+
+    /// A local stand-in for PubQ that answers one query with `data`, after a change pushed for another path
+    /// and the status of an earlier request, and returns the query it got and the message sent after it.
+    /// With `skip_unchanged`, it leaves out the data when the query has its hash, like Firebase.
+    async fn query_server(data: Value, skip_unchanged: bool) -> (String, tokio::task::JoinHandle<(Value, Value)>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(socket).await.unwrap();
+            let header = r#"{"t":"c","d":{"t":"h","d":{"ts":1763912344687,"v":"5","h":"localhost","s":"session"}}}"#;
+            stream.send(Message::Text(header.into())).await.unwrap();
+            let request: Value = loop {
+                if let Message::Text(text) = stream.try_next().await.unwrap().unwrap() {
+                    break serde_json::from_str(&text).unwrap();
+                }
+            };
+            // Like Firebase, the data path is sent without the leading slash.
+            let path = request["d"]["b"]["p"].as_str().unwrap().trim_start_matches('/');
+            let response = serde_json::json!({"t": "d", "d": {"b": {"p": path, "d": data}, "a": "d"}});
+            let status = serde_json::json!({"t": "d", "d": {"r": request["d"]["r"], "b": {"s": "ok", "d": {}}}});
+            let other_push = serde_json::json!({"t": "d", "d": {"b": {"p": "Clients/other/activeMenu/categories", "d": "other"}, "a": "d"}});
+            let other_status = serde_json::json!({"t": "d", "d": {"r": 1000, "b": {"s": "ok", "d": {}}}});
+            stream.send(Message::Text("0".into())).await.unwrap();
+            stream.send(Message::Text(other_push.to_string().into())).await.unwrap();
+            stream.send(Message::Text(other_status.to_string().into())).await.unwrap();
+            if !(skip_unchanged && request["d"]["b"]["h"] == data_hash(&data)) {
+                // Like Firebase, large messages are sent as a frame count and then the frames.
+                let response: Vec<char> = response.to_string().chars().collect();
+                let frames: Vec<String> = response.chunks(crate::frame_decoder::MAX_FRAME_SIZE).map(|frame| frame.iter().collect()).collect();
+                if frames.len() > 1 {
+                    stream.send(Message::Text(frames.len().to_string().into())).await.unwrap();
+                }
+                for frame in frames {
+                    stream.send(Message::Text(frame.into())).await.unwrap();
+                }
+            }
+            stream.send(Message::Text(status.to_string().into())).await.unwrap();
+            let next = loop {
+                match stream.try_next().await {
+                    Ok(Some(Message::Text(text))) => break serde_json::from_str(&text).unwrap_or(Value::Null),
+                    Ok(Some(_)) => continue,
+                    _ => break Value::Null,
+                }
+            };
+            (request, next)
+        });
+        (format!("ws://{}", address), server)
+    }
+
+    #[rocket::async_test]
+    async fn query_sends_options_and_deserializes_result() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Vendor {
+            name: String,
+        }
+
+        let (socket_url, server) = query_server(serde_json::json!({"a": {"name": "Dhaba"}, "b": {"name": "Grød"}}), true).await;
+        let mut client = connected_client(socket_url).await;
+        let options = QueryOptions { order_by: Some("name".to_string()), start_at: Some("D".into()), limit: Some(2), view_from: Some(ViewFrom::First), ..Default::default() };
+        let Fetched::Changed { data: vendors, hash } = client.query::<HashMap<String, Vendor>>("/Clients", &options, "", Duration::from_secs(5)).await.unwrap() else {
+            panic!("Expected data");
+        };
+
+        assert_eq!(vendors["a"], Vendor { name: "Dhaba".to_string() });
+        assert_eq!(hash, data_hash(&serde_json::json!({"a": {"name": "Dhaba"}, "b": {"name": "Grød"}})));
+        assert!(client.last_query_at().is_some());
+        let (request, unlisten) = server.await.unwrap();
+        assert_eq!(request["d"]["b"], serde_json::json!({"p": "/Clients", "h": "", "q": {"i": "name", "sp": "D", "l": 2, "vf": "l"}}));
+        assert_eq!(unlisten["d"]["a"], "n");
+        assert_eq!(unlisten["d"]["b"], serde_json::json!({"p": "/Clients", "q": {"i": "name", "sp": "D", "l": 2, "vf": "l"}}));
+        assert_ne!(unlisten["d"]["r"], request["d"]["r"]);
+    }
+
+    #[rocket::async_test]
+    async fn query_missing_path_is_none() {
+        let (socket_url, _server) = query_server(Value::Null, true).await;
+        let mut client = connected_client(socket_url).await;
+        let missing = client.query::<Option<Value>>("/Clients/unknown", &QueryOptions::default(), "", Duration::from_secs(5)).await.unwrap();
+        assert_eq!(missing, Fetched::Changed { data: None, hash: String::new() });
+    }
+
+    #[rocket::async_test]
+    async fn query_with_matching_hash_is_unchanged() {
+        let menu = serde_json::json!([{"items": [{"Name": "Today's hot dish", "Cost": 3500}], "name": "Sculpture Garden"}]);
+        let hash = data_hash(&menu);
+        for skip_unchanged in [true, false] {
+            let (socket_url, server) = query_server(menu.clone(), skip_unchanged).await;
+            let mut client = connected_client(socket_url).await;
+            let fetched = client.get_vender_menu("compassdk_dbvendor1", &hash, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(fetched, Fetched::Unchanged);
+            assert_eq!(server.await.unwrap().0["d"]["b"]["h"], hash);
+        }
+
+        let (socket_url, _server) = query_server(menu.clone(), true).await;
+        let mut client = connected_client(socket_url).await;
+        let fetched = client.get_vender_menu("compassdk_dbvendor1", "outdated", Duration::from_secs(5)).await.unwrap();
+        assert_eq!(fetched, Fetched::Changed { data: menu, hash });
+    }
+
+    /// A local stand-in for an authenticated PubQ database, serving `connections` connections one after the other.
+    /// Tokens starting with `token-` are accepted. The first query on a connection gets its authentication revoked.
+    /// Returns the tokens it got.
+    async fn auth_server(connections: usize) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut tokens = Vec::new();
+            for _ in 0..connections {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut stream = tokio_tungstenite::accept_async(socket).await.unwrap();
+                let header = r#"{"t":"c","d":{"t":"h","d":{"ts":1763912344687,"v":"5","h":"localhost","s":"session"}}}"#;
+                stream.send(Message::Text(header.into())).await.unwrap();
+                while let Ok(Some(message)) = stream.try_next().await {
+                    let Message::Text(text) = message else { continue };
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    let reply = match request["d"]["a"].as_str() {
+                        Some("auth") => {
+                            let token = request["d"]["b"]["cred"].as_str().unwrap().to_string();
+                            let status = if token.starts_with("token-") {
+                                serde_json::json!({"s": "ok", "d": {"auth": null, "expires": 1763915944}})
+                            } else {
+                                serde_json::json!({"s": "invalid_token", "d": "Could not parse auth token."})
+                            };
+                            tokens.push(token);
+                            serde_json::json!({"t": "d", "d": {"r": request["d"]["r"], "b": status}})
+                        },
+                        _ => serde_json::json!({"t": "d", "d": {"a": "ac", "b": {"s": "expired_token", "d": "Auth token is expired."}}}),
+                    };
+                    stream.send(Message::Text(reply.to_string().into())).await.unwrap();
+                }
+            }
+            tokens
+        });
+        (format!("ws://{}", address), server)
+    }
+
+    /// Hands out `token-1`, `token-2` and so on.
+    struct CountingTokens(std::sync::atomic::AtomicUsize);
+
+    #[rocket::async_trait]
+    impl TokenProvider for CountingTokens {
+        async fn credential(&self) -> Result<crate::pubq_auth::Credential, String> {
+            let count = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            Ok(crate::pubq_auth::Credential { token: format!("token-{}", count), kind: TokenKind::Firebase })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn rejected_token_fails_to_connect() {
+        let (socket_url, _server) = auth_server(1).await;
+        let token = crate::pubq_auth::StaticToken { token: "bad".to_string(), kind: TokenKind::Firebase };
+        let mut client = PubqClient::new().with_socket_url(&socket_url).with_auth(Arc::new(token));
+
+        let error = client.connect(Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<AuthError>(), Some(&AuthError::Failed { status: "invalid_token".to_string(), reason: "Could not parse auth token.".to_string() }));
+        assert!(!client.is_connected());
+    }
+
+    #[rocket::async_test]
+    async fn reauthenticates_after_revocation() {
+        let (socket_url, server) = auth_server(2).await;
+        let mut client = PubqClient::new().with_socket_url(&socket_url).with_auth(Arc::new(CountingTokens(Default::default())));
+        client.connect(Duration::from_secs(5)).await.unwrap();
+        assert!(client.is_connected());
+
+        let error = client.get_vendors("", Duration::from_secs(5)).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<AuthError>(), Some(AuthError::Revoked { status, .. }) if status == "expired_token"));
+        assert!(!client.is_connected());
+
+        client.connect(Duration::from_secs(5)).await.unwrap();
+        client.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(server.await.unwrap(), vec!["token-1", "token-2"]);
+    }
+
+    #[test]
+    fn can_serialize_auth_request() {
+        let request = MessageWrapper::Data(Data {
+            request_id: Some(1),
+            action: Some(RequestAction::GoogleAuth),
+            body: RequestBody { credential: Some("token".to_string()), ..Default::default() },
+        });
+        assert_eq!(serde_json::to_string(&request).unwrap(), r#"{"t":"d","d":{"r":1,"a":"gauth","b":{"cred":"token"}}}"#);
+    }
+
+    #[rocket::async_test]
+    async fn query_receives_large_messages_in_frames() {
+        let items: Vec<Value> = (0..500).map(|index| serde_json::json!({"Name": format!("Smørrebrød {}", index), "Cost": 3500 + index})).collect();
+        let menu = serde_json::json!([{"items": items, "name": "Hallernes"}]);
+        let (socket_url, _server) = query_server(menu.clone(), true).await;
+        let mut client = connected_client(socket_url).await;
+        let fetched = client.get_vender_menu("compassdk_dbvendor4", "", Duration::from_secs(5)).await.unwrap();
+        assert_eq!(fetched, Fetched::Changed { hash: data_hash(&menu), data: menu });
+    }
+
+    #[rocket::async_test]
+    async fn oversized_message_drops_the_connection() {
+        let menu = serde_json::json!([{"name": "x".repeat(40000)}]);
+        let (socket_url, _server) = query_server(menu, true).await;
+        let mut client = connected_client(socket_url).await.with_max_message_size(20000);
+        let error = client.get_vender_menu("compassdk_dbvendor4", "", Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<FrameError>(), Some(&FrameError::TooManyFrames { count: 3, max: 2 }));
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn shallow_keeps_only_keys() {
+        assert_eq!(shallow(serde_json::json!({"a": {"name": "Dhaba"}, "b": 1})), serde_json::json!({"a": true, "b": true}));
+        assert_eq!(shallow(serde_json::json!([{"name": "Dhaba"}])), serde_json::json!({"0": true}));
+        assert_eq!(shallow(serde_json::json!("text")), serde_json::json!("text"));
+    }
+
+    #[test]
+    fn query_without_options_sends_no_query() {
+        assert!(QueryOptions::default().is_empty());
+        assert!(QueryOptions { shallow: true, ..Default::default() }.is_empty());
+        assert!(!QueryOptions { limit: Some(1), ..Default::default() }.is_empty());
+    }
 
     #[test]
     fn can_parse_control_message() {
@@ -0,0 +1,316 @@
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Europe::Copenhagen;
+use rocket::futures::lock::Mutex;
+use rocket::futures::{stream, StreamExt};
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, instrument, warn};
+
+use crate::menu::{parse_menu, vendors, MenuFilter};
+use crate::menu_source::SourceRegistry;
+use crate::tags::Tagger;
+use crate::timeslots::{parse_enabled_slots, TimeslotProduct, TimeslotRequest};
+use crate::token::UserToken;
+use crate::vendor_order::VendorOrder;
+
+/// Picks remembered per caller, and the most days that can be avoided.
+const MAX_AVOID_DAYS: u32 = 30;
+
+/// Candidates asked about timeslots before giving up, each one is a request to the payments service.
+const MAX_TIMESLOT_CHECKS: usize = 8;
+
+/// Menus fetched at the same time while gathering candidates.
+const MENU_FETCH_CONCURRENCY: usize = 8;
+
+/// Callers remembered at once, those picked for least recently are forgotten first.
+const MAX_REMEMBERED_CALLERS: usize = 10_000;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RandomDish {
+    vendor: String,
+    vendor_name: String,
+    key: String,
+    name: String,
+    description: String,
+    /// Price in DKK.
+    price: f64,
+    image_url: String,
+    tags: Vec<String>,
+}
+
+/// Remembers which dishes each caller was given, so they can be avoided on the following days.
+#[derive(Default)]
+pub struct DishPicker {
+    given: HashMap<UserToken, Vec<(NaiveDate, String, String)>>,
+    /// The day callers that can no longer avoid anything were last forgotten.
+    expired_on: Option<NaiveDate>,
+}
+
+impl DishPicker {
+    /// Dishes given from `since` up to, but not including, `today`.
+    /// Today's pick is left out, so asking again with the same seed gives the same dish.
+    fn given_since(&self, token: &UserToken, since: NaiveDate, today: NaiveDate) -> Vec<(&str, &str)> {
+        self.given.get(token)
+            .map(|given| given.iter()
+                .filter(|(date, _, _)| *date >= since && *date < today)
+                .map(|(_, vendor, key)| (vendor.as_str(), key.as_str()))
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// The candidates not given to the caller in the last `days` days, or all of them if every one was.
+    fn avoid_given(&self, token: &UserToken, candidates: Vec<RandomDish>, days: u32, today: NaiveDate) -> Vec<RandomDish> {
+        let since = today - chrono::Duration::days(days.min(MAX_AVOID_DAYS) as i64);
+        let given = self.given_since(token, since, today);
+        let fresh: Vec<RandomDish> = candidates.iter()
+            .filter(|dish| !given.contains(&(dish.vendor.as_str(), dish.key.as_str())))
+            .cloned()
+            .collect();
+        if fresh.is_empty() {
+            info!("Every matching dish was given in the last {} days, picking among all of them", days);
+            candidates
+        } else {
+            fresh
+        }
+    }
+
+    /// Remembers the dish of the day for a caller, replacing an earlier pick of the same day.
+    fn remember(&mut self, token: UserToken, today: NaiveDate, dish: &RandomDish) {
+        let is_recent = |date: &NaiveDate| (today - *date).num_days() < MAX_AVOID_DAYS as i64;
+        if self.expired_on != Some(today) {
+            self.given.retain(|_, given| given.iter().any(|(date, _, _)| is_recent(date)));
+            self.expired_on = Some(today);
+        }
+        if !self.given.contains_key(&token) && self.given.len() >= MAX_REMEMBERED_CALLERS {
+            let oldest = self.given.iter()
+                .min_by_key(|(_, given)| given.iter().map(|(date, _, _)| *date).max())
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                self.given.remove(&oldest);
+            }
+        }
+
+        let given = self.given.entry(token).or_default();
+        given.retain(|(date, _, _)| is_recent(date) && *date != today);
+        given.push((today, dish.vendor.clone(), dish.key.clone()));
+    }
+}
+
+/// FNV-1a, so the same seed gives the same pick across restarts and builds.
+fn hash_seed(seed: &str, date: NaiveDate) -> u64 {
+    format!("{}/{}", seed, date).bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// SplitMix64, plenty for picking lunch.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Shuffles the candidates in an order that only depends on the seed and the candidates.
+fn shuffle(mut candidates: Vec<RandomDish>, seed: u64) -> Vec<RandomDish> {
+    candidates.sort_by(|a, b| (&a.vendor, &a.key).cmp(&(&b.vendor, &b.key)));
+    let mut state = seed;
+    for i in (1..candidates.len()).rev() {
+        let j = (next_random(&mut state) % (i as u64 + 1)) as usize;
+        candidates.swap(i, j);
+    }
+    candidates
+}
+
+/// Picks a random dish among today's available items.
+/// The same `seed` gives the same dish for everyone on the same day.
+/// With `avoid_days` and an `X-User-Token` header, dishes the caller got in that many days are skipped when possible.
+#[allow(clippy::too_many_arguments)]
+#[get("/random-dish?<seed>&<avoid_days>&<exclude_vendors>&<with_timeslot>&<filter..>")]
+#[instrument]
+pub async fn random_dish(
+    seed: Option<&str>,
+    avoid_days: Option<u32>,
+    exclude_vendors: Vec<String>,
+    with_timeslot: bool,
+    filter: MenuFilter,
+    token: Option<UserToken>,
+    sources: &State<Arc<SourceRegistry>>,
+    order: &State<Mutex<VendorOrder>>,
+    tagger: &State<Mutex<Tagger>>,
+    picker: &State<Mutex<DishPicker>>,
+) -> Result<Json<RandomDish>, (Status, String)> {
+    let today = Utc::now().with_timezone(&Copenhagen).date_naive();
+    let locations = sources.locations(&*order.lock().await).await?;
+    let listed: Vec<(String, String)> = vendors(&locations).into_iter()
+        .filter(|vendor| vendor.enabled != Some(false) && vendor.visible != Some(false) && !exclude_vendors.contains(&vendor.route_name))
+        .map(|vendor| (vendor.route_name.clone(), vendor.name.clone()))
+        .collect();
+    let menus: Vec<_> = stream::iter(listed)
+        .map(|(route_name, name)| async move {
+            let menu = sources.menu(&route_name).await;
+            (route_name, name, menu)
+        })
+        .buffer_unordered(MENU_FETCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut candidates = Vec::new();
+    for (route_name, vendor_name, menu) in menus {
+        let mut menu = match menu {
+            Ok(menu) => menu.value,
+            Err((_, er)) => {
+                warn!("Leaving {} out of the random dish: {}", route_name, er);
+                continue;
+            }
+        };
+        tagger.lock().await.annotate(&mut menu);
+        let Ok(menu) = parse_menu(&menu) else {
+            warn!("Leaving {} out of the random dish, unexpected menu format", route_name);
+            continue;
+        };
+        candidates.extend(menu.iter()
+            .flat_map(|category| category.items.iter())
+            .filter(|item| !item.key.is_empty() && item.is_available() && filter.matches(item))
+            .map(|item| RandomDish {
+                vendor: route_name.clone(),
+                vendor_name: vendor_name.clone(),
+                key: item.key.clone(),
+                name: item.name.clone(),
+                description: item.description.clone(),
+                price: item.cost as f64 / 100.0,
+                image_url: item.image_url.clone(),
+                tags: item.tags.clone(),
+            }));
+    }
+
+    if let (Some(token), Some(days)) = (&token, avoid_days) {
+        candidates = picker.lock().await.avoid_given(token, candidates, days, today);
+    }
+
+    let seed = match seed {
+        Some(seed) => hash_seed(seed, today),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_nanos() as u64).unwrap_or_default(),
+    };
+    let candidates = shuffle(candidates, seed);
+
+    let dish = if with_timeslot {
+        let mut found = None;
+        for dish in candidates.into_iter().take(MAX_TIMESLOT_CHECKS) {
+            let request = TimeslotRequest {
+                route_name: dish.vendor.clone(),
+                products: vec![TimeslotProduct { bong_category_id: 0, product_id: dish.key.clone(), product_name: dish.name.clone(), quantity: 1 }],
+            };
+            let slots = match sources.timeslots(&request).await.and_then(|timeslots_json| parse_enabled_slots(&timeslots_json)) {
+                Ok(slots) => slots,
+                Err((_, er)) => {
+                    warn!("Skipping {} of {} for the random dish, no timeslots: {}", dish.key, dish.vendor, er);
+                    continue;
+                }
+            };
+            if slots.iter().any(|slot| slot.date_naive() == today) {
+                found = Some(dish);
+                break;
+            }
+        }
+        found
+    } else {
+        candidates.into_iter().next()
+    };
+    let dish = dish.ok_or((Status::NotFound, "No dish matches the filters".to_string()))?;
+
+    if let Some(token) = token {
+        picker.lock().await.remember(token, today, &dish);
+    }
+    Ok(Json(dish))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn dish(vendor: &str, key: &str) -> RandomDish {
+        RandomDish {
+            vendor: vendor.to_string(),
+            vendor_name: vendor.to_string(),
+            key: key.to_string(),
+            name: key.to_string(),
+            description: String::new(),
+            price: 65.0,
+            image_url: String::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    fn dishes() -> Vec<RandomDish> {
+        vec![dish("a", "1"), dish("a", "2"), dish("b", "1"), dish("b", "2"), dish("c", "1")]
+    }
+
+    #[test]
+    fn same_seed_gives_same_order_regardless_of_input_order() {
+        let date = NaiveDate::from_ymd_opt(2025, 12, 10).unwrap();
+        let mut reversed = dishes();
+        reversed.reverse();
+        assert_eq!(shuffle(dishes(), hash_seed("team", date)), shuffle(reversed, hash_seed("team", date)));
+    }
+
+    #[test]
+    fn seed_changes_with_the_day() {
+        let monday = NaiveDate::from_ymd_opt(2025, 12, 8).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2025, 12, 9).unwrap();
+        assert_ne!(hash_seed("team", monday), hash_seed("team", tuesday));
+    }
+
+    #[test]
+    fn shuffle_keeps_every_candidate() {
+        let mut shuffled = shuffle(dishes(), 42);
+        shuffled.sort_by(|a, b| (&a.vendor, &a.key).cmp(&(&b.vendor, &b.key)));
+        assert_eq!(shuffled, dishes());
+    }
+
+    #[test]
+    fn remembers_given_dishes_per_token() {
+        let mut picker = DishPicker::default();
+        let token = UserToken("a".to_string());
+        let day = |day| NaiveDate::from_ymd_opt(2025, 12, day).unwrap();
+        picker.remember(token.clone(), day(8), &dish("a", "1"));
+        picker.remember(token.clone(), day(10), &dish("b", "2"));
+        assert_eq!(picker.given_since(&token, day(9), day(11)), vec![("b", "2")]);
+        assert_eq!(picker.given_since(&token, day(1), day(11)).len(), 2);
+        assert_eq!(picker.given_since(&token, day(1), day(10)), vec![("a", "1")]);
+        assert!(picker.given_since(&UserToken("b".to_string()), day(1), day(11)).is_empty());
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_dish_again_on_the_same_day() {
+        let mut picker = DishPicker::default();
+        let token = UserToken("a".to_string());
+        let today = NaiveDate::from_ymd_opt(2025, 12, 10).unwrap();
+        let pick = |picker: &DishPicker| shuffle(picker.avoid_given(&token, dishes(), 7, today), hash_seed("team", today)).remove(0);
+
+        let first = pick(&picker);
+        picker.remember(token.clone(), today, &first);
+        assert_eq!(pick(&picker), first);
+        picker.remember(token.clone(), today, &first);
+        assert_eq!(picker.given[&token].len(), 1);
+
+        let tomorrow = today.succ_opt().unwrap();
+        assert!(!picker.avoid_given(&token, dishes(), 7, tomorrow).contains(&first));
+    }
+
+    #[test]
+    fn forgets_callers_that_can_no_longer_avoid_anything() {
+        let mut picker = DishPicker::default();
+        let day = |day| NaiveDate::from_ymd_opt(2025, 12, day).unwrap();
+        picker.remember(UserToken("old".to_string()), day(1), &dish("a", "1"));
+        picker.remember(UserToken("new".to_string()), day(20), &dish("a", "1"));
+        assert_eq!(picker.given.len(), 2);
+        picker.remember(UserToken("new".to_string()), day(31), &dish("a", "2"));
+        assert_eq!(picker.given.keys().map(|token| token.0.as_str()).collect::<Vec<_>>(), vec!["new"]);
+    }
+}
@@ -0,0 +1,287 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::Deserialize;
+use rocket::{Data, Request};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{instrument, warn};
+
+/// Requests over the limit are rerouted here.
+const LIMITED_PATH: &str = "/api/rate-limited";
+
+/// Buckets kept before idle, full buckets are dropped. When that is not enough, the least recently used are.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Buckets kept after dropping the least recently used, so that it happens rarely.
+const BUCKETS_AFTER_EVICTION: usize = MAX_BUCKETS * 9 / 10;
+
+/// A token bucket: up to `burst` requests at once, refilled by `per_second` requests per second.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token if there is one, otherwise returns the seconds until there is.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), f64> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - self.tokens) / limit.per_second)
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() * limit.per_second >= limit.burst
+    }
+}
+
+/// Limits requests per client IP address. `X-User-Token` and forwarded headers are chosen by the client,
+/// so the address comes from the connection unless `ip_header` names the header set by a trusted proxy.
+/// Proxies append to `X-Forwarded-For`, so only its last address is from the trusted proxy.
+/// Limits apply to paths starting with a configured prefix, the longest matching prefix wins.
+pub struct RateLimiter {
+    limits: HashMap<String, RateLimit>,
+    ip_header: Option<String>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryAfter(u64);
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<String, RateLimit>, ip_header: Option<String>) -> Self {
+        RateLimiter { limits, ip_header, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn limit_for(&self, path: &str) -> Option<(&String, &RateLimit)> {
+        self.limits.iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+    }
+
+    /// Takes a token for `client` on `path`, or returns the seconds to wait.
+    fn check(&self, path: &str, client: &str, now: Instant) -> Result<(), f64> {
+        let Some((prefix, limit)) = self.limit_for(path) else { return Ok(()) };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let key = (prefix.clone(), client.to_string());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            self.evict(&mut buckets, now);
+        }
+        buckets.entry(key)
+            .or_insert(Bucket { tokens: limit.burst, updated: now })
+            .take(limit, now)
+    }
+
+    /// Drops full buckets, and then the least recently used until `BUCKETS_AFTER_EVICTION` are left.
+    fn evict(&self, buckets: &mut HashMap<(String, String), Bucket>, now: Instant) {
+        buckets.retain(|(prefix, _), bucket| self.limits.get(prefix).is_some_and(|limit| !bucket.is_full(limit, now)));
+        if buckets.len() > BUCKETS_AFTER_EVICTION {
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, &mut oldest_kept, _) = updated.select_nth_unstable(buckets.len() - BUCKETS_AFTER_EVICTION);
+            buckets.retain(|_, bucket| bucket.updated >= oldest_kept);
+            warn!("Rate limiter dropped recently used buckets, {} are left", buckets.len());
+        }
+    }
+
+    /// The client's IP address, from the trusted proxy's header when configured, otherwise from the connection.
+    fn client_key(&self, request: &Request<'_>) -> String {
+        let forwarded = self.ip_header.as_ref()
+            .and_then(|header| request.headers().get_one(header))
+            .and_then(|value| value.rsplit(',').next()?.trim().parse::<IpAddr>().ok());
+        forwarded.or_else(|| request.remote().map(|remote| remote.ip()))
+            .map(|ip| ip.to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info { name: "Rate limiter", kind: Kind::Request }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let client = self.client_key(request);
+        let path = request.uri().path().to_string();
+        if let Err(wait) = self.check(&path, &client, Instant::now()) {
+            warn!("Rate limited {} on {}", client, path);
+            request.local_cache(|| RetryAfter(wait.ceil() as u64));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(LIMITED_PATH).unwrap());
+        }
+    }
+}
+
+/// Responds with 429 and the `Retry-After` set by the rate limiter.
+pub struct TooManyRequests;
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let retry_after = request.local_cache(|| RetryAfter(1)).0;
+        Response::build_from(format!("Too many requests, retry in {} seconds", retry_after).respond_to(request)?)
+            .status(Status::TooManyRequests)
+            .header(Header::new("Retry-After", retry_after.to_string()))
+            .ok()
+    }
+}
+
+#[get("/rate-limited")]
+#[instrument]
+pub fn rate_limited() -> TooManyRequests {
+    TooManyRequests
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::local::blocking::Client;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(HashMap::from([
+            ("/api".to_string(), RateLimit { burst: 10.0, per_second: 5.0 }),
+            ("/api/timeslots".to_string(), RateLimit { burst: 2.0, per_second: 0.5 }),
+        ]), None)
+    }
+
+    #[test]
+    fn allows_burst_then_limits() {
+        let limiter = limiter();
+        let now = Instant::now();
+        assert!(limiter.check("/api/timeslots", "a", now).is_ok());
+        assert!(limiter.check("/api/timeslots", "a", now).is_ok());
+        assert_eq!(limiter.check("/api/timeslots", "a", now), Err(2.0));
+        assert!(limiter.check("/api/timeslots", "b", now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check("/api/timeslots", "a", now).unwrap();
+        limiter.check("/api/timeslots", "a", now).unwrap();
+        assert!(limiter.check("/api/timeslots", "a", now + Duration::from_secs(1)).is_err());
+        assert!(limiter.check("/api/timeslots", "a", now + Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn uses_longest_prefix() {
+        let limiter = limiter();
+        assert_eq!(limiter.limit_for("/api/timeslots").unwrap().1.burst, 2.0);
+        assert_eq!(limiter.limit_for("/api/menu/compassdk_dbvendor1").unwrap().1.burst, 10.0);
+        assert!(limiter.limit_for("/index.html").is_none());
+    }
+
+    #[get("/timeslots")]
+    fn timeslots() -> &'static str {
+        "[]"
+    }
+
+    fn client(ip_header: Option<&str>) -> Client {
+        let rocket = rocket::build()
+            .mount("/api", routes![timeslots, rate_limited])
+            .attach(RateLimiter::new(limiter().limits, ip_header.map(str::to_string)));
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn limits_by_address_not_by_token() {
+        let client = client(None);
+        let remote: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        for token in ["a", "b"] {
+            let response = client.get("/api/timeslots").remote(remote).header(Header::new("X-User-Token", token)).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+        let response = client.get("/api/timeslots").remote(remote)
+            .header(Header::new("X-User-Token", "c"))
+            .header(Header::new("X-Real-IP", "198.51.100.7"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client.get("/api/timeslots").remote("192.0.2.2:4000".parse().unwrap()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn trusts_the_configured_proxy_header() {
+        let client = client(Some("X-Forwarded-For"));
+        let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        // The client picks the addresses before the last one, which the proxy appended.
+        for spoofed in ["198.51.100.1", "198.51.100.2"] {
+            let response = client.get("/api/timeslots").remote(proxy).header(Header::new("X-Forwarded-For", format!("{}, 192.0.2.1", spoofed))).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        }
+        let response = client.get("/api/timeslots").remote(proxy).header(Header::new("X-Forwarded-For", "198.51.100.3, 192.0.2.1")).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = client.get("/api/timeslots").remote(proxy).header(Header::new("X-Forwarded-For", "192.0.2.1, 192.0.2.2")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn caps_the_number_of_buckets() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for client in 0..MAX_BUCKETS + 1 {
+            limiter.check("/api/timeslots", &client.to_string(), now + Duration::from_millis(client as u64)).unwrap();
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_BUCKETS);
+        assert!(buckets.contains_key(&("/api/timeslots".to_string(), MAX_BUCKETS.to_string())));
+        assert!(!buckets.contains_key(&("/api/timeslots".to_string(), "0".to_string())));
+    }
+
+    #[get("/vendors")]
+    fn vendors() -> &'static str {
+        "[]"
+    }
+
+    #[get("/menu/<_vendor>")]
+    fn menu(_vendor: &str) -> &'static str {
+        "[]"
+    }
+
+    #[post("/timeslots")]
+    fn post_timeslots() -> &'static str {
+        "[]"
+    }
+
+    #[test]
+    fn configured_limits_allow_a_page_load() {
+        use rocket::figment::providers::{Format, Toml};
+        // The board loads the vendors, the menu of each vendor and then the timeslots of every dish at once.
+        const VENDORS: usize = 20;
+        const DISHES_PER_VENDOR: usize = 8;
+        let limits: HashMap<String, RateLimit> = rocket::figment::Figment::from(Toml::file("Rocket.toml").nested())
+            .select("default")
+            .extract_inner("rate_limits")
+            .unwrap();
+        let rocket = rocket::build()
+            .mount("/api", routes![vendors, menu, post_timeslots, rate_limited])
+            .attach(RateLimiter::new(limits, None));
+        let client = Client::tracked(rocket).unwrap();
+        assert_eq!(client.get("/api/vendors").dispatch().status(), Status::Ok);
+        for vendor in 0..VENDORS {
+            assert_eq!(client.get(format!("/api/menu/vendor{}", vendor)).dispatch().status(), Status::Ok);
+        }
+        for _ in 0..VENDORS * DISHES_PER_VENDOR {
+            assert_eq!(client.post("/api/timeslots").dispatch().status(), Status::Ok);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use chrono::{DateTime, Utc};
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+use crate::menu::MenuCategory;
+use crate::menu_source::SourceRegistry;
+
+const DEFAULT_LIMIT: usize = 20;
+
+/// Field weights, a hit in the name counts more than one in the long description.
+const NAME_WEIGHT: f32 = 3.0;
+const NAME_INTERNAL_WEIGHT: f32 = 2.0;
+const CATEGORY_WEIGHT: f32 = 1.5;
+const DESCRIPTION_WEIGHT: f32 = 1.0;
+const DESCRIPTION_LONG_WEIGHT: f32 = 0.5;
+
+/// Score multipliers for how well a query term matched an indexed term.
+const EXACT_MATCH: f32 = 1.0;
+const PREFIX_MATCH: f32 = 0.7;
+const FUZZY_MATCH: f32 = 0.5;
+
+const STOP_WORDS: &[&str] = &[
+    "og", "med", "af", "til", "på", "paa", "i", "en", "et", "den", "det", "de", "eller", "fra",
+    "och", "av", "ett",
+    "and", "with", "of", "the", "or", "a", "an", "in", "on",
+];
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchHit {
+    vendor: String,
+    vendor_name: String,
+    key: String,
+    name: String,
+    description: String,
+    category: String,
+    /// Price in DKK.
+    price: f64,
+    enabled: bool,
+    score: f32,
+}
+
+/// A document is addressed by its vendor and position in that vendor's menu.
+type DocumentId = (String, usize);
+
+struct Document {
+    hit: SearchHit,
+    /// Terms of the item along with the weight of the best field they appeared in.
+    terms: HashMap<String, f32>,
+}
+
+/// Inverted index over the items of all menus that have been loaded, kept up to date by `SourceRegistry`.
+#[derive(Default)]
+pub struct SearchIndex {
+    /// Documents per vendor, along with when the menu they were built from last changed.
+    vendors: HashMap<String, (DateTime<Utc>, Vec<Document>)>,
+    /// Term to the documents containing it and the term weight.
+    postings: HashMap<String, Vec<(DocumentId, f32)>>,
+    document_count: usize,
+}
+
+impl SearchIndex {
+    /// Replaces the documents of a vendor, unless they were already built from this version of the menu.
+    /// Returns whether the vendor was (re)indexed.
+    pub fn update_vendor(&mut self, vendor: &str, vendor_name: &str, changed_at: DateTime<Utc>, menu: &[MenuCategory]) -> bool {
+        if self.is_indexed(vendor, changed_at) {
+            return false;
+        }
+
+        let documents = menu.iter()
+            .flat_map(|category| category.items.iter().map(move |item| (category, item)))
+            .map(|(category, item)| {
+                let fields = [
+                    (item.name.as_str(), NAME_WEIGHT),
+                    (item.name_internal.as_str(), NAME_INTERNAL_WEIGHT),
+                    (item.bong_category_label.as_str(), CATEGORY_WEIGHT),
+                    (category.name.as_str(), CATEGORY_WEIGHT),
+                    (item.description.as_str(), DESCRIPTION_WEIGHT),
+                    (item.description_long.as_str(), DESCRIPTION_LONG_WEIGHT),
+                ];
+                let mut terms: HashMap<String, f32> = HashMap::new();
+                for (text, weight) in fields {
+                    for term in analyze(text) {
+                        let entry = terms.entry(term).or_insert(0.0);
+                        *entry = entry.max(weight);
+                    }
+                }
+
+                Document {
+                    hit: SearchHit {
+                        vendor: vendor.to_string(),
+                        vendor_name: vendor_name.to_string(),
+                        key: item.key.clone(),
+                        name: item.name.clone(),
+                        description: item.description.clone(),
+                        category: category.name.clone(),
+                        price: item.cost as f64 / 100.0,
+                        enabled: item.enabled,
+                        score: 0.0,
+                    },
+                    terms,
+                }
+            })
+            .collect();
+        self.vendors.insert(vendor.to_string(), (changed_at, documents));
+        true
+    }
+
+    /// Whether the documents of a vendor were built from the menu that last changed at `changed_at`.
+    pub fn is_indexed(&self, vendor: &str, changed_at: DateTime<Utc>) -> bool {
+        self.vendors.get(vendor).is_some_and(|(indexed, _)| *indexed == changed_at)
+    }
+
+    /// Removes the documents of vendors that are no longer listed. Returns whether any were removed.
+    pub fn retain_vendors(&mut self, listed: &HashSet<&str>) -> bool {
+        let count = self.vendors.len();
+        self.vendors.retain(|vendor, _| listed.contains(vendor.as_str()));
+        self.vendors.len() != count
+    }
+
+    /// Rebuilds the postings from the documents of all vendors.
+    pub fn rebuild(&mut self) {
+        self.postings.clear();
+        self.document_count = 0;
+        for (vendor, (_, documents)) in &self.vendors {
+            for (position, document) in documents.iter().enumerate() {
+                for (term, weight) in &document.terms {
+                    self.postings.entry(term.clone()).or_default().push(((vendor.clone(), position), *weight));
+                }
+            }
+            self.document_count += documents.len();
+        }
+        info!("Search index rebuilt with {} items and {} terms", self.document_count, self.postings.len());
+    }
+
+    /// Returns the documents matching every term of the query, best match first.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let terms = analyze(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let document_count = self.document_count as f32;
+        let mut scores: HashMap<&DocumentId, f32> = HashMap::new();
+        for (index, term) in terms.iter().enumerate() {
+            let is_last = index == terms.len() - 1;
+            let mut term_scores: HashMap<&DocumentId, f32> = HashMap::new();
+            for (indexed_term, postings) in &self.postings {
+                let Some(quality) = match_quality(term, indexed_term, is_last) else { continue };
+                let idf = (1.0 + document_count / postings.len() as f32).ln();
+                for (document, weight) in postings {
+                    let score = term_scores.entry(document).or_insert(0.0);
+                    *score = score.max(weight * quality * idf);
+                }
+            }
+
+            if index == 0 {
+                scores = term_scores;
+            } else {
+                scores = scores.into_iter()
+                    .filter_map(|(document, score)| term_scores.get(&document).map(|term_score| (document, score + term_score)))
+                    .collect();
+            }
+        }
+
+        let mut hits: Vec<SearchHit> = scores.into_iter()
+            .map(|((vendor, position), score)| SearchHit { score, ..self.vendors[vendor].1[*position].hit.clone() })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Searches the menus that were loaded, the index is updated as menus load, see `SourceRegistry::menu`.
+#[get("/search?<q>&<limit>")]
+#[instrument]
+pub async fn search(q: &str, limit: Option<usize>, sources: &State<Arc<SourceRegistry>>) -> Json<Vec<SearchHit>> {
+    Json(sources.search(q, limit.unwrap_or(DEFAULT_LIMIT)).await)
+}
+
+/// Splits text into normalized search terms: lowercased, stemmed and with diacritics folded.
+fn analyze(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && !STOP_WORDS.contains(word))
+        .map(|word| fold_diacritics(&stem(word)))
+        .filter(|term| seen.insert(term.clone()))
+        .collect()
+}
+
+/// How well a query term matches an indexed term, if at all.
+fn match_quality(query: &str, indexed: &str, allow_prefix: bool) -> Option<f32> {
+    if query == indexed {
+        return Some(EXACT_MATCH);
+    }
+
+    let query_length = query.chars().count();
+    if allow_prefix && query_length >= 3 && indexed.starts_with(query) {
+        return Some(PREFIX_MATCH);
+    }
+
+    let max_distance = match query_length {
+        0..=3 => return None,
+        4..=7 => 1,
+        _ => 2,
+    };
+    if query_length.abs_diff(indexed.chars().count()) > max_distance {
+        return None;
+    }
+    (levenshtein(query, indexed) <= max_distance).then_some(FUZZY_MATCH)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Folds Danish and Swedish letters so "smorrebrod" finds "smørrebrød".
+fn fold_diacritics(word: &str) -> String {
+    let mut folded = String::with_capacity(word.len());
+    for c in word.chars() {
+        match c {
+            'æ' | 'ä' => folded.push_str("ae"),
+            'ø' | 'ö' => folded.push('o'),
+            'å' | 'à' | 'á' | 'â' => folded.push('a'),
+            'é' | 'è' | 'ê' | 'ë' => folded.push('e'),
+            'ü' | 'ú' => folded.push('u'),
+            'í' | 'ï' => folded.push('i'),
+            'ó' | 'ô' => folded.push('o'),
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y' | 'æ' | 'ø' | 'å' | 'ä' | 'ö')
+}
+
+/// Light version of the Snowball Danish stemmer: strips common inflection suffixes
+/// (`-erne`, `-ene`, `-er`, `-en`, `-et`, `-e`, `-s`, ...) and undoubles the final consonant.
+fn stem(word: &str) -> String {
+    const SUFFIXES: &[&str] = &[
+        "erendes", "erende", "hedens", "ethed", "erede", "heden", "heder", "endes", "ernes",
+        "erens", "erets", "ered", "ende", "erne", "eren", "erer", "heds", "enes", "eres",
+        "eret", "hed", "ene", "ere", "ens", "ers", "ets", "en", "er", "es", "et", "e",
+    ];
+    const S_ENDINGS: &str = "abcdfghjklmnoprtvyzå";
+
+    let chars: Vec<char> = word.chars().collect();
+    if chars.iter().any(|c| c.is_numeric()) {
+        return word.to_string();
+    }
+
+    // R1 starts after the first consonant following a vowel, but at least three letters in.
+    let r1 = chars.windows(2)
+        .position(|pair| is_vowel(pair[0]) && !is_vowel(pair[1]))
+        .map(|position| (position + 2).max(3))
+        .unwrap_or(chars.len());
+    if r1 >= chars.len() {
+        return word.to_string();
+    }
+
+    let mut stemmed: Vec<char> = chars.clone();
+    let region: String = chars[r1..].iter().collect();
+    if let Some(suffix) = SUFFIXES.iter().find(|suffix| region.ends_with(*suffix)) {
+        stemmed.truncate(chars.len() - suffix.chars().count());
+    } else if region.ends_with('s') && chars.len() >= 2 && S_ENDINGS.contains(chars[chars.len() - 2]) {
+        stemmed.pop();
+    }
+
+    // Undouble a final consonant, "grønne" -> "grønn" -> "grøn".
+    let length = stemmed.len();
+    if length > r1 && length >= 2 && stemmed[length - 1] == stemmed[length - 2] && !is_vowel(stemmed[length - 1]) {
+        stemmed.pop();
+    }
+    stemmed.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::menu::parse_menu;
+
+    fn index() -> SearchIndex {
+        let menu = parse_menu(&serde_json::json!({
+            "0": {"name": "Smørrebrød", "items": {
+                "0": {"key": "a", "Name": "Rugbrød med æg og rejer", "Description": "Håndpillede rejer", "Cost": 6500, "bongCategoryLabel": "Mad"},
+                "1": {"key": "b", "Name": "Kartoffelmad", "Description": "Nye kartofler, purløg og mayonnaise", "Cost": 5500}
+            }},
+            "1": {"name": "Drinks", "items": {
+                "0": {"key": "c", "Name": "Hyldeblomst saft", "DescriptionLong": "Hjemmelavet af friske blomster", "Cost": 2500}
+            }}
+        })).unwrap();
+        let mut index = SearchIndex::default();
+        assert!(index.update_vendor("vendor", "Hallernes", Utc::now(), &menu));
+        index.rebuild();
+        index
+    }
+
+    fn keys(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.key.as_str()).collect()
+    }
+
+    #[test]
+    fn stems_danish_inflections() {
+        assert_eq!(stem("kartoflerne"), stem("kartofler"));
+        assert_eq!(stem("rejerne"), stem("rejer"));
+        assert_eq!(stem("blomster"), "blomst");
+    }
+
+    #[test]
+    fn folds_diacritics() {
+        assert_eq!(analyze("Smørrebrød Æble Blåbær"), vec!["smorrebrod", "aebl", "blabaer"]);
+    }
+
+    #[test]
+    fn finds_items_by_inflected_and_folded_terms() {
+        let index = index();
+        assert_eq!(keys(&index.search("kartofler", 10)), vec!["b"]);
+        assert_eq!(keys(&index.search("rugbrod", 10)), vec!["a"]);
+        assert_eq!(keys(&index.search("blomsterne", 10)), vec!["c"]);
+    }
+
+    #[test]
+    fn tolerates_typos_and_prefixes() {
+        let index = index();
+        assert_eq!(keys(&index.search("kartofelmad", 10)), vec!["b"]);
+        assert_eq!(keys(&index.search("hylde", 10)), vec!["c"]);
+    }
+
+    #[test]
+    fn ranks_name_matches_above_description_matches() {
+        let index = index();
+        let hits = index.search("smørrebrød rejer", 10);
+        assert_eq!(keys(&hits), vec!["a"]);
+        let hits = index.search("mad", 10);
+        assert_eq!(keys(&hits)[0], "a");
+    }
+
+    #[test]
+    fn skips_unchanged_menus() {
+        let mut index = SearchIndex::default();
+        let changed_at = Utc::now();
+        assert!(index.update_vendor("vendor", "Vendor", changed_at, &[]));
+        assert!(!index.update_vendor("vendor", "Vendor", changed_at, &[]));
+    }
+
+    #[test]
+    fn drops_vendors_that_are_no_longer_listed() {
+        let mut index = index();
+        assert!(!index.retain_vendors(&HashSet::from(["vendor", "other"])));
+        assert_eq!(keys(&index.search("rejer", 10)), vec!["a"]);
+
+        assert!(index.retain_vendors(&HashSet::from(["other"])));
+        index.rebuild();
+        assert!(index.search("rejer", 10).is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method};
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+use rocket_cors::{AllowedOrigins, CorsOptions};
+use std::str::FromStr;
+use tracing::warn;
+
+/// Which other sites may call the API from a browser, from `[<profile>.cors]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins such as `https://food.example.com`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Not allowed together with `*`, since any site could then call the API with the user's cookies.
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].iter().map(|m| m.to_string()).collect(),
+            allow_credentials: false,
+        }
+    }
+}
+
+pub fn setup_cors(config: &CorsConfig) -> rocket_cors::Cors {
+    let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+    let allowed_origins = if any_origin {
+        AllowedOrigins::all()
+    } else {
+        AllowedOrigins::some_exact(&config.allowed_origins)
+    };
+    let allow_credentials = if any_origin && config.allow_credentials {
+        warn!("CORS credentials are not allowed together with any origin, disabling them");
+        false
+    } else {
+        config.allow_credentials
+    };
+    let allowed_methods = config.allowed_methods.iter()
+        .filter_map(|method| match rocket_cors::Method::from_str(method) {
+            Ok(method) => Some(method),
+            Err(_) => {
+                warn!("Ignoring unknown CORS method {}", method);
+                None
+            }
+        })
+        .collect();
+
+    CorsOptions {
+        allowed_origins,
+        allowed_methods,
+        allow_credentials,
+        ..Default::default()
+    }.to_cors().expect("Error creating CORS fairing")
+}
+
+/// Security headers for every response, from `[<profile>.security_headers]` in Rocket.toml.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+    /// `max-age` of `Strict-Transport-Security` in seconds, 0 leaves the header out, e.g. when not served over HTTPS.
+    pub hsts_max_age: u64,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            content_security_policy: [
+                "default-src 'self'",
+                "script-src 'self' 'unsafe-inline' https://analytics.homelab.soren.ranneries.com",
+                "style-src 'self' 'unsafe-inline'",
+                "img-src 'self' data: https://firebasestorage.googleapis.com",
+                "connect-src 'self' wss://*.firebaseio.com https://analytics.homelab.soren.ranneries.com",
+                "frame-ancestors 'self'",
+            ].join("; "),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            hsts_max_age: 31536000,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info { name: "Security headers", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // Preflight responses only need the CORS headers.
+        if request.method() == Method::Options {
+            return;
+        }
+        response.set_header(Header::new("Content-Security-Policy", self.content_security_policy.clone()));
+        response.set_header(Header::new("Referrer-Policy", self.referrer_policy.clone()));
+        response.set_header(Header::new("X-Content-Type-Options", "nosniff"));
+        if self.hsts_max_age > 0 {
+            response.set_header(Header::new("Strict-Transport-Security", format!("max-age={}; includeSubDomains", self.hsts_max_age)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    #[get("/")]
+    fn index() -> &'static str {
+        "OK"
+    }
+
+    fn client(cors: CorsConfig, headers: SecurityHeaders) -> Client {
+        let rocket = rocket::build()
+            .mount("/", routes![index])
+            .attach(setup_cors(&cors))
+            .attach(headers);
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn sets_security_headers() {
+        let client = client(CorsConfig::default(), SecurityHeaders::default());
+        let response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let headers = response.headers();
+        assert!(headers.get_one("Content-Security-Policy").unwrap().contains("img-src 'self' data: https://firebasestorage.googleapis.com"));
+        assert_eq!(headers.get_one("Referrer-Policy"), Some("strict-origin-when-cross-origin"));
+        assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(headers.get_one("Strict-Transport-Security"), Some("max-age=31536000; includeSubDomains"));
+    }
+
+    #[test]
+    fn leaves_out_hsts_when_disabled() {
+        let client = client(CorsConfig::default(), SecurityHeaders { hsts_max_age: 0, ..Default::default() });
+        let response = client.get("/").dispatch();
+        assert_eq!(response.headers().get_one("Strict-Transport-Security"), None);
+    }
+
+    #[test]
+    fn only_allows_configured_origins() {
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://food.example.com".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let client = client(cors, SecurityHeaders::default());
+
+        let allowed = client.get("/").header(Header::new("Origin", "https://food.example.com")).dispatch();
+        assert_eq!(allowed.headers().get_one("Access-Control-Allow-Origin"), Some("https://food.example.com"));
+        assert_eq!(allowed.headers().get_one("Access-Control-Allow-Credentials"), Some("true"));
+
+        let other = client.get("/").header(Header::new("Origin", "https://evil.example.com")).dispatch();
+        assert_eq!(other.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn never_allows_credentials_for_any_origin() {
+        let cors = CorsConfig { allow_credentials: true, ..Default::default() };
+        let client = client(cors, SecurityHeaders::default());
+        let response = client.get("/").header(Header::new("Origin", "https://evil.example.com")).dispatch();
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Credentials"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::time::Instant;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+use crate::{VendorCache, VenderMenuCache, CACHE_TTL};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry<T> {
+    fetched_at: DateTime<Utc>,
+    data: T,
+    #[serde(default)]
+    hash: String,
+    /// Missing in older snapshots, which then use `fetched_at`.
+    #[serde(default)]
+    changed_at: Option<DateTime<Utc>>,
+}
+
+/// The vendor and menu caches as saved at shutdown, so a restart can serve them without asking PubQ.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct CacheSnapshot {
+    vendors: Option<Entry<String>>,
+    menus: HashMap<String, Entry<serde_json::Value>>,
+}
+
+pub fn fetched_at(fetched: Instant) -> DateTime<Utc> {
+    Utc::now() - fetched.elapsed()
+}
+
+/// When an entry fetched at `fetched_at` is still fresh, the instant it was fetched.
+fn restore(fetched_at: DateTime<Utc>) -> Option<Instant> {
+    let age = (Utc::now() - fetched_at).to_std().ok()?;
+    if age >= CACHE_TTL {
+        return None;
+    }
+    Instant::now().checked_sub(age)
+}
+
+impl CacheSnapshot {
+    pub fn take(vendor_cache: &Option<VendorCache>, menu_cache: &VenderMenuCache) -> Self {
+        CacheSnapshot {
+            vendors: vendor_cache.as_ref().map(|cache| Entry { fetched_at: fetched_at(cache.0), data: cache.1.clone(), hash: cache.2.clone(), changed_at: Some(cache.3) }),
+            menus: menu_cache.0.iter()
+                .map(|(vendor, (fetched, menu, hash, changed_at))| {
+                    (vendor.clone(), Entry { fetched_at: fetched_at(*fetched), data: menu.clone(), hash: hash.clone(), changed_at: Some(*changed_at) })
+                })
+                .collect(),
+        }
+    }
+
+    /// The caches with the entries that are still fresh.
+    pub fn restore(self) -> (Option<VendorCache>, VenderMenuCache) {
+        let vendors = self.vendors.and_then(|entry| {
+            let changed_at = entry.changed_at.unwrap_or(entry.fetched_at);
+            restore(entry.fetched_at).map(|fetched| VendorCache(fetched, entry.data, entry.hash, changed_at))
+        });
+        let menus = self.menus.into_iter()
+            .filter_map(|(vendor, entry)| {
+                let changed_at = entry.changed_at.unwrap_or(entry.fetched_at);
+                restore(entry.fetched_at).map(|fetched| (vendor, (fetched, entry.data, entry.hash, changed_at)))
+            })
+            .collect();
+        (vendors, VenderMenuCache(menus))
+    }
+
+    /// Loads the snapshot at `path`, or an empty one if there is none.
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|er| {
+                warn!("Ignoring cache snapshot {:?}: {}", path, er);
+                CacheSnapshot::default()
+            }),
+            Err(_) => CacheSnapshot::default(),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) {
+        let result = match serde_json::to_string(self) {
+            Ok(json) => rocket::tokio::fs::write(path, json).await.map_err(|er| er.to_string()),
+            Err(er) => Err(er.to_string()),
+        };
+        match result {
+            Ok(()) => info!("Saved cache snapshot with {} menus to {:?}", self.menus.len(), path),
+            Err(er) => error!("Failed to save cache snapshot to {:?}: {}", path, er),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::tokio::time::Duration;
+
+    #[test]
+    fn restores_only_fresh_entries() {
+        let now = Instant::now();
+        let changed_at = Utc::now() - chrono::Duration::hours(1);
+        let vendor_cache = Some(VendorCache(now, "[]".to_string(), String::new(), changed_at));
+        let menu_cache = VenderMenuCache(HashMap::from([
+            ("compassdk_dbvendor1".to_string(), (now, serde_json::json!([]), String::new(), Utc::now())),
+            ("compassdk_dbvendor2".to_string(), (now - CACHE_TTL - Duration::from_secs(1), serde_json::json!([]), String::new(), Utc::now())),
+        ]));
+
+        let snapshot = CacheSnapshot::take(&vendor_cache, &menu_cache);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let (vendors, menus) = serde_json::from_str::<CacheSnapshot>(&json).unwrap().restore();
+
+        let vendors = vendors.unwrap();
+        assert_eq!(vendors.1, "[]");
+        assert_eq!(vendors.3, changed_at);
+        assert!(menus.0.contains_key("compassdk_dbvendor1"));
+        assert!(!menus.0.contains_key("compassdk_dbvendor2"));
+    }
+}
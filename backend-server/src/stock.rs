@@ -0,0 +1,292 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Europe::Copenhagen;
+use chrono_tz::Tz;
+use rocket::futures::lock::Mutex;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::{select, time::sleep};
+use rocket::Shutdown;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::menu::{items_mut, vendors, MenuItem};
+use crate::menu_source::SourceRegistry;
+use crate::vendor_order::VendorOrder;
+use crate::CACHE_TTL;
+
+/// Number of sell-out days kept per item.
+const HISTORY_DAYS: usize = 30;
+
+/// Sell-out times needed before predicting when an item usually sells out.
+const MIN_HISTORY_FOR_ESTIMATE: usize = 2;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StockSample {
+    pub at: DateTime<Tz>,
+    pub stock_balance: Option<i64>,
+    pub available: bool,
+}
+
+#[derive(Debug, Default)]
+struct ItemDay {
+    samples: Vec<StockSample>,
+    sold_out_at: Option<DateTime<Tz>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SellOut {
+    pub date: NaiveDate,
+    pub time: NaiveTime,
+}
+
+/// Follows the stock of every item over the day and remembers when items sold out on earlier days.
+#[derive(Debug)]
+pub struct StockTracker {
+    today: Option<NaiveDate>,
+    items: HashMap<String, ItemDay>,
+    history: HashMap<String, Vec<SellOut>>,
+    history_path: Option<PathBuf>,
+}
+
+impl StockTracker {
+    /// Creates a tracker, loading the sell-out history from `history_path` if given.
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let history = match &history_path {
+            Some(path) if path.exists() => std::fs::read_to_string(path)
+                .map_err(|er| er.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|er| er.to_string()))
+                .unwrap_or_else(|er| {
+                    error!("Failed to load stock history from {:?}: {}", path, er);
+                    HashMap::new()
+                }),
+            _ => HashMap::new(),
+        };
+
+        StockTracker { today: None, items: HashMap::new(), history, history_path }
+    }
+
+    /// Records the current state of an item. Returns whether a new sell-out was added to the history.
+    /// Items disabled for a reason, e.g. at closing time, have not sold out, so they are not recorded.
+    pub fn observe(&mut self, item: &MenuItem, now: DateTime<Tz>) -> bool {
+        if self.today != Some(now.date_naive()) {
+            self.today = Some(now.date_naive());
+            self.items.clear();
+        }
+        if !item.enabled && !item.disabled_reason.is_empty() {
+            return false;
+        }
+
+        let available = item.is_available();
+        let sample = StockSample {
+            at: now,
+            stock_balance: item.use_stock_balance.then_some(item.stock_balance),
+            available,
+        };
+        let day = self.items.entry(item.key.clone()).or_default();
+        let previous = day.samples.last().cloned();
+        if previous.as_ref().is_some_and(|p| p.stock_balance == sample.stock_balance && p.available == sample.available) {
+            return false;
+        }
+        day.samples.push(sample);
+
+        // Only a change from available to sold out tells us when it happened.
+        let Some(previous) = previous else { return false };
+        if available {
+            day.sold_out_at = None;
+            return false;
+        }
+        if !previous.available {
+            return false;
+        }
+
+        day.sold_out_at = Some(now);
+        let history = self.history.entry(item.key.clone()).or_default();
+        if history.iter().any(|sell_out| sell_out.date == now.date_naive()) {
+            return false;
+        }
+        history.push(SellOut { date: now.date_naive(), time: now.time() });
+        if history.len() > HISTORY_DAYS {
+            history.remove(0);
+        }
+        info!("Item {} ({}) sold out at {}", item.name, item.key, now);
+        true
+    }
+
+    /// Observes every item of a raw menu. Returns whether the history changed.
+    pub fn sample(&mut self, menu: &Value, now: DateTime<Tz>) -> bool {
+        let mut menu = menu.clone();
+        let mut history_changed = false;
+        for item in items_mut(&mut menu) {
+            let Ok(typed) = MenuItem::deserialize(&*item) else { continue };
+            if !typed.key.is_empty() {
+                history_changed |= self.observe(&typed, now);
+            }
+        }
+        history_changed
+    }
+
+    /// Adds `sold_out_at`, `stock_trend` and `usually_sold_out_by` to every item of a raw menu.
+    /// The stock trend is only shown for items whose vendor shows the stock balance.
+    pub fn annotate(&self, menu: &mut Value) {
+        for item in items_mut(menu) {
+            let Ok(typed) = MenuItem::deserialize(&*item) else { continue };
+            if typed.key.is_empty() {
+                continue;
+            }
+
+            let day = self.items.get(&typed.key);
+            let stock_trend: Vec<&StockSample> = day
+                .filter(|_| typed.show_stock_balance)
+                .map(|day| day.samples.iter().filter(|sample| sample.stock_balance.is_some()).collect())
+                .unwrap_or_default();
+            if let Value::Object(fields) = item {
+                fields.insert("sold_out_at".to_string(), serde_json::json!(day.and_then(|day| day.sold_out_at)));
+                fields.insert("stock_trend".to_string(), serde_json::json!(stock_trend));
+                fields.insert("usually_sold_out_by".to_string(), serde_json::json!(self.usually_sold_out_by(&typed.key).map(|time| time.format("%H:%M").to_string())));
+            }
+        }
+    }
+
+    /// The median time of day the item sold out on earlier days.
+    pub fn usually_sold_out_by(&self, key: &str) -> Option<NaiveTime> {
+        let history = self.history.get(key)?;
+        if history.len() < MIN_HISTORY_FOR_ESTIMATE {
+            return None;
+        }
+
+        let mut seconds: Vec<u32> = history.iter().map(|sell_out| sell_out.time.num_seconds_from_midnight()).collect();
+        seconds.sort();
+        let median = seconds[seconds.len() / 2];
+        NaiveTime::from_num_seconds_from_midnight_opt(median - median % 60, 0)
+    }
+
+    pub async fn save(&self) {
+        let Some(path) = &self.history_path else { return };
+        let result = match serde_json::to_string_pretty(&self.history) {
+            Ok(json) => rocket::tokio::fs::write(path, json).await.map_err(|er| er.to_string()),
+            Err(er) => Err(er.to_string()),
+        };
+        if let Err(er) = result {
+            error!("Failed to save stock history to {:?}: {}", path, er);
+        }
+    }
+}
+
+/// Samples the menus of all vendors every `CACHE_TTL`, as their cache runs out, until shutdown.
+/// Sell-out times then do not depend on when someone happens to ask for a menu.
+pub async fn run(sources: Arc<SourceRegistry>, tracker: Arc<Mutex<StockTracker>>, shutdown: Shutdown) {
+    loop {
+        sample_all(&sources, &tracker, Utc::now().with_timezone(&Copenhagen)).await;
+        select! {
+            _ = shutdown.clone() => return,
+            _ = sleep(CACHE_TTL) => {},
+        }
+    }
+}
+
+async fn sample_all(sources: &SourceRegistry, tracker: &Mutex<StockTracker>, now: DateTime<Tz>) {
+    let locations = match sources.locations(&VendorOrder::new(HashMap::new(), None)).await {
+        Ok(locations) => locations,
+        Err((_, er)) => {
+            warn!("Not sampling stock, vendors failed to load: {}", er);
+            return;
+        }
+    };
+    let mut history_changed = false;
+    for vendor in vendors(&locations) {
+        match sources.menu(&vendor.route_name).await {
+            Ok(menu) => history_changed |= tracker.lock().await.sample(&menu.value, now),
+            Err((_, er)) => warn!("Not sampling stock of {}: {}", vendor.route_name, er),
+        }
+    }
+    if history_changed {
+        tracker.lock().await.save().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use chrono::TimeZone;
+    use chrono_tz::Europe::Copenhagen;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Tz> {
+        Copenhagen.with_ymd_and_hms(2025, 12, day, hour, minute, 0).unwrap()
+    }
+
+    fn item(stock_balance: i64) -> MenuItem {
+        MenuItem { key: "a".to_string(), enabled: true, use_stock_balance: true, stock_balance, ..Default::default() }
+    }
+
+    #[test]
+    fn records_sell_out_on_transition() {
+        let mut tracker = StockTracker::new(None);
+        assert!(!tracker.observe(&item(5), at(10, 11, 0)));
+        assert!(!tracker.observe(&item(2), at(10, 11, 30)));
+        assert!(tracker.observe(&item(0), at(10, 11, 50)));
+        assert!(!tracker.observe(&item(0), at(10, 12, 0)));
+        assert_eq!(tracker.items["a"].sold_out_at, Some(at(10, 11, 50)));
+        assert_eq!(tracker.items["a"].samples.len(), 3);
+    }
+
+    #[test]
+    fn does_not_guess_sell_out_when_first_seen_sold_out() {
+        let mut tracker = StockTracker::new(None);
+        assert!(!tracker.observe(&item(0), at(10, 11, 0)));
+        assert_eq!(tracker.items["a"].sold_out_at, None);
+    }
+
+    #[test]
+    fn restock_clears_sold_out() {
+        let mut tracker = StockTracker::new(None);
+        tracker.observe(&item(1), at(10, 11, 0));
+        tracker.observe(&item(0), at(10, 11, 10));
+        tracker.observe(&item(10), at(10, 11, 20));
+        assert_eq!(tracker.items["a"].sold_out_at, None);
+    }
+
+    #[test]
+    fn closing_time_is_not_a_sell_out() {
+        let mut tracker = StockTracker::new(None);
+        let closed = MenuItem { enabled: false, disabled_reason: "Closed".to_string(), ..item(3) };
+        tracker.observe(&item(3), at(10, 13, 0));
+        assert!(!tracker.observe(&closed, at(10, 14, 0)));
+        assert_eq!(tracker.items["a"].sold_out_at, None);
+        assert!(!tracker.history.contains_key("a"));
+
+        // Disabled without a reason, it sold out.
+        let sold_out = MenuItem { enabled: false, ..item(3) };
+        assert!(tracker.observe(&sold_out, at(10, 14, 5)));
+        assert_eq!(tracker.items["a"].sold_out_at, Some(at(10, 14, 5)));
+    }
+
+    #[test]
+    fn estimates_usual_sell_out_from_history() {
+        let mut tracker = StockTracker::new(None);
+        for (day, minute) in [(8, 40), (9, 50), (10, 55)] {
+            tracker.observe(&item(1), at(day, 11, 0));
+            tracker.observe(&item(0), at(day, 11, minute));
+        }
+        assert_eq!(tracker.history["a"].len(), 3);
+        assert_eq!(tracker.usually_sold_out_by("a"), NaiveTime::from_hms_opt(11, 50, 0));
+    }
+
+    #[test]
+    fn annotates_raw_menu_items() {
+        let mut tracker = StockTracker::new(None);
+        let mut menu = serde_json::json!({"0": {"items": {
+            "0": {"key": "a", "enabled": true, "useStockBalance": true, "showStockBalance": true, "stockBalance": 3},
+            "1": {"key": "b", "enabled": true, "useStockBalance": true, "stockBalance": 4},
+        }}});
+        tracker.sample(&menu, at(10, 11, 0));
+        tracker.annotate(&mut menu);
+        let item = &menu["0"]["items"]["0"];
+        assert_eq!(item["sold_out_at"], Value::Null);
+        assert_eq!(item["stock_trend"][0]["stock_balance"], 3);
+        assert_eq!(item["usually_sold_out_by"], Value::Null);
+        assert_eq!(menu["0"]["items"]["1"]["stock_trend"], serde_json::json!([]));
+    }
+}
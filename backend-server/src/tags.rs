@@ -0,0 +1,321 @@
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use tracing::{error, info, instrument, warn};
+
+use crate::admin::Admin;
+use crate::menu::{items_mut, MenuItem};
+
+pub const VEGETARIAN: &str = "vegetarian";
+pub const VEGAN: &str = "vegan";
+pub const CONTAINS_NUTS: &str = "contains-nuts";
+pub const GLUTEN: &str = "gluten";
+pub const LACTOSE: &str = "lactose";
+pub const FISH: &str = "fish";
+pub const PORK: &str = "pork";
+pub const SPICY: &str = "spicy";
+
+/// Item keys are short ids, longer ones are rejected so overrides cannot grow the saved file without bound.
+const MAX_KEY_LENGTH: usize = 128;
+
+/// Keywords that apply a tag, and keywords that prevent it (e.g. "glutenfri" for `gluten`).
+/// A keyword matches at the start of a word, so "ost" matches "osten" but not "kost".
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TagRule {
+    pub keywords: Vec<String>,
+    pub negations: Vec<String>,
+}
+
+/// Manually added and removed tags for a single item.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TagOverride {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Tagger {
+    rules: BTreeMap<String, TagRule>,
+    overrides: HashMap<String, TagOverride>,
+    overrides_path: Option<PathBuf>,
+}
+
+impl Tagger {
+    /// Creates a tagger with the built-in dictionary, where `rules` replaces the rule of a tag.
+    /// Overrides are loaded from and saved to `overrides_path`, if given.
+    pub fn new(rules: HashMap<String, TagRule>, overrides_path: Option<PathBuf>) -> Self {
+        let mut all_rules = default_rules();
+        all_rules.extend(rules);
+
+        let overrides = match &overrides_path {
+            Some(path) if path.exists() => std::fs::read_to_string(path)
+                .map_err(|er| er.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|er| er.to_string()))
+                .unwrap_or_else(|er| {
+                    error!("Failed to load tag overrides from {:?}: {}", path, er);
+                    HashMap::new()
+                }),
+            _ => HashMap::new(),
+        };
+
+        Tagger { rules: all_rules, overrides, overrides_path }
+    }
+
+    pub fn is_known_tag(&self, tag: &str) -> bool {
+        self.rules.contains_key(tag)
+    }
+
+    /// Derives the tags of an item from its name and descriptions, then applies its override.
+    pub fn tags(&self, item: &MenuItem) -> Vec<String> {
+        let text = format!("{} {} {}", item.name, item.description, item.description_long).to_lowercase();
+        let mut tags: Vec<String> = self.rules.iter()
+            .filter(|(_, rule)| {
+                rule.keywords.iter().any(|keyword| contains_word(&text, keyword))
+                    && !rule.negations.iter().any(|negation| contains_word(&text, negation))
+            })
+            .map(|(tag, _)| tag.clone())
+            .collect();
+
+        let has = |tags: &[String], tag: &str| tags.iter().any(|t| t == tag);
+        if has(&tags, FISH) || has(&tags, PORK) {
+            tags.retain(|tag| tag != VEGETARIAN && tag != VEGAN);
+        }
+        if has(&tags, VEGAN) {
+            tags.retain(|tag| tag != LACTOSE);
+            if !has(&tags, VEGETARIAN) {
+                tags.push(VEGETARIAN.to_string());
+            }
+        }
+
+        if let Some(tag_override) = self.overrides.get(&item.key) {
+            tags.retain(|tag| !tag_override.remove.contains(tag));
+            for tag in &tag_override.add {
+                if !has(&tags, tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        tags.sort();
+        tags
+    }
+
+    /// Adds a `tags` field to every item of a raw menu.
+    pub fn annotate(&self, menu: &mut Value) {
+        for item in items_mut(menu) {
+            let Ok(typed) = MenuItem::deserialize(&*item) else { continue };
+            if let Value::Object(fields) = item {
+                fields.insert("tags".to_string(), serde_json::json!(self.tags(&typed)));
+            }
+        }
+    }
+
+    pub fn overrides(&self) -> &HashMap<String, TagOverride> {
+        &self.overrides
+    }
+
+    /// Sets or, when `tag_override` is `None`, clears the override of an item and saves all overrides.
+    pub async fn set_override(&mut self, key: &str, tag_override: Option<TagOverride>) -> Result<(), std::io::Error> {
+        match tag_override {
+            Some(tag_override) => self.overrides.insert(key.to_string(), tag_override),
+            None => self.overrides.remove(key),
+        };
+
+        if let Some(path) = &self.overrides_path {
+            let json = serde_json::to_string_pretty(&self.overrides)?;
+            rocket::tokio::fs::write(path, json).await?;
+            info!("Saved {} tag overrides to {:?}", self.overrides.len(), path);
+        }
+        Ok(())
+    }
+}
+
+/// Whether `keyword` occurs in `text` at the start of a word.
+fn contains_word(text: &str, keyword: &str) -> bool {
+    text.match_indices(keyword).any(|(position, _)| {
+        text[..position].chars().next_back().is_none_or(|c| !c.is_alphanumeric())
+    })
+}
+
+fn rule(keywords: &[&str], negations: &[&str]) -> TagRule {
+    TagRule {
+        keywords: keywords.iter().map(|k| k.to_string()).collect(),
+        negations: negations.iter().map(|n| n.to_string()).collect(),
+    }
+}
+
+/// Danish, Swedish and English keywords for each tag.
+fn default_rules() -> BTreeMap<String, TagRule> {
+    const MEAT: &[&str] = &[
+        "kylling", "kyckling", "chicken", "okse", "oksekød", "nötkött", "beef", "kalv", "veal",
+        "lam", "lamm", "lamb", "anka", "duck", "kød", "kött", "meat", "kebab", "bøf", "steak",
+    ];
+    BTreeMap::from([
+        (VEGETARIAN.to_string(), rule(
+            &["vegetar", "vegetarisk", "vegetarian", "veggie", "vego", "grøntsagsret"],
+            MEAT)),
+        (VEGAN.to_string(), rule(
+            &["vegan", "vegansk", "veganes", "plantebaseret", "växtbaserad", "plant-based", "plant based"],
+            MEAT)),
+        (CONTAINS_NUTS.to_string(), rule(
+            &["nød", "nødder", "hasselnød", "valnød", "jordnød", "mandel", "mandler", "pistacie",
+              "nötter", "hasselnöt", "valnöt", "jordnöt", "mandlar",
+              "nuts", "peanut", "walnut", "hazelnut", "almond", "cashew", "pistachio", "pecan"],
+            &["nøddefri", "nötfri", "nut-free", "nut free"])),
+        (GLUTEN.to_string(), rule(
+            &["gluten", "hvede", "rug", "rugbrød", "byg", "spelt", "brød", "smørrebrød", "bolle", "pasta", "nudler",
+              "vete", "råg", "korn", "bröd", "smörgås",
+              "wheat", "rye", "barley", "bread", "bun", "noodle", "couscous", "bulgur", "tortilla", "pizza", "burger", "sandwich"],
+            &["glutenfri", "glutenfritt", "gluten-free", "gluten free"])),
+        (LACTOSE.to_string(), rule(
+            &["mælk", "fløde", "smør", "ost", "flødeost", "gedeost", "yoghurt", "skyr", "creme fraiche",
+              "mjölk", "grädde", "smör", "kvarg",
+              "milk", "cream", "butter", "cheese", "feta", "parmesan", "mozzarella", "cheddar", "halloumi", "latte"],
+            &["laktosefri", "laktosfri", "lactose-free", "lactose free", "havremælk", "havremjölk", "oat milk"])),
+        (FISH.to_string(), rule(
+            &["fisk", "laks", "torsk", "tun", "rejer", "sild", "makrel", "rødspætte", "ansjos", "rogn", "skaldyr", "krabbe", "hummer", "muslinger",
+              "lax", "räkor", "sill", "kräftor",
+              "fish", "salmon", "cod", "tuna", "shrimp", "prawn", "herring", "mackerel", "anchov", "seafood", "crab", "lobster", "mussel", "sushi"],
+            &[])),
+        (PORK.to_string(), rule(
+            &["svin", "svinekød", "flæsk", "flæskesteg", "gris", "skinke", "bacon", "pølse", "frikadelle", "ribbensteg",
+              "fläsk", "skinka", "korv",
+              "pork", "sausage", "chorizo", "pancetta", "prosciutto", "salami", "pulled pork"],
+            &[])),
+        (SPICY.to_string(), rule(
+            &["stærk", "chili", "jalapeño", "jalapeno", "sriracha", "harissa", "wasabi", "kimchi",
+              "stark", "kryddig",
+              "spicy", "hot sauce", "habanero", "vindaloo", "gochujang"],
+            &["mild"])),
+    ])
+}
+
+#[get("/tags/overrides")]
+#[instrument]
+pub async fn get_overrides(tagger: &State<Mutex<Tagger>>) -> Json<HashMap<String, TagOverride>> {
+    Json(tagger.lock().await.overrides().clone())
+}
+
+#[put("/tags/overrides/<key>", data = "<body>")]
+#[instrument(skip(_admin, body))]
+pub async fn put_override(_admin: Admin, key: &str, body: Json<TagOverride>, tagger: &State<Mutex<Tagger>>) -> Result<Json<TagOverride>, (Status, String)> {
+    if key.len() > MAX_KEY_LENGTH {
+        return Err((Status::BadRequest, format!("Item keys are at most {} characters", MAX_KEY_LENGTH)));
+    }
+    let mut tagger = tagger.lock().await;
+    let tag_override = body.into_inner();
+    if let Some(unknown) = tag_override.add.iter().chain(tag_override.remove.iter()).find(|tag| !tagger.is_known_tag(tag)) {
+        return Err((Status::BadRequest, format!("Unknown tag {}", unknown)));
+    }
+
+    tagger.set_override(key, Some(tag_override.clone())).await
+        .map_err(|er| {
+            error!("Failed to save tag overrides: {:?}", er);
+            (Status::InternalServerError, format!("Saving overrides failed {:?}", er))
+        })?;
+    Ok(Json(tag_override))
+}
+
+#[delete("/tags/overrides/<key>")]
+#[instrument(skip(_admin))]
+pub async fn delete_override(_admin: Admin, key: &str, tagger: &State<Mutex<Tagger>>) -> Result<Status, (Status, String)> {
+    let mut tagger = tagger.lock().await;
+    if !tagger.overrides().contains_key(key) {
+        warn!("No tag override for {}", key);
+        return Err((Status::NotFound, format!("No tag override for {}", key)));
+    }
+
+    tagger.set_override(key, None).await
+        .map_err(|er| {
+            error!("Failed to save tag overrides: {:?}", er);
+            (Status::InternalServerError, format!("Saving overrides failed {:?}", er))
+        })?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::admin::AdminConfig;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    fn item(key: &str, name: &str, description: &str) -> MenuItem {
+        MenuItem { key: key.to_string(), name: name.to_string(), description: description.to_string(), ..Default::default() }
+    }
+
+    fn tagger() -> Tagger {
+        Tagger::new(HashMap::new(), None)
+    }
+
+    #[test]
+    fn derives_tags_from_danish_swedish_and_english_text() {
+        let tagger = tagger();
+        assert_eq!(tagger.tags(&item("a", "Rugbrød med æg og rejer", "")), vec![FISH, GLUTEN]);
+        assert_eq!(tagger.tags(&item("b", "Vegansk dahl", "Stærk karry med cashewnødder og cashew")), vec![CONTAINS_NUTS, SPICY, VEGAN, VEGETARIAN]);
+        assert_eq!(tagger.tags(&item("c", "Pulled pork burger", "with cheese")), vec![GLUTEN, LACTOSE, PORK]);
+        assert_eq!(tagger.tags(&item("d", "Kycklingsallad", "med jordnötter")), vec![CONTAINS_NUTS]);
+    }
+
+    #[test]
+    fn matches_keywords_at_word_start_only() {
+        let tagger = tagger();
+        assert!(tagger.tags(&item("a", "Dagens kost", "")).is_empty());
+        assert_eq!(tagger.tags(&item("b", "Ostemad", "")), vec![LACTOSE]);
+    }
+
+    #[test]
+    fn negations_prevent_tags() {
+        let tagger = tagger();
+        assert_eq!(tagger.tags(&item("a", "Glutenfri pasta", "")), Vec::<String>::new());
+        assert_eq!(tagger.tags(&item("b", "Vegetar lasagne", "med kylling")), Vec::<String>::new());
+    }
+
+    #[test]
+    fn overrides_add_and_remove_tags() {
+        let mut tagger = tagger();
+        tagger.overrides.insert("a".to_string(), TagOverride { add: vec![SPICY.to_string()], remove: vec![FISH.to_string()] });
+        assert_eq!(tagger.tags(&item("a", "Rejer", "")), vec![SPICY]);
+        assert_eq!(tagger.tags(&item("b", "Rejer", "")), vec![FISH]);
+    }
+
+    #[test]
+    fn configured_rules_replace_defaults() {
+        let tagger = Tagger::new(HashMap::from([(SPICY.to_string(), rule(&["hot"], &[]))]), None);
+        assert_eq!(tagger.tags(&item("a", "Hot wings", "")), vec![SPICY]);
+        assert!(tagger.tags(&item("b", "Chili con carne", "")).is_empty());
+    }
+
+    #[test]
+    fn annotates_raw_menu_items() {
+        let mut menu = serde_json::json!({"0": {"items": {"0": {"key": "a", "Name": "Laks"}}}});
+        tagger().annotate(&mut menu);
+        assert_eq!(menu["0"]["items"]["0"]["tags"], serde_json::json!([FISH]));
+    }
+
+    #[test]
+    fn changing_overrides_requires_the_admin_token() {
+        let rocket = rocket::build()
+            .mount("/api", routes![get_overrides, put_override, delete_override])
+            .manage(AdminConfig { token: Some("secret".to_string()) })
+            .manage(Mutex::new(tagger()));
+        let client = Client::tracked(rocket).unwrap();
+        let body = r#"{"add": ["spicy"]}"#;
+
+        assert_eq!(client.put("/api/tags/overrides/a").body(body).dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.delete("/api/tags/overrides/a").dispatch().status(), Status::Unauthorized);
+
+        let admin = Header::new("Authorization", "Bearer secret");
+        let long_key = "a".repeat(MAX_KEY_LENGTH + 1);
+        assert_eq!(client.put(format!("/api/tags/overrides/{}", long_key)).header(admin.clone()).body(body).dispatch().status(), Status::BadRequest);
+        assert_eq!(client.put("/api/tags/overrides/a").header(admin.clone()).body(body).dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/api/tags/overrides").dispatch().into_json::<HashMap<String, TagOverride>>().unwrap().len(), 1);
+        assert_eq!(client.delete("/api/tags/overrides/a").header(admin).dispatch().status(), Status::NoContent);
+    }
+}
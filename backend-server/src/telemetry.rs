@@ -0,0 +1,203 @@
+use opentelemetry::global;
+use opentelemetry::metrics::{Meter, MeterProvider};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::logs::SdkLoggerProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use rocket::http::HeaderMap;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{Data, Request};
+use std::collections::HashMap;
+use tracing::{info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+const SERVICE_NAME: &str = "market-food-dashboard-backend";
+
+/// The OpenTelemetry providers, when exporting over OTLP.
+#[derive(Clone)]
+pub struct Telemetry {
+    pub endpoint: String,
+    pub logger_provider: SdkLoggerProvider,
+    pub tracer_provider: SdkTracerProvider,
+    pub meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    pub fn meter(&self) -> Meter {
+        self.meter_provider.meter(SERVICE_NAME)
+    }
+
+    /// Exports what is still buffered and stops the exporters. Blocks until the collector answers or times out.
+    pub fn shutdown(&self) {
+        if let Err(er) = self.tracer_provider.shutdown() {
+            tracing::warn!("Failed to shut down the tracer provider: {:?}", er);
+        }
+        if let Err(er) = self.meter_provider.shutdown() {
+            tracing::warn!("Failed to shut down the meter provider: {:?}", er);
+        }
+        // Last, so the warnings above are exported too. This one still reaches the console.
+        if let Err(er) = self.logger_provider.shutdown() {
+            tracing::warn!("Failed to shut down the logger provider: {:?}", er);
+        }
+    }
+}
+
+fn filter() -> EnvFilter {
+    // TODO: Remove logs fomr select components
+    EnvFilter::new("info")
+        .add_directive("rocket=warn".parse().unwrap())
+}
+
+/// Sets up console logging and, when `otel_endpoint` is given, OTLP export of logs, traces and metrics.
+/// `otel_endpoint` is the base URL of the collector, e.g. `http://localhost:4318`.
+pub fn setup_telemetry(otel_endpoint: Option<&str>) -> Option<Telemetry> {
+    let telemetry = otel_endpoint.map(otlp_providers);
+
+    let otel_layer = telemetry.as_ref().map(|telemetry| OpenTelemetryTracingBridge::new(&telemetry.logger_provider)
+        .with_filter(filter()));
+    let trace_layer = telemetry.as_ref().map(|telemetry| tracing_opentelemetry::layer()
+        .with_tracer(telemetry.tracer_provider.tracer(SERVICE_NAME))
+        .with_filter(filter()));
+
+    // This causes output to the console
+    let filter_fmt = EnvFilter::new("info")
+        .add_directive("opentelemetry=info".parse().unwrap())
+        .add_directive("rocket=warn".parse().unwrap());
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_thread_names(true)
+        .with_filter(filter_fmt);
+
+    tracing_subscriber::registry()
+        .with(otel_layer)
+        .with(trace_layer)
+        .with(fmt_layer)
+        .init();
+
+    if telemetry.is_none() {
+        tracing::info!("No otel_endpoint configured, logging to the console only");
+    }
+    telemetry
+}
+
+fn otlp_providers(otel_endpoint: &str) -> Telemetry {
+    // Older configurations point at the logs endpoint itself.
+    let base = otel_endpoint.trim_end_matches('/').trim_end_matches("/v1/logs");
+    let resource = Resource::builder()
+        .with_service_name(SERVICE_NAME)
+        .build();
+
+    let log_exporter = opentelemetry_otlp::LogExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpBinary)
+        .with_endpoint(format!("{}/v1/logs", base))
+        .build()
+        .expect("Failed to create OTLP Log Exporter");
+    let logger_provider = SdkLoggerProvider::builder()
+        .with_resource(resource.clone())
+        .with_batch_exporter(log_exporter)
+        .build();
+
+    let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpBinary)
+        .with_endpoint(format!("{}/v1/traces", base))
+        .build()
+        .expect("Failed to create OTLP Span Exporter");
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_resource(resource.clone())
+        .with_batch_exporter(span_exporter)
+        .build();
+
+    let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpBinary)
+        .with_endpoint(format!("{}/v1/metrics", base))
+        .build()
+        .expect("Failed to create OTLP Metric Exporter");
+    let meter_provider = SdkMeterProvider::builder()
+        .with_resource(resource)
+        .with_periodic_exporter(metric_exporter)
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+
+    Telemetry { endpoint: base.to_string(), logger_provider, tracer_provider, meter_provider }
+}
+
+struct HeaderExtractor<'a, 'r>(&'a HeaderMap<'r>);
+
+impl Extractor for HeaderExtractor<'_, '_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        ["traceparent", "tracestate"].into_iter().filter(|key| self.0.contains(*key)).collect()
+    }
+}
+
+/// Headers carrying the current trace context, for requests to other services.
+pub fn trace_headers() -> HashMap<String, String> {
+    struct HeaderInjector(HashMap<String, String>);
+
+    impl Injector for HeaderInjector {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_string(), value);
+        }
+    }
+
+    let mut injector = HeaderInjector(HashMap::new());
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&Span::current().context(), &mut injector));
+    injector.0
+}
+
+/// Runs a route inside a `request` span that continues the trace of the W3C `traceparent` header, if any.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let route = request.route().map(|route| route.uri.to_string()).unwrap_or_default();
+        let span = info_span!("request", otel.name = %format!("{} {}", request.method(), route), http.method = %request.method(), http.route = %route);
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())));
+        // Fails only when no OpenTelemetry layer is installed, the span is then local anyway.
+        let _ = span.set_parent(parent);
+        self.0.handle(request, data).instrument(span).await
+    }
+}
+
+/// Wraps the handlers of `routes` so they are traced.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rocket::http::Header;
+
+    #[test]
+    fn extracts_trace_context_headers() {
+        let mut headers = HeaderMap::new();
+        headers.add(Header::new("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        headers.add(Header::new("Accept", "application/json"));
+        let extractor = HeaderExtractor(&headers);
+        assert_eq!(extractor.get("traceparent"), Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+    }
+}
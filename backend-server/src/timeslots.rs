@@ -0,0 +1,190 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Europe::Copenhagen;
+use chrono_tz::Tz;
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::response::content::RawJson;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::tokio::time::{Duration, Instant};
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+use crate::menu::{find_item, parse_menu};
+use crate::metrics::{cache_lookup, TIMESLOT_REQUEST_DURATION};
+use crate::menu_source::{MenuSource, SourceRegistry};
+use crate::telemetry::trace_headers;
+
+/// Most products in one timeslot request.
+pub const MAX_PRODUCTS: usize = 50;
+
+/// Most of one product in one timeslot request.
+pub const MAX_QUANTITY: u32 = 100;
+
+#[derive(Deserialize, Serialize)]
+pub struct TimeslotRequest {
+    #[serde(rename = "routeName")]
+    pub route_name: String,
+    pub products: Vec<TimeslotProduct>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TimeslotProduct {
+    #[serde(rename = "bongCategoryId")]
+    pub bong_category_id: i32,
+    #[serde(rename = "productId")]
+    pub product_id: String,
+    #[serde(rename = "productName")]
+    pub product_name: String,
+    pub quantity: u32,
+}
+
+/// The outcome of the last request to the payments service: the HTTP status code or `error`.
+#[derive(Serialize, Debug, Clone)]
+pub struct TimeslotStatus {
+    pub status: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct TimeSlotCache {
+    pub entries: HashMap<String, (Instant, String)>,
+    pub last_status: Option<TimeslotStatus>,
+}
+
+#[post("/timeslots", data = "<body>")]
+#[instrument(skip(body))]
+pub async fn get_item_timeslots(
+    body : Json<TimeslotRequest>,
+    sources : &State<Arc<SourceRegistry>>,
+) -> Result<RawJson<String>, (rocket::http::Status, String)> {
+    let request = body.into_inner();
+    validate_request(&request)?;
+    let source = sources.find(&request.route_name).await?;
+    check_products(&request, source.as_ref()).await?;
+    let timeslots_json = source.timeslots(&request).await?;
+    Ok(RawJson(timeslots_json))
+}
+
+/// Rejects requests the payments service should not be asked about, see `MAX_PRODUCTS` and `MAX_QUANTITY`.
+pub fn validate_request(request : &TimeslotRequest) -> Result<(), (rocket::http::Status, String)> {
+    if request.products.is_empty() || request.products.len() > MAX_PRODUCTS {
+        return Err((Status::BadRequest, format!("A request needs between 1 and {} products", MAX_PRODUCTS)));
+    }
+    if let Some(product) = request.products.iter().find(|product| product.quantity == 0 || product.quantity > MAX_QUANTITY) {
+        return Err((Status::BadRequest, format!("The quantity of {} must be between 1 and {}", product.product_id, MAX_QUANTITY)));
+    }
+    Ok(())
+}
+
+/// Only requests for known products of a known vendor are passed on to the payments service.
+async fn check_products(request : &TimeslotRequest, source : &dyn MenuSource) -> Result<(), (rocket::http::Status, String)> {
+    let menu = source.menu(&request.route_name).await?;
+    let menu = parse_menu(&menu.value)
+        .map_err(|er| (Status::BadGateway, format!("Unexpected menu format {:?}", er)))?;
+    if let Some(product) = request.products.iter().find(|product| find_item(&menu, &product.product_id).is_none()) {
+        warn!("Unknown product {} requested for {}", product.product_id, request.route_name);
+        return Err((Status::BadRequest, format!("Product {} is not on the menu of {}", product.product_id, request.route_name)));
+    }
+    Ok(())
+}
+
+/// Returns the raw timeslot JSON for a request, from the cache if it is fresh, otherwise from the payments service.
+pub async fn fetch_timeslots(request : &TimeslotRequest, timeslot_cache : &Mutex<TimeSlotCache>) -> Result<String, (rocket::http::Status, String)> {
+    let cache_key = format!(
+        "{}-{}",
+        request.route_name,
+        &request.products.iter()
+            .map(|p| format!("{}x{}", p.product_id, p.quantity))
+            .collect::<Vec<String>>().join("|"));
+
+    {
+        let cache = &mut timeslot_cache.lock().await.entries;
+            
+        if let Some((timestamp, cached_timeslots)) = cache.get(&cache_key) {
+            if timestamp.elapsed() < Duration::from_secs(300) {
+                cache_lookup("timeslots", true);
+                return Ok(cached_timeslots.clone());
+            }
+        }
+    }
+    cache_lookup("timeslots", false);
+    
+    info!("Fetching timeslots for key {} from external service", cache_key);
+    let json = serde_json::to_string(request)
+        .map_err(|er| (Status::InternalServerError, format!("Serialization failed {:?}", er)))?;
+    let start = Instant::now();
+    let span = info_span!("timeslot_request", route_name = %request.route_name, products = request.products.len());
+    let mut http_request = reqwest::Client::new()
+        .post("https://payments2-jaonrqeeaq-ew.a.run.app/v1/orders/timeslots")
+        .header("Content-Type", "application/json")
+        .body(json);
+    for (name, value) in span.in_scope(trace_headers) {
+        http_request = http_request.header(name, value);
+    }
+    let response = http_request
+        .send()
+        .instrument(span)
+        .await;
+    let status = response.as_ref().map(|response| response.status().as_u16().to_string()).unwrap_or_else(|_| "error".to_string());
+    TIMESLOT_REQUEST_DURATION.with_label_values(&[&status]).observe(start.elapsed().as_secs_f64());
+    timeslot_cache.lock().await.last_status = Some(TimeslotStatus { status, at: Utc::now() });
+    let response = response
+        .map_err(|er| (Status::InternalServerError, format!("HTTP request failed {:?}", er)))?;    
+    let timeslots_json = response.text().await
+        .map_err(|er| (Status::InternalServerError, format!("Deserializing response failed {:?}", er)))?;
+    let cache = &mut timeslot_cache.lock().await.entries;
+
+    cache.insert(cache_key, (Instant::now(), timeslots_json.clone()));
+    Ok(timeslots_json)
+}
+
+#[derive(Deserialize, Debug)]
+struct TimeslotDay {
+    #[serde(default)]
+    timeslots: Vec<Timeslot>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Timeslot {
+    #[serde(rename = "dateISO")]
+    date_iso: String,
+    #[serde(default)]
+    enabled: bool,
+}
+
+/// A pickup slot in local time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+    pub start: DateTime<Tz>,
+    pub enabled: bool,
+}
+
+/// Parses a timeslot response into the start times of its enabled slots.
+pub fn parse_enabled_slots(timeslots_json: &str) -> Result<Vec<DateTime<Tz>>, (Status, String)> {
+    Ok(parse_slots(timeslots_json)?.into_iter()
+        .filter(|slot| slot.enabled)
+        .map(|slot| slot.start)
+        .collect())
+}
+
+/// Parses a timeslot response into all of its slots, sorted by start time.
+pub fn parse_slots(timeslots_json: &str) -> Result<Vec<Slot>, (Status, String)> {
+    let days = serde_json::from_str::<Vec<TimeslotDay>>(timeslots_json)
+        .map_err(|er| (Status::BadGateway, format!("Unexpected timeslot response {:?}", er)))?;
+    let mut slots: Vec<Slot> = days.iter()
+        .flat_map(|day| day.timeslots.iter())
+        .filter_map(|slot| match DateTime::parse_from_rfc3339(&slot.date_iso) {
+            Ok(date) => Some(Slot { start: date.with_timezone(&Copenhagen), enabled: slot.enabled }),
+            Err(er) => {
+                warn!("Skipping timeslot with invalid date {}: {:?}", slot.date_iso, er);
+                None
+            }
+        })
+        .collect();
+    // An enabled slot wins over a disabled one at the same time.
+    slots.sort_by_key(|slot| (slot.start, !slot.enabled));
+    slots.dedup_by_key(|slot| slot.start);
+    Ok(slots)
+}
@@ -0,0 +1,23 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+const TOKEN_HEADER: &str = "X-User-Token";
+const MAX_TOKEN_LENGTH: usize = 128;
+
+/// An opaque token identifying the caller, sent in the `X-User-Token` header.
+/// It is not authenticated, it only tells callers apart, e.g. for one vote per person.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserToken(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserToken {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one(TOKEN_HEADER).map(str::trim) {
+            Some(token) if !token.is_empty() && token.len() <= MAX_TOKEN_LENGTH => Outcome::Success(UserToken(token.to_string())),
+            Some(_) => Outcome::Error((Status::BadRequest, "Invalid user token")),
+            None => Outcome::Error((Status::Unauthorized, "Missing X-User-Token header")),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use rocket::futures::lock::Mutex;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::{error, info, instrument, warn};
+
+use crate::admin::Admin;
+
+/// Vendors hidden from and pinned to the top of the vendor list of a site.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct VendorSettings {
+    pub excluded: Vec<String>,
+    /// Shown first, in this order, before the rotated vendors.
+    pub pinned: Vec<String>,
+}
+
+/// Decides which vendors `/api/vendors` lists and in which order, so every client sees the same list.
+#[derive(Debug)]
+pub struct VendorOrder {
+    configured: HashMap<String, VendorSettings>,
+    overrides: HashMap<String, VendorSettings>,
+    overrides_path: Option<PathBuf>,
+}
+
+impl VendorOrder {
+    /// Creates the vendor order from the configured settings per site.
+    /// Settings changed through the API are loaded from and saved to `overrides_path`, if given, and replace the configured ones.
+    pub fn new(configured: HashMap<String, VendorSettings>, overrides_path: Option<PathBuf>) -> Self {
+        let overrides = match &overrides_path {
+            Some(path) if path.exists() => std::fs::read_to_string(path)
+                .map_err(|er| er.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|er| er.to_string()))
+                .unwrap_or_else(|er| {
+                    error!("Failed to load vendor settings from {:?}: {}", path, er);
+                    HashMap::new()
+                }),
+            _ => HashMap::new(),
+        };
+
+        VendorOrder { configured, overrides, overrides_path }
+    }
+
+    pub fn settings(&self, site: &str) -> VendorSettings {
+        self.overrides.get(site)
+            .or_else(|| self.configured.get(site))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Sets or, when `settings` is `None`, reverts the settings of a site to the configured ones, and saves all changed settings.
+    pub async fn set_settings(&mut self, site: &str, settings: Option<VendorSettings>) -> Result<(), std::io::Error> {
+        match settings {
+            Some(settings) => self.overrides.insert(site.to_string(), settings),
+            None => self.overrides.remove(site),
+        };
+
+        if let Some(path) = &self.overrides_path {
+            let json = serde_json::to_string_pretty(&self.overrides)?;
+            rocket::tokio::fs::write(path, json).await?;
+            info!("Saved vendor settings of {} sites to {:?}", self.overrides.len(), path);
+        }
+        Ok(())
+    }
+
+    /// Removes excluded vendors from a raw `clientUnits` payload and orders each list of vendors:
+    /// pinned vendors first, then the others rotated by one place per day.
+    /// Lists are returned as arrays, since clients iterate index-keyed objects in key order.
+    pub fn arrange(&self, site: &str, locations: &mut Value, day_of_year: u32) {
+        let settings = self.settings(site);
+        let mut top = take_list(locations);
+        for location in top.iter_mut() {
+            if let Some(children) = location.get_mut("children") {
+                let children_list = take_list(children);
+                *children = Value::Array(arrange_list(children_list, &settings, day_of_year));
+            }
+        }
+        *locations = Value::Array(arrange_list(top, &settings, day_of_year));
+    }
+}
+
+/// The entries of a Firebase list, which is either an array or an object keyed by index.
+fn take_list(value: &mut Value) -> Vec<Value> {
+    match value.take() {
+        Value::Array(list) => list,
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by_key(|(key, _)| (key.parse::<usize>().unwrap_or(usize::MAX), key.clone()));
+            entries.into_iter().map(|(_, entry)| entry).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn route_name(location: &Value) -> &str {
+    location.get("routeName").and_then(Value::as_str).unwrap_or_default()
+}
+
+fn arrange_list(list: Vec<Value>, settings: &VendorSettings, day_of_year: u32) -> Vec<Value> {
+    // Only vendors are excluded, a food court can share the route name of one of its vendors.
+    let (mut pinned, mut rest): (Vec<Value>, Vec<Value>) = list.into_iter()
+        .filter(|location| location.get("children").is_some() || !settings.excluded.iter().any(|excluded| excluded == route_name(location)))
+        .partition(|location| settings.pinned.iter().any(|pinned| pinned == route_name(location)));
+
+    pinned.sort_by_key(|location| settings.pinned.iter().position(|pinned| pinned == route_name(location)));
+    if !rest.is_empty() {
+        let offset = day_of_year as usize % rest.len();
+        rest.rotate_left(offset);
+    }
+    pinned.extend(rest);
+    pinned
+}
+
+#[get("/vendors/settings/<site>")]
+#[instrument]
+pub async fn get_settings(site: &str, order: &State<Mutex<VendorOrder>>) -> Json<VendorSettings> {
+    Json(order.lock().await.settings(site))
+}
+
+#[put("/vendors/settings/<site>", data = "<body>")]
+#[instrument(skip(_admin, body))]
+pub async fn put_settings(_admin: Admin, site: &str, body: Json<VendorSettings>, order: &State<Mutex<VendorOrder>>) -> Result<Json<VendorSettings>, (Status, String)> {
+    let settings = body.into_inner();
+    if let Some(both) = settings.pinned.iter().find(|pinned| settings.excluded.contains(pinned)) {
+        return Err((Status::BadRequest, format!("{} is both pinned and excluded", both)));
+    }
+
+    order.lock().await.set_settings(site, Some(settings.clone())).await
+        .map_err(|er| {
+            error!("Failed to save vendor settings: {:?}", er);
+            (Status::InternalServerError, format!("Saving vendor settings failed {:?}", er))
+        })?;
+    Ok(Json(settings))
+}
+
+#[delete("/vendors/settings/<site>")]
+#[instrument(skip(_admin))]
+pub async fn delete_settings(_admin: Admin, site: &str, order: &State<Mutex<VendorOrder>>) -> Result<Status, (Status, String)> {
+    let mut order = order.lock().await;
+    if !order.overrides.contains_key(site) {
+        warn!("No changed vendor settings for {}", site);
+        return Err((Status::NotFound, format!("No changed vendor settings for {}", site)));
+    }
+
+    order.set_settings(site, None).await
+        .map_err(|er| {
+            error!("Failed to save vendor settings: {:?}", er);
+            (Status::InternalServerError, format!("Saving vendor settings failed {:?}", er))
+        })?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::admin::AdminConfig;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+    use serde_json::json;
+
+    fn order(excluded: &[&str], pinned: &[&str]) -> VendorOrder {
+        let settings = VendorSettings {
+            excluded: excluded.iter().map(|e| e.to_string()).collect(),
+            pinned: pinned.iter().map(|p| p.to_string()).collect(),
+        };
+        VendorOrder::new(HashMap::from([("site".to_string(), settings)]), None)
+    }
+
+    fn locations() -> Value {
+        json!({
+            "0": {"routeName": "market", "children": {
+                "0": {"routeName": "a"}, "1": {"routeName": "b"}, "2": {"routeName": "c"},
+                "10": {"routeName": "k"}, "3": {"routeName": "d"}
+            }},
+            "1": {"routeName": "cafe"},
+            "2": {"routeName": "market"},
+        })
+    }
+
+    fn names(list: &Value) -> Vec<&str> {
+        list.as_array().unwrap().iter().map(route_name).collect()
+    }
+
+    fn children(locations: &Value) -> Vec<&str> {
+        names(locations.as_array().unwrap().iter().find_map(|location| location.get("children")).unwrap())
+    }
+
+    #[test]
+    fn keeps_index_order_without_rotation() {
+        let mut value = locations();
+        order(&[], &[]).arrange("site", &mut value, 0);
+        assert_eq!(children(&value), vec!["a", "b", "c", "d", "k"]);
+    }
+
+    #[test]
+    fn rotates_by_day() {
+        let mut value = locations();
+        order(&[], &[]).arrange("site", &mut value, 7);
+        assert_eq!(children(&value), vec!["c", "d", "k", "a", "b"]);
+        assert_eq!(names(&value), vec!["cafe", "market", "market"]);
+    }
+
+    #[test]
+    fn excludes_vendors_but_not_food_courts() {
+        let mut value = locations();
+        order(&["market", "b"], &[]).arrange("site", &mut value, 0);
+        assert_eq!(names(&value), vec!["market", "cafe"]);
+        assert_eq!(children(&value), vec!["a", "c", "d", "k"]);
+    }
+
+    #[test]
+    fn pins_vendors_first_in_order() {
+        let mut value = locations();
+        order(&[], &["k", "c"]).arrange("site", &mut value, 1);
+        assert_eq!(children(&value), vec!["k", "c", "b", "d", "a"]);
+    }
+
+    #[test]
+    fn other_sites_are_untouched() {
+        let mut value = locations();
+        order(&["a"], &["k"]).arrange("other", &mut value, 0);
+        assert_eq!(children(&value), vec!["a", "b", "c", "d", "k"]);
+    }
+
+    #[test]
+    fn changing_settings_requires_the_admin_token() {
+        let rocket = rocket::build()
+            .mount("/api", routes![get_settings, put_settings, delete_settings])
+            .manage(AdminConfig { token: Some("secret".to_string()) })
+            .manage(Mutex::new(order(&[], &[])));
+        let client = Client::tracked(rocket).unwrap();
+        let body = r#"{"excluded": ["a"]}"#;
+
+        assert_eq!(client.put("/api/vendors/settings/site").body(body).dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.delete("/api/vendors/settings/site").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/api/vendors/settings/site").dispatch().into_json::<VendorSettings>(), Some(VendorSettings::default()));
+
+        let admin = Header::new("Authorization", "Bearer secret");
+        assert_eq!(client.put("/api/vendors/settings/site").header(admin.clone()).body(body).dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/api/vendors/settings/site").dispatch().into_json::<VendorSettings>().unwrap().excluded, vec!["a"]);
+        assert_eq!(client.delete("/api/vendors/settings/site").header(admin).dispatch().status(), Status::NoContent);
+    }
+}
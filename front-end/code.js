@@ -412,25 +412,6 @@ function validateMenuItem(item) {
            /^\d+$/.test(item.Cost);
 }
 
-function getDayOfYear(date) {
-    const start = new Date(date.getFullYear(), 0, 0);
-    const diff = date - start;
-    const oneDay = 1000 * 60 * 60 * 24;
-    return Math.floor(diff / oneDay);
-}
-
-function shuffleVendorList(vendors, dayOfYear) {
-    const keys = Object.keys(vendors);
-    let offset = dayOfYear % keys.length;
-    let shuffled = keys.slice(offset).concat(keys.slice(0, offset));
-    let shuffledVendors = {};
-    for (const key of shuffled) {
-        shuffledVendors[key] = vendors[key];
-    }
-
-    return shuffledVendors;
-}
-
 async function loadAllSites() {
     const sites = [];
     const client = new ApiClient();
@@ -475,7 +456,6 @@ async function loadAllSites() {
 /**
  * @typedef {Object} MainConfig
  * @property {number} [messageTimeout=5000] - Timeout for reading messages in milliseconds
- * @property {string[]} [excludedVendors=['compassdk_townhallcafe', 'compassdk_centralcafe']] - Vendors to exclude
  * @property {string} [clientUnitsPath='/clientUnits/compassdk_danskebank/all'] - Path for client units query
  */
 
@@ -485,7 +465,6 @@ async function loadAllSites() {
 async function main(config = {}) {
     const {
         messageTimeout = 5000,
-        excludedVendors = ['compassdk_townhallcafe', 'compassdk_centralcafe'],
         clientUnitsPath = '/clientUnits/compassdk_danskebank/all'
     } = config;
 
@@ -496,17 +475,10 @@ async function main(config = {}) {
     
     try {
         let listVendorResponse = await fetch("api/vendors").then(res => res.json());
+        // The backend leaves out excluded vendors and orders the rest for the day
         handleVendorMessage(vendors, listVendorResponse);
 
-        for (const excludedVendor of excludedVendors) {
-            delete vendors[excludedVendor];
-        }
-
-        // Shuffle the vendor list based on the day of the year
-        const dayOfYear = getDayOfYear(new Date());
-        vendors = shuffleVendorList(vendors, dayOfYear);
-
-        // Fetch menus for all non-excluded vendors
+        // Fetch menus for all vendors
         for (const vendorRoute in vendors) {
             if (Object.hasOwnProperty.call(vendors, vendorRoute)) {
                 const vendor = vendors[vendorRoute];